@@ -0,0 +1,741 @@
+use crate::{constant::*, state::*, traits::*, types::*};
+use anyhow::{bail, Result};
+
+// T-cycles per second, the APU keeps this pace in double speed too
+const CLOCK_RATE: u32 = 4_194_304;
+
+// NR10 to NR52
+const REGISTER_COUNT: usize = (ADDR_APU_NR52 - ADDR_APU_NR10 + 1) as usize;
+
+// bits that read as 1 whatever was written, NR10 to NR52
+const READ_MASKS: [Byte; REGISTER_COUNT] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+const DUTY_PATTERNS: [[Byte; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// the wave channel fetches its first sample this much later than a whole
+// period after a trigger
+const WAVE_TRIGGER_DELAY: u32 = 6;
+
+// the high-pass filter in front of the output keeps this much of its charge
+// per T-cycle
+const CAPACITOR_CHARGE: f32 = 0.999958;
+
+fn reg(addr: Word) -> usize {
+    (addr - ADDR_APU_NR10) as usize
+}
+
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    // clocked on the even steps of the frame sequencer, returns whether it ran out
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    // NRx4: enabling the counter in the half of a frame sequencer period
+    // that doesn't clock it clocks it once more. Returns whether it ran out.
+    fn write(&mut self, value: Byte, length_step_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = value & 0x40 != 0;
+        if length_step_next || was_enabled || !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    // a trigger restarts an expired counter, less the extra clock above
+    fn trigger(&mut self, max: u16, length_step_next: bool) {
+        if self.counter == 0 {
+            self.counter = max;
+            if self.enabled && !length_step_next {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    volume: Byte,
+    timer: Byte,
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: Byte) {
+        self.volume = nrx2 >> 4;
+        self.timer = nrx2 & 0x07;
+    }
+
+    // step 7 of the frame sequencer, a period of 0 stops the envelope
+    fn clock(&mut self, nrx2: Byte) {
+        let period = nrx2 & 0x07;
+        if period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+        if nrx2 & 0x08 != 0 && self.volume < 0x0F {
+            self.volume += 1;
+        } else if nrx2 & 0x08 == 0 && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+// channel 1's frequency sweep
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    shadow: u16,
+    timer: Byte,
+    // clearing the negate bit after a negated calculation disables the channel
+    negated: bool,
+}
+
+impl Sweep {
+    fn period(nr10: Byte) -> Byte {
+        match (nr10 >> 4) & 0x07 {
+            0 => 8,
+            p => p,
+        }
+    }
+
+    // the next frequency, over 2047 disables the channel
+    fn next(&mut self, nr10: Byte) -> u16 {
+        let delta = self.shadow >> (nr10 & 0x07);
+        if nr10 & 0x08 != 0 {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    timer: u32,
+    duty_step: usize,
+}
+
+impl Square {
+    fn run(&mut self, cycles: u32, frequency: u16) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = (2048 - frequency as u32) * 4;
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self, nrx1: Byte) -> Byte {
+        DUTY_PATTERNS[(nrx1 >> 6) as usize][self.duty_step] * self.envelope.volume
+    }
+
+    fn trigger(&mut self, nrx2: Byte, frequency: u16, length_step_next: bool) {
+        self.enabled = nrx2 & 0xF8 != 0;
+        self.length.trigger(64, length_step_next);
+        self.envelope.trigger(nrx2);
+        self.timer = (2048 - frequency as u32) * 4;
+    }
+}
+
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    length: Length,
+    timer: u32,
+    position: usize,
+    // the last sample fetched from wave RAM, 4 bits
+    sample: Byte,
+    // T-cycles since wave RAM was last read
+    since_fetch: u32,
+}
+
+impl Wave {
+    fn period(frequency: u16) -> u32 {
+        (2048 - frequency as u32) * 2
+    }
+
+    fn run(&mut self, cycles: u32, frequency: u16, wave_ram: &[Byte; 0x10]) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = Self::period(frequency);
+            self.position = (self.position + 1) % 32;
+            let byte = wave_ram[self.position / 2];
+            self.sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+            self.since_fetch = cycles;
+        }
+        self.timer -= cycles;
+        self.since_fetch = self.since_fetch.saturating_add(cycles);
+    }
+
+    fn output(&self, nr32: Byte) -> Byte {
+        match (nr32 >> 5) & 0x03 {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+
+    // on DMG the CPU only gets at wave RAM while the channel plays in the
+    // cycle the channel reads it, and then only at the byte it reads
+    fn fetched_now(&self) -> bool {
+        self.since_fetch < 2
+    }
+}
+
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Noise {
+    // shifts of 14 and 15 stop the LFSR
+    fn period(nr43: Byte) -> Option<u32> {
+        let shift = nr43 >> 4;
+        (shift < 14).then(|| NOISE_DIVISORS[(nr43 & 0x07) as usize] << shift)
+    }
+
+    fn run(&mut self, cycles: u32, nr43: Byte) {
+        let Some(period) = Self::period(nr43) else {
+            return;
+        };
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = period;
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if nr43 & 0x08 != 0 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> Byte {
+        (!self.lfsr & 0x01) as Byte * self.envelope.volume
+    }
+
+    fn trigger(&mut self, nr42: Byte, nr43: Byte, length_step_next: bool) {
+        self.enabled = nr42 & 0xF8 != 0;
+        self.length.trigger(64, length_step_next);
+        self.envelope.trigger(nr42);
+        self.timer = Self::period(nr43).unwrap_or(0);
+        self.lfsr = 0x7FFF;
+    }
+}
+
+#[derive(Default)]
+pub struct Apu {
+    // NR10 to NR52 as written, the channels take their settings from here
+    regs: [Byte; REGISTER_COUNT],
+    wave_ram: [Byte; 0x10],
+    // CGB wave RAM and power quirks
+    cgb: bool,
+    square1: Square,
+    sweep: Sweep,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    // the next step of the frame sequencer, 512 Hz from the falling edges
+    // of a DIV bit
+    frame_step: Byte,
+    div_bit: bool,
+    // collected samples, stereo, None while nobody listens
+    sample_rate: Option<u32>,
+    sample_clock: u32,
+    mix: (f32, f32),
+    mix_cycles: u32,
+    capacitor: (f32, f32),
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new(cgb: bool) -> Self {
+        Self {
+            cgb,
+            ..Default::default()
+        }
+    }
+
+    /// Collect stereo samples at `rate` Hz for `take_samples`, or stop with None.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.sample_rate = rate;
+        self.sample_clock = 0;
+        self.mix = (0.0, 0.0);
+        self.mix_cycles = 0;
+        self.samples.clear();
+    }
+
+    /// The samples played since the last call, interleaved left and right.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// How many samples `take_samples` would return.
+    pub fn pending_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Drop the samples played after the first `len`.
+    pub fn truncate_samples(&mut self, len: usize) {
+        self.samples.truncate(len);
+    }
+
+    /// Run the channels through `cycles` T-cycles. `div_bit` is the DIV bit
+    /// whose falling edges step the frame sequencer.
+    pub fn tick(&mut self, cycles: u16, div_bit: bool) {
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+        let cycles = cycles as u32;
+
+        if self.powered() {
+            if falling_edge {
+                self.step_frame_sequencer();
+            }
+            self.square1.run(cycles, self.frequency(ADDR_APU_NR13));
+            self.square2.run(cycles, self.frequency(ADDR_APU_NR23));
+            if self.wave.enabled {
+                self.wave.run(cycles, self.frequency(ADDR_APU_NR33), &self.wave_ram);
+            }
+            self.noise.run(cycles, self.regs[reg(ADDR_APU_NR43)]);
+        }
+
+        if self.sample_rate.is_some() {
+            self.sample(cycles);
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.regs[reg(ADDR_APU_NR52)] & 0x80 != 0
+    }
+
+    // from NRx3 and the low bits of NRx4
+    fn frequency(&self, nrx3: Word) -> u16 {
+        let low = self.regs[reg(nrx3)] as u16;
+        let high = (self.regs[reg(nrx3 + 1)] & 0x07) as u16;
+        high << 8 | low
+    }
+
+    fn set_frequency(&mut self, nrx3: Word, frequency: u16) {
+        self.regs[reg(nrx3)] = frequency as Byte;
+        let nrx4 = &mut self.regs[reg(nrx3 + 1)];
+        *nrx4 = (*nrx4 & 0xF8) | (frequency >> 8) as Byte & 0x07;
+    }
+
+    // lengths on even steps, the sweep on 2 and 6 and envelopes on 7
+    fn length_step_next(&self) -> bool {
+        self.frame_step & 0x01 == 0
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_step;
+        self.frame_step = (self.frame_step + 1) % 8;
+
+        if step & 0x01 == 0 {
+            if self.square1.length.clock() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.clock(self.regs[reg(ADDR_APU_NR12)]);
+            self.square2.envelope.clock(self.regs[reg(ADDR_APU_NR22)]);
+            self.noise.envelope.clock(self.regs[reg(ADDR_APU_NR42)]);
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let nr10 = self.regs[reg(ADDR_APU_NR10)];
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.sweep.timer = Sweep::period(nr10);
+        if !self.sweep.enabled || (nr10 >> 4) & 0x07 == 0 {
+            return;
+        }
+        let frequency = self.sweep.next(nr10);
+        if frequency > 2047 {
+            self.square1.enabled = false;
+        } else if nr10 & 0x07 != 0 {
+            self.sweep.shadow = frequency;
+            self.set_frequency(ADDR_APU_NR13, frequency);
+            // checked again with the new frequency, without using it
+            if self.sweep.next(nr10) > 2047 {
+                self.square1.enabled = false;
+            }
+        }
+    }
+
+    fn trigger_square1(&mut self) {
+        let nr10 = self.regs[reg(ADDR_APU_NR10)];
+        let frequency = self.frequency(ADDR_APU_NR13);
+        let length_step_next = self.length_step_next();
+        self.square1.trigger(self.regs[reg(ADDR_APU_NR12)], frequency, length_step_next);
+
+        self.sweep.shadow = frequency;
+        self.sweep.timer = Sweep::period(nr10);
+        self.sweep.enabled = nr10 & 0x77 != 0;
+        self.sweep.negated = false;
+        if nr10 & 0x07 != 0 && self.sweep.next(nr10) > 2047 {
+            self.square1.enabled = false;
+        }
+    }
+
+    fn trigger_wave(&mut self) {
+        // triggering on DMG as the channel reads wave RAM corrupts its start
+        // with the block of 4 bytes being read, or just the byte if it's one
+        // of the first 4
+        if !self.cgb && self.wave.enabled && self.wave.timer <= 2 {
+            let offset = self.wave.position.div_ceil(2) % 16;
+            if offset < 4 {
+                self.wave_ram[0] = self.wave_ram[offset];
+            } else {
+                let block = offset & !0x03;
+                self.wave_ram.copy_within(block..block + 4, 0);
+            }
+        }
+        self.wave.enabled = self.regs[reg(ADDR_APU_NR30)] & 0x80 != 0;
+        self.wave.length.trigger(256, self.length_step_next());
+        self.wave.timer = Wave::period(self.frequency(ADDR_APU_NR33)) + WAVE_TRIGGER_DELAY;
+        self.wave.position = 0;
+    }
+
+    fn power_off(&mut self) {
+        // DMG length counters survive
+        let lengths = [
+            self.square1.length.counter,
+            self.square2.length.counter,
+            self.wave.length.counter,
+            self.noise.length.counter,
+        ];
+        self.regs = [0; REGISTER_COUNT];
+        self.square1 = Default::default();
+        self.sweep = Default::default();
+        self.square2 = Default::default();
+        self.wave = Default::default();
+        self.noise = Default::default();
+        if !self.cgb {
+            self.square1.length.counter = lengths[0];
+            self.square2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+        }
+    }
+
+    fn power_on(&mut self) {
+        self.frame_step = 0;
+        self.square1.duty_step = 0;
+        self.square2.duty_step = 0;
+        self.wave.sample = 0;
+    }
+
+    fn read_wave_ram(&self, addr: Word) -> Byte {
+        if !self.wave.enabled {
+            return self.wave_ram[(addr - ADDR_APU_WAVE_START) as usize];
+        }
+        if self.cgb || self.wave.fetched_now() {
+            self.wave_ram[self.wave.position / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_wave_ram(&mut self, addr: Word, value: Byte) {
+        if !self.wave.enabled {
+            self.wave_ram[(addr - ADDR_APU_WAVE_START) as usize] = value;
+        } else if self.cgb || self.wave.fetched_now() {
+            self.wave_ram[self.wave.position / 2] = value;
+        }
+    }
+
+    fn write_register(&mut self, addr: Word, value: Byte) {
+        let length_step_next = self.length_step_next();
+        match addr {
+            ADDR_APU_NR10 if self.sweep.negated && value & 0x08 == 0 => self.square1.enabled = false,
+            ADDR_APU_NR11 => self.square1.length.counter = 64 - (value & 0x3F) as u16,
+            ADDR_APU_NR21 => self.square2.length.counter = 64 - (value & 0x3F) as u16,
+            ADDR_APU_NR31 => self.wave.length.counter = 256 - value as u16,
+            ADDR_APU_NR41 => self.noise.length.counter = 64 - (value & 0x3F) as u16,
+            ADDR_APU_NR12 if value & 0xF8 == 0 => self.square1.enabled = false,
+            ADDR_APU_NR22 if value & 0xF8 == 0 => self.square2.enabled = false,
+            ADDR_APU_NR30 if value & 0x80 == 0 => self.wave.enabled = false,
+            ADDR_APU_NR42 if value & 0xF8 == 0 => self.noise.enabled = false,
+            ADDR_APU_NR14 => {
+                let expired = self.square1.length.write(value, length_step_next);
+                if expired && value & 0x80 == 0 {
+                    self.square1.enabled = false;
+                }
+            }
+            ADDR_APU_NR24 => {
+                let expired = self.square2.length.write(value, length_step_next);
+                if expired && value & 0x80 == 0 {
+                    self.square2.enabled = false;
+                }
+            }
+            ADDR_APU_NR34 => {
+                let expired = self.wave.length.write(value, length_step_next);
+                if expired && value & 0x80 == 0 {
+                    self.wave.enabled = false;
+                }
+            }
+            ADDR_APU_NR44 => {
+                let expired = self.noise.length.write(value, length_step_next);
+                if expired && value & 0x80 == 0 {
+                    self.noise.enabled = false;
+                }
+            }
+            _ => (),
+        }
+        self.regs[reg(addr)] = value;
+
+        if value & 0x80 == 0 {
+            return;
+        }
+        match addr {
+            ADDR_APU_NR14 => self.trigger_square1(),
+            ADDR_APU_NR24 => {
+                let frequency = self.frequency(ADDR_APU_NR23);
+                self.square2.trigger(self.regs[reg(ADDR_APU_NR22)], frequency, length_step_next);
+            }
+            ADDR_APU_NR34 => self.trigger_wave(),
+            ADDR_APU_NR44 => {
+                let (nr42, nr43) = (self.regs[reg(ADDR_APU_NR42)], self.regs[reg(ADDR_APU_NR43)]);
+                self.noise.trigger(nr42, nr43, length_step_next);
+            }
+            _ => (),
+        }
+    }
+
+    // channel outputs through their DACs, -1.0 to 1.0, and off without a DAC
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |on: bool, enabled: bool, output: Byte| {
+            if !on {
+                return 0.0;
+            }
+            let output = if enabled { output } else { 0 };
+            1.0 - output as f32 / 7.5
+        };
+        [
+            dac(self.regs[reg(ADDR_APU_NR12)] & 0xF8 != 0, self.square1.enabled, self.square1.output(self.regs[reg(ADDR_APU_NR11)])),
+            dac(self.regs[reg(ADDR_APU_NR22)] & 0xF8 != 0, self.square2.enabled, self.square2.output(self.regs[reg(ADDR_APU_NR21)])),
+            dac(self.regs[reg(ADDR_APU_NR30)] & 0x80 != 0, self.wave.enabled, self.wave.output(self.regs[reg(ADDR_APU_NR32)])),
+            dac(self.regs[reg(ADDR_APU_NR42)] & 0xF8 != 0, self.noise.enabled, self.noise.output()),
+        ]
+    }
+
+    // NR51 routes the channels to either side, NR50 sets their volumes
+    fn sample(&mut self, cycles: u32) {
+        let Some(rate) = self.sample_rate else {
+            return;
+        };
+        let (mut left, mut right) = (0.0, 0.0);
+        if self.powered() {
+            let nr50 = self.regs[reg(ADDR_APU_NR50)];
+            let nr51 = self.regs[reg(ADDR_APU_NR51)];
+            for (i, output) in self.dac_outputs().into_iter().enumerate() {
+                if nr51 & (0x10 << i) != 0 {
+                    left += output;
+                }
+                if nr51 & (0x01 << i) != 0 {
+                    right += output;
+                }
+            }
+            left *= (((nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+            right *= ((nr50 & 0x07) + 1) as f32 / 8.0;
+        }
+        self.mix.0 += left * cycles as f32;
+        self.mix.1 += right * cycles as f32;
+        self.mix_cycles += cycles;
+
+        self.sample_clock += cycles * rate;
+        if self.sample_clock < CLOCK_RATE {
+            return;
+        }
+        self.sample_clock -= CLOCK_RATE;
+
+        let charge = CAPACITOR_CHARGE.powf((CLOCK_RATE / rate) as f32);
+        let filter = |input: f32, capacitor: &mut f32| {
+            let output = input - *capacitor;
+            *capacitor = input - output * charge;
+            // 4 channels at full volume fill the range
+            (output / 4.0 * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        };
+        let left = filter(self.mix.0 / self.mix_cycles as f32, &mut self.capacitor.0);
+        let right = filter(self.mix.1 / self.mix_cycles as f32, &mut self.capacitor.1);
+        self.samples.push(left);
+        self.samples.push(right);
+        self.mix = (0.0, 0.0);
+        self.mix_cycles = 0;
+    }
+}
+
+impl Reader for Apu {
+    fn read(&self, addr: Word) -> Byte {
+        match addr {
+            ADDR_APU_NR52 => {
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                let status = channels.iter().rev().fold(0, |s, &on| s << 1 | on as Byte);
+                self.regs[reg(addr)] & 0x80 | READ_MASKS[reg(addr)] | status
+            }
+            ADDR_APU_NR10..=ADDR_APU_NR51 => self.regs[reg(addr)] | READ_MASKS[reg(addr)],
+            ADDR_APU_WAVE_START..=ADDR_APU_WAVE_END => self.read_wave_ram(addr),
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Writer for Apu {
+    fn write(&mut self, addr: Word, value: Byte) {
+        match addr {
+            ADDR_APU_NR52 => {
+                let powered = self.powered();
+                if powered && value & 0x80 == 0 {
+                    self.power_off();
+                } else if !powered && value & 0x80 != 0 {
+                    self.power_on();
+                }
+                self.regs[reg(addr)] = value & 0x80;
+            }
+            ADDR_APU_WAVE_START..=ADDR_APU_WAVE_END => self.write_wave_ram(addr, value),
+            ADDR_APU_NR10..=ADDR_APU_NR51 if self.powered() => self.write_register(addr, value),
+            // powered off, a DMG still takes the lengths
+            ADDR_APU_NR11 | ADDR_APU_NR21 | ADDR_APU_NR31 | ADDR_APU_NR41 if !self.cgb => {
+                let value = if addr == ADDR_APU_NR31 { value } else { value & 0x3F };
+                self.write_register(addr, value);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Savable for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_raw(&self.regs);
+        w.write_raw(&self.wave_ram);
+        w.write_u8(self.frame_step);
+        w.write_bool(self.div_bit);
+
+        for square in [&self.square1, &self.square2] {
+            w.write_bool(square.enabled);
+            w.write_u16(square.length.counter);
+            w.write_bool(square.length.enabled);
+            w.write_u8(square.envelope.volume);
+            w.write_u8(square.envelope.timer);
+            w.write_u32(square.timer);
+            w.write_u8(square.duty_step as Byte);
+        }
+        w.write_bool(self.sweep.enabled);
+        w.write_u16(self.sweep.shadow);
+        w.write_u8(self.sweep.timer);
+        w.write_bool(self.sweep.negated);
+
+        w.write_bool(self.wave.enabled);
+        w.write_u16(self.wave.length.counter);
+        w.write_bool(self.wave.length.enabled);
+        w.write_u32(self.wave.timer);
+        w.write_u8(self.wave.position as Byte);
+        w.write_u8(self.wave.sample);
+        w.write_u32(self.wave.since_fetch);
+
+        w.write_bool(self.noise.enabled);
+        w.write_u16(self.noise.length.counter);
+        w.write_bool(self.noise.length.enabled);
+        w.write_u8(self.noise.envelope.volume);
+        w.write_u8(self.noise.envelope.timer);
+        w.write_u32(self.noise.timer);
+        w.write_u16(self.noise.lfsr);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for v in self.regs.iter_mut().chain(self.wave_ram.iter_mut()) {
+            *v = r.read_u8()?;
+        }
+        self.frame_step = r.read_u8()? % 8;
+        self.div_bit = r.read_bool()?;
+
+        for square in [&mut self.square1, &mut self.square2] {
+            square.enabled = r.read_bool()?;
+            square.length.counter = r.read_u16()?;
+            square.length.enabled = r.read_bool()?;
+            square.envelope.volume = r.read_u8()? & 0x0F;
+            square.envelope.timer = r.read_u8()?;
+            square.timer = r.read_u32()?;
+            square.duty_step = (r.read_u8()? % 8) as usize;
+        }
+        self.sweep.enabled = r.read_bool()?;
+        self.sweep.shadow = r.read_u16()?;
+        self.sweep.timer = r.read_u8()?;
+        self.sweep.negated = r.read_bool()?;
+
+        self.wave.enabled = r.read_bool()?;
+        self.wave.length.counter = r.read_u16()?;
+        self.wave.length.enabled = r.read_bool()?;
+        self.wave.timer = r.read_u32()?;
+        self.wave.position = (r.read_u8()? % 32) as usize;
+        self.wave.sample = r.read_u8()? & 0x0F;
+        self.wave.since_fetch = r.read_u32()?;
+
+        self.noise.enabled = r.read_bool()?;
+        self.noise.length.counter = r.read_u16()?;
+        self.noise.length.enabled = r.read_bool()?;
+        self.noise.envelope.volume = r.read_u8()? & 0x0F;
+        self.noise.envelope.timer = r.read_u8()?;
+        self.noise.timer = r.read_u32()?;
+        self.noise.lfsr = r.read_u16()?;
+
+        if self.square1.length.counter > 64 || self.square2.length.counter > 64
+            || self.wave.length.counter > 256 || self.noise.length.counter > 64
+        {
+            bail!("APU length counter out of range");
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,40 @@
+use crate::{traits::*, types::*};
+use anyhow::{bail, Result};
+
+// DMG, MGB and SGB boot ROMs are 256 bytes, CGB ones are 2304 bytes with
+// a hole at 0x0100-0x01FF where the cartridge header shows through.
+pub const BOOTROM_SIZE_DMG: usize = 0x100;
+pub const BOOTROM_SIZE_CGB: usize = 0x900;
+
 pub struct Bootrom {
-    rom: Box<u8>
+    rom: Vec<Byte>,
 }
 
 impl Bootrom {
-    pub fn new(rom: Box<u8>) -> Self {
-        Self {
-            rom
+    pub fn new(rom: &[Byte]) -> Result<Self> {
+        if rom.len() != BOOTROM_SIZE_DMG && rom.len() != BOOTROM_SIZE_CGB {
+            bail!(
+                "Invalid boot ROM size {} (expected {} or {} bytes)",
+                rom.len(),
+                BOOTROM_SIZE_DMG,
+                BOOTROM_SIZE_CGB
+            );
         }
+        Ok(Self { rom: rom.to_vec() })
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
+    /// true when `addr` is served by the boot ROM instead of the cartridge
+    pub fn is_mapped(&self, addr: Word) -> bool {
+        match addr {
+            0x0000..=0x00FF => true,
+            0x0200..=0x08FF => self.rom.len() == BOOTROM_SIZE_CGB,
+            _ => false,
+        }
+    }
+}
+
+impl Reader for Bootrom {
+    fn read(&self, addr: Word) -> Byte {
         self.rom[addr as usize]
     }
-}
\ No newline at end of file
+}
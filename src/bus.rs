@@ -6,6 +6,8 @@ use std::{
 };
 
 use crate::{
+    apu::Apu,
+    bootrom::Bootrom,
     traits::*,
     types::*,
     {mbc::Mbc, mbc::MbcTrait},
@@ -13,61 +15,132 @@ use crate::{
     io::*,
     interrupt::Interrupt,
     timer::Timer,
-    ppu::Ppu,
+    ppu::{OamBugAccess, Ppu},
     joypad::Joypad,
+    oam_dma::OamDma,
     constant::*,
+    state::*,
 };
+use anyhow::{bail, Result};
 
 pub struct Bus {
     mbc: Mbc,
-    vram: RAM,
+    bootrom: Option<Bootrom>,
+    // cleared by writing 0xFF50, after which the cartridge shows through
+    bootrom_enabled: bool,
+    // CGB mode: banked VRAM and WRAM
+    cgb: bool,
+    // VRAM bank selected by VBK, VRAM itself is the PPU's
+    vram_bank: Byte,
+    // 0xC000-0xCFFF
     wram: RAM,
+    // banks 1-7 at 0xD000-0xDFFF, selected by SVBK
     wram2: RAM,
+    wram_bank: Byte,
+    // KEY1: bit 0 arms a speed switch on the next STOP, bit 7 is the current speed
+    speed_switch_armed: bool,
+    double_speed: bool,
     hram: RAM,
     eram: RAM,
-    oam: RAM,
+    oam_dma: OamDma,
     ppu: Arc<Mutex<Ppu>>,
     interrupt: Arc<Mutex<Interrupt>>,
     timer: Arc<Mutex<Timer>>,
     joypad: Arc<Mutex<Joypad>>,
+    apu: Arc<Mutex<Apu>>,
     io: Io,
 }
 
 impl Bus {
-    pub fn new(mbc: Mbc, timer: Arc<Mutex<Timer>>, interrupt: Arc<Mutex<Interrupt>>, ppu: Arc<Mutex<Ppu>>, joypad: Arc<Mutex<Joypad>>) -> Box<dyn BusTrait + Send> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_shared(mbc: Mbc, bootrom: Option<Bootrom>, cgb: bool, timer: Arc<Mutex<Timer>>, interrupt: Arc<Mutex<Interrupt>>, ppu: Arc<Mutex<Ppu>>, joypad: Arc<Mutex<Joypad>>, apu: Arc<Mutex<Apu>>) -> Box<dyn BusTrait + Send> {
         Box::new(Bus {
             mbc,
-            vram: RAM::new(0x2000),
-            wram: RAM::new(0x4000),
-            wram2: RAM::new(0x4000),
+            bootrom_enabled: bootrom.is_some(),
+            bootrom,
+            cgb,
+            vram_bank: 0,
+            wram: RAM::new(0x1000),
+            wram2: RAM::new(0x7000),
+            wram_bank: 0,
+            speed_switch_armed: false,
+            double_speed: false,
             hram: RAM::new(0x0080),
             eram: RAM::new(0x2000),
-            oam: RAM::new(0x00A0),
+            oam_dma: OamDma::new(),
             ppu,
             interrupt,
             timer,
             joypad,
+            apu,
             io: Io::new(),
         })
     }
-}
 
-impl Reader for Bus {
-    fn read(&self, addr: Word) -> Byte {
+    // bank 0 selects bank 1 too
+    fn wram2_addr(&self, addr: Word) -> Word {
+        (self.wram_bank.max(1) as Word - 1) * 0x1000 + (addr - 0xD000)
+    }
+
+    // which of the buses the CPU and the OAM DMA share an address is on
+    fn memory_bus(&self, addr: Word) -> MemoryBus {
+        match addr {
+            0x8000..=0x9FFF => MemoryBus::Vram,
+            0xC000..=0xFDFF if self.cgb => MemoryBus::Wram,
+            _ => MemoryBus::External,
+        }
+    }
+
+    // what the CPU sees instead of `addr` while an OAM DMA copies,
+    // everything but HRAM and the IO registers is out of reach
+    fn oam_dma_conflict(&self, addr: Word) -> Option<Byte> {
+        if !self.oam_dma.active() {
+            return None;
+        }
+        match addr {
+            0xFE00..=0xFEFF => Some(0xFF),
+            0xFF00..=0xFFFF => None,
+            _ if self.memory_bus(addr) == self.memory_bus(self.oam_dma.src()) => Some(self.oam_dma.value()),
+            _ => None,
+        }
+    }
+
+    // the PPU keeps VRAM to itself in mode 3, and OAM in modes 2 and 3
+    fn locked_by_ppu(&self, addr: Word) -> bool {
+        match addr {
+            0x8000..=0x9FFF => !self.ppu.lock().unwrap().vram_accessible(),
+            0xFE00..=0xFE9F => !self.ppu.lock().unwrap().oam_accessible(),
+            _ => false,
+        }
+    }
+
+    fn read_memory(&self, addr: Word) -> Byte {
+        if self.bootrom_enabled {
+            if let Some(bootrom) = self.bootrom.as_ref().filter(|b| b.is_mapped(addr)) {
+                return bootrom.read(addr);
+            }
+        }
+
         match addr {
             0x0000..=0x7FFF => self.mbc.read(addr),
-            0x8000..=0x9FFF => self.vram.read(addr - 0x8000),
+            0x8000..=0x9FFF => self.ppu.lock().unwrap().read_vram(self.vram_bank, addr),
             0xA000..=0xBFFF => self.mbc.read(addr),
             0xC000..=0xCFFF => self.wram.read(addr - 0xC000),
-            0xD000..=0xDFFF => self.wram2.read(addr - 0xD000),
+            0xD000..=0xDFFF => self.wram2.read(self.wram2_addr(addr)),
             0xE000..=0xFDFF => self.eram.read(addr - 0xE000),
-            0xFE00..=0xFE9F => self.oam.read(addr - 0xFE00),
+            0xFE00..=0xFE9F => self.ppu.lock().unwrap().read_oam(addr),
             0xFEA0..=0xFEFF => 0,
+            ADDR_KEY1 if self.cgb => (self.double_speed as Byte) << 7 | 0x7E | self.speed_switch_armed as Byte,
+            ADDR_VBK if self.cgb => 0xFE | self.vram_bank,
+            ADDR_SVBK if self.cgb => 0xF8 | self.wram_bank,
+            ADDR_HDMA1..=ADDR_HDMA5 | ADDR_PPU_BCPS..=ADDR_PPU_OCPD if self.cgb => self.ppu.lock().unwrap().read(addr),
             0xFF4C..=0xFF7F => 0xFF, // unused
             ADDR_JOYPAD => self.joypad.lock().unwrap().read(addr),
             ADDR_TIMER_DIV..=ADDR_TIMER_TAC => self.timer.lock().unwrap().read(addr),
+            ADDR_PPU_DMA => self.oam_dma.read(),
             ADDR_PPU_LCDC..=ADDR_PPU_OCPD => self.ppu.lock().unwrap().read(addr),
             ADDR_INTERRUPT_IF | ADDR_INTERRUPT_IE => self.interrupt.lock().unwrap().read(addr),
+            ADDR_APU_NR10..=ADDR_APU_WAVE_END => self.apu.lock().unwrap().read(addr),
             0xFF00..=0xFF70 => self.io.read(addr),
             0xFF80..=0xFFFE => self.hram.read(addr - 0xFF80),
             v => todo!("addr {:04X} is not readable", v),
@@ -75,25 +148,160 @@ impl Reader for Bus {
     }
 }
 
+#[derive(PartialEq, Eq)]
+enum MemoryBus {
+    External,
+    Vram,
+    // CGB only, on DMG WRAM hangs off the external bus
+    Wram,
+}
+
+impl Reader for Bus {
+    fn read(&self, addr: Word) -> Byte {
+        if let Some(value) = self.oam_dma_conflict(addr) {
+            return value;
+        }
+        if self.locked_by_ppu(addr) {
+            return 0xFF;
+        }
+        self.read_memory(addr)
+    }
+}
+
 impl Writer for Bus {
     fn write(&mut self, addr: Word, value: Byte) {
+        if self.oam_dma_conflict(addr).is_some() || self.locked_by_ppu(addr) {
+            return;
+        }
         match addr {
             0x0000..=0x7FFF => self.mbc.write(addr, value),
-            0x8000..=0x9FFF => self.vram.write(addr - 0x8000, value),
+            0x8000..=0x9FFF => self.ppu.lock().unwrap().write_vram(self.vram_bank, addr, value),
             0xA000..=0xBFFF => self.mbc.write(addr, value),
             0xC000..=0xCFFF => self.wram.write(addr - 0xC000, value),
-            0xD000..=0xDFFF => self.wram2.write(addr - 0xD000, value),
+            0xD000..=0xDFFF => self.wram2.write(self.wram2_addr(addr), value),
             0xE000..=0xFDFF => self.eram.write(addr - 0xE000, value),
-            0xFE00..=0xFE9F => self.oam.write(addr - 0xFE00, value),
+            0xFE00..=0xFE9F => self.ppu.lock().unwrap().write_oam(addr, value),
             0xFEA0..=0xFEFF => (),
+            ADDR_BOOT => {
+                if value != 0 {
+                    self.bootrom_enabled = false;
+                }
+            }
+            ADDR_KEY1 if self.cgb => self.speed_switch_armed = value & 0x01 != 0,
+            ADDR_VBK if self.cgb => self.vram_bank = value & 0x01,
+            ADDR_SVBK if self.cgb => self.wram_bank = value & 0x07,
+            ADDR_KEY1 | ADDR_VBK | ADDR_HDMA1..=ADDR_HDMA5 | ADDR_PPU_BCPS..=ADDR_PPU_OCPD if !self.cgb => (),
             0xFF6C..=0xFF7F => (),
             ADDR_JOYPAD => self.joypad.lock().unwrap().write(addr, value),
             ADDR_TIMER_DIV..=ADDR_TIMER_TAC => self.timer.lock().unwrap().write(addr, value),
+            ADDR_PPU_DMA => self.oam_dma.write(value),
             ADDR_PPU_LCDC..=ADDR_PPU_OCPD => self.ppu.lock().unwrap().write(addr, value),
             ADDR_INTERRUPT_IF | ADDR_INTERRUPT_IE => self.interrupt.lock().unwrap().write(addr, value),
+            ADDR_APU_NR10..=ADDR_APU_WAVE_END => self.apu.lock().unwrap().write(addr, value),
             0xFF00..=0xFF70 => self.io.write(addr, value),
             0xFF80..=0xFFFE => self.hram.write(addr - 0xFF80, value),
             v => todo!("addr {:04X} is not writable", v),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Clock for Bus {
+    fn tick(&mut self) {
+        if let Some((src, offset)) = self.oam_dma.tick() {
+            let value = self.read_memory(src);
+            self.ppu.lock().unwrap().write_oam(ADDR_OAM_START + offset, value);
+            self.oam_dma.copied(value);
+        }
+        // in double speed the CPU and timer get through an M-cycle every 2 dots,
+        // the PPU and APU keep their pace
+        let dots = if self.double_speed { 2 } else { 4 };
+        self.ppu.lock().unwrap().step(dots);
+        let counter = {
+            let mut timer = self.timer.lock().unwrap();
+            timer.tick(1);
+            timer.counter()
+        };
+        // the frame sequencer steps as DIV bit 4 falls, bit 5 in double speed
+        let bit = if self.double_speed { 13 } else { 12 };
+        let div_bit = (counter >> bit) & 0x01 != 0;
+        self.apu.lock().unwrap().tick(dots, div_bit);
+    }
+}
+
+impl SpeedSwitch for Bus {
+    fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+}
+
+impl SerialLink for Bus {
+    fn serial_exchange(&mut self, value: Byte) -> Option<Byte> {
+        let out = self.io.serial_exchange(value)?;
+        self.interrupt.lock().unwrap().request(INT_SERIAL_FLG);
+        Some(out)
+    }
+}
+
+impl OamBug for Bus {
+    fn trigger_oam_bug(&mut self, addr: Word, access: OamBugAccess) {
+        if !(0xFE00..=0xFEFF).contains(&addr) {
+            return;
+        }
+        self.ppu.lock().unwrap().trigger_oam_bug(access);
+    }
+}
+
+impl VramBank for Bus {
+    fn vram_bank(&self) -> Byte {
+        self.vram_bank
+    }
+}
+
+impl Savable for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.bootrom_enabled);
+        w.write_bool(self.cgb);
+        self.mbc.save_state(w);
+        w.write_u8(self.vram_bank);
+        self.wram.save_state(w);
+        self.wram2.save_state(w);
+        w.write_u8(self.wram_bank);
+        w.write_bool(self.speed_switch_armed);
+        w.write_bool(self.double_speed);
+        self.hram.save_state(w);
+        self.eram.save_state(w);
+        self.oam_dma.save_state(w);
+        self.io.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let bootrom_enabled = r.read_bool()?;
+        if bootrom_enabled && self.bootrom.is_none() {
+            bail!("State was saved while running a boot ROM, but none is loaded");
+        }
+        if r.read_bool()? != self.cgb {
+            bail!("State was saved in {} mode", if self.cgb { "DMG" } else { "CGB" });
+        }
+        self.bootrom_enabled = bootrom_enabled;
+        self.mbc.load_state(r)?;
+        self.vram_bank = r.read_u8()? & 0x01;
+        self.wram.load_state(r)?;
+        self.wram2.load_state(r)?;
+        self.wram_bank = r.read_u8()? & 0x07;
+        self.speed_switch_armed = r.read_bool()?;
+        self.double_speed = r.read_bool()?;
+        self.hram.load_state(r)?;
+        self.eram.load_state(r)?;
+        self.oam_dma.load_state(r)?;
+        self.io.load_state(r)
+    }
+}
@@ -10,6 +10,8 @@ pub struct Cartridge {
     /// 0x0143        CGB Flag
     pub title: String,
     pub new_licensee_code: [Byte; 2],
+    /// 0x0143 bit 7: the game supports (0x80) or requires (0xC0) CGB features
+    pub cgb_flag: bool,
     pub sgb_flag: bool,
     pub cartridge_type: CartridgeType,
     pub rom_size: u64,
@@ -116,8 +118,8 @@ impl fmt::Display for CartridgeType {
         let mut result = String::from("CartridgeType:");
         result += format!(" {:02X}", self.code).as_str();
 
-        let mbc = self.mbc.clone();
-        result += format!(" {}", mbc.unwrap()).as_str();
+        let mbc = self.mbc.clone().unwrap_or(Mbc::NoMbc);
+        result += format!(" {}", mbc).as_str();
         write!(
             f,
             "{}{}{}{}{}{}",
@@ -186,16 +188,20 @@ impl fmt::Display for Cartridge {
 
 impl Cartridge {
     pub fn new(buf: &[Byte]) -> Result<Self> {
+        if buf.len() < 0x150 {
+            bail!("ROM is too small to have a header ({} bytes)", buf.len());
+        }
         let entry_point: [u8; 4] = buf[0x100..=0x103].try_into()?;
         let logo: [u8; 0x30] = buf[0x104..=0x133].try_into()?;
         let title = String::from_utf8_lossy(&buf[0x134..=0x143]).to_string();
+        let cgb_flag = buf[0x143] & 0x80 != 0;
         let new_licensee_code: [u8; 2] = buf[0x144..=0x145].try_into()?;
         let sgb_flag = match buf[0x146] {
             0x00 => false,
             0x03 => true,
             v => bail!("Invalid SGB flag: ${v:02X}"),
         };
-        let cartridge_type = CartridgeType::new(buf[0x0147]).unwrap();
+        let cartridge_type = CartridgeType::new(buf[0x0147])?;
         let rom_size = match buf[0x0148] {
             0x00 => 2 * 16 * 1024,
             0x01 => 4 * 16 * 1024,
@@ -243,6 +249,7 @@ impl Cartridge {
             logo,
             title,
             new_licensee_code,
+            cgb_flag,
             sgb_flag,
             cartridge_type,
             rom_size,
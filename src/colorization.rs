@@ -0,0 +1,264 @@
+use std::fmt;
+
+use crate::{cartridge::Cartridge, joypad::*, ppu::{DmgColors, LayerColors}, types::*};
+
+// the CGB boot ROM's palettes for DMG games, 4 RGB555 colours each
+const PALETTE_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+    0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+    0x231F, 0x035F, 0x00F2, 0x0009, // 17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+    0x299F, 0x001A, 0x000C, 0x0000, // 19
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+// where the BG/window and object palettes of a combination start in
+// PALETTE_COLORS; a few start one colour before a palette
+struct Combination {
+    obj0: usize,
+    obj1: usize,
+    bg: usize,
+}
+
+const fn p(palette: usize) -> usize {
+    palette * 4
+}
+
+const fn offsets(obj0: usize, obj1: usize, bg: usize) -> Combination {
+    Combination { obj0, obj1, bg }
+}
+
+const fn combo(obj0: usize, obj1: usize, bg: usize) -> Combination {
+    offsets(p(obj0), p(obj1), p(bg))
+}
+
+const COMBINATIONS: [Combination; 51] = [
+    combo(4, 4, 29), // 0
+    combo(18, 18, 18), // 1
+    combo(20, 20, 20), // 2
+    combo(24, 24, 24), // 3
+    combo(9, 9, 9), // 4
+    combo(0, 0, 0), // 5
+    combo(27, 27, 27), // 6
+    combo(5, 5, 5), // 7
+    combo(12, 12, 12), // 8
+    combo(26, 26, 26), // 9
+    combo(16, 8, 8), // 10
+    combo(4, 28, 28), // 11
+    combo(4, 2, 2), // 12
+    combo(3, 4, 4), // 13
+    combo(4, 29, 29), // 14
+    combo(28, 4, 28), // 15
+    combo(2, 17, 2), // 16
+    combo(16, 16, 8), // 17
+    combo(4, 4, 7), // 18
+    combo(4, 4, 18), // 19
+    combo(4, 4, 20), // 20
+    combo(19, 19, 9), // 21
+    offsets(p(4) - 1, p(4) - 1, p(11)), // 22
+    combo(17, 17, 2), // 23
+    combo(4, 4, 2), // 24
+    combo(4, 4, 3), // 25
+    combo(28, 28, 0), // 26
+    combo(3, 3, 0), // 27
+    combo(0, 0, 1), // 28
+    combo(18, 22, 18), // 29
+    combo(20, 22, 20), // 30
+    combo(24, 22, 24), // 31
+    combo(16, 22, 8), // 32
+    combo(17, 4, 13), // 33
+    offsets(p(28) - 1, p(0), p(14)), // 34
+    offsets(p(28) - 1, p(4), p(15)), // 35
+    combo(19, 22, 9), // 36
+    combo(16, 28, 10), // 37
+    combo(4, 23, 28), // 38
+    combo(17, 22, 2), // 39
+    combo(4, 0, 2), // 40
+    combo(4, 28, 3), // 41
+    combo(28, 3, 0), // 42
+    combo(3, 28, 4), // 43
+    combo(21, 28, 4), // 44
+    combo(3, 28, 0), // 45
+    combo(25, 3, 28), // 46
+    combo(0, 28, 8), // 47
+    combo(4, 3, 28), // 48
+    combo(28, 3, 6), // 49
+    combo(4, 28, 29), // 50
+];
+
+// sums of the title bytes the boot ROM knows; those after the first
+// UNIQUE_CHECKSUMS are shared by several titles
+const TITLE_CHECKSUMS: [Byte; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+const UNIQUE_CHECKSUMS: usize = 65;
+// 4th title letter of each shared checksum; a checksum shared by several
+// titles comes back every FOURTH_LETTER_STRIDE entries
+const FOURTH_LETTERS: &[Byte; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+const FOURTH_LETTER_STRIDE: usize = 14;
+// index into COMBINATIONS for each entry of TITLE_CHECKSUMS
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// the combination of unknown titles and other publishers, same as Right+A
+const DEFAULT_COMBINATION: usize = 0;
+
+/// The palettes a CGB offers for DMG games when a direction, optionally
+/// with A or B, is held while the boot logo shows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompatPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl fmt::Display for CompatPalette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            CompatPalette::Up => "Up",
+            CompatPalette::UpA => "Up+A",
+            CompatPalette::UpB => "Up+B",
+            CompatPalette::Left => "Left",
+            CompatPalette::LeftA => "Left+A",
+            CompatPalette::LeftB => "Left+B",
+            CompatPalette::Down => "Down",
+            CompatPalette::DownA => "Down+A",
+            CompatPalette::DownB => "Down+B",
+            CompatPalette::Right => "Right",
+            CompatPalette::RightA => "Right+A",
+            CompatPalette::RightB => "Right+B",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl CompatPalette {
+    /// The combo held in `buttons` (BUTTON_* flags): one direction, with A
+    /// or B. A wins over B.
+    pub fn from_buttons(buttons: Byte) -> Option<Self> {
+        use CompatPalette::*;
+        let [plain, a, b] = match buttons & 0xF0 {
+            BUTTON_UP => [Up, UpA, UpB],
+            BUTTON_LEFT => [Left, LeftA, LeftB],
+            BUTTON_DOWN => [Down, DownA, DownB],
+            BUTTON_RIGHT => [Right, RightA, RightB],
+            _ => return None,
+        };
+        Some(if buttons & BUTTON_A != 0 {
+            a
+        } else if buttons & BUTTON_B != 0 {
+            b
+        } else {
+            plain
+        })
+    }
+
+    pub fn colors(&self) -> LayerColors {
+        let combination = match self {
+            CompatPalette::Up => 5,
+            CompatPalette::UpA => 43,
+            CompatPalette::UpB => 28,
+            CompatPalette::Left => 48,
+            CompatPalette::LeftA => 40,
+            CompatPalette::LeftB => 7,
+            CompatPalette::Down => 8,
+            CompatPalette::DownA => 3,
+            CompatPalette::DownB => 49,
+            CompatPalette::Right => 1,
+            CompatPalette::RightA => 0,
+            CompatPalette::RightB => 6,
+        };
+        combination_colors(combination)
+    }
+}
+
+fn combination_colors(index: usize) -> LayerColors {
+    let c = &COMBINATIONS[index];
+    LayerColors {
+        bg: palette_colors(c.bg),
+        obj0: palette_colors(c.obj0),
+        obj1: palette_colors(c.obj1),
+    }
+}
+
+// 4 colours from `offset` on, 5 bits per channel widened to 8
+fn palette_colors(offset: usize) -> DmgColors {
+    let expand = |v: u16| {
+        let v = (v & 0x1F) as Byte;
+        (v << 3) | (v >> 2)
+    };
+    std::array::from_fn(|i| {
+        let color = PALETTE_COLORS[offset + i];
+        [expand(color), expand(color >> 5), expand(color >> 10)]
+    })
+}
+
+/// Palettes a CGB picks for a DMG cartridge.
+///
+/// Only games published by Nintendo are looked up, by the sum of their
+/// title bytes and, where titles share a sum, their 4th letter; everything
+/// else gets the Right+A palettes.
+pub fn lookup(cartridge: &Cartridge) -> LayerColors {
+    let nintendo = cartridge.old_licensee_code == 0x01
+        || (cartridge.old_licensee_code == 0x33 && &cartridge.new_licensee_code == b"01");
+    if !nintendo {
+        return combination_colors(DEFAULT_COMBINATION);
+    }
+
+    let title = &cartridge.rom.buf[0x134..=0x143];
+    let checksum = title.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    let entry = TITLE_CHECKSUMS.iter().position(|&c| c == checksum).and_then(|i| {
+        if i < UNIQUE_CHECKSUMS {
+            return Some(i);
+        }
+        (i - UNIQUE_CHECKSUMS..FOURTH_LETTERS.len())
+            .step_by(FOURTH_LETTER_STRIDE)
+            .find(|&j| FOURTH_LETTERS[j] == title[3])
+            .map(|j| UNIQUE_CHECKSUMS + j)
+    });
+    combination_colors(entry.map_or(DEFAULT_COMBINATION, |i| CHECKSUM_COMBINATIONS[i] as usize))
+}
@@ -7,6 +7,10 @@ pub const M_ARR: [&str; 1] = ["(C)"];
 pub const MM_ARR: [&str; 4] = ["(BC)", "(DE)", "(HL)", "(AF)"];
 pub const COND_ARR: [&str; 4] = ["Z", "NZ", "C", "NC"];
 
+// cpu
+// M-cycles the CPU stays stopped after a CGB speed switch
+pub const SPEED_SWITCH_CYCLES: u16 = 2050;
+
 // ppu
 pub const SPRITE_NUM: u16 = 40;
 pub const CYCLE_PER_LINE: u16 = 456;
@@ -24,6 +28,9 @@ pub const BG_TILE_MAP_AREA_1: Word = 0x9C00;
 // screen
 pub const SCREEN_WIDTH: u8 = 160;
 pub const SCREEN_HEIGHT: u8 = 144;
+// Super Game Boy output, the screen framed by the border
+pub const SGB_SCREEN_WIDTH: u16 = 256;
+pub const SGB_SCREEN_HEIGHT: u16 = 224;
 
 // interrupt
 pub const INT_VBLANK_FLG: Byte = 0x01;
@@ -68,6 +75,8 @@ pub const ADDR_APU_NR44: Word = 0xFF23;
 pub const ADDR_APU_NR50: Word = 0xFF24;
 pub const ADDR_APU_NR51: Word = 0xFF25;
 pub const ADDR_APU_NR52: Word = 0xFF26;
+pub const ADDR_APU_WAVE_START: Word = 0xFF30;
+pub const ADDR_APU_WAVE_END: Word = 0xFF3F;
 pub const ADDR_PPU_LCDC: Word = 0xFF40;
 pub const ADDR_PPU_LCDS: Word = 0xFF41;
 pub const ADDR_PPU_SCY: Word = 0xFF42;
@@ -80,7 +89,16 @@ pub const ADDR_PPU_OBP0: Word = 0xFF48;
 pub const ADDR_PPU_OBP1: Word = 0xFF49;
 pub const ADDR_PPU_WY: Word = 0xFF4A;
 pub const ADDR_PPU_WX: Word = 0xFF4B;
+pub const ADDR_KEY1: Word = 0xFF4D;
+pub const ADDR_VBK: Word = 0xFF4F;
+pub const ADDR_BOOT: Word = 0xFF50;
+pub const ADDR_HDMA1: Word = 0xFF51;
+pub const ADDR_HDMA2: Word = 0xFF52;
+pub const ADDR_HDMA3: Word = 0xFF53;
+pub const ADDR_HDMA4: Word = 0xFF54;
+pub const ADDR_HDMA5: Word = 0xFF55;
 pub const ADDR_PPU_BCPS: Word = 0xFF68;
 pub const ADDR_PPU_BCPD: Word = 0xFF69;
 pub const ADDR_PPU_OCPS: Word = 0xFF6A;
 pub const ADDR_PPU_OCPD: Word = 0xFF6B;
+pub const ADDR_SVBK: Word = 0xFF70;
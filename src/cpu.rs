@@ -4,8 +4,11 @@ use crate::{
     types::*,
     util::*,
     interrupt::Interrupt,
+    ppu::OamBugAccess,
+    state::*,
 };
 
+use anyhow::Result;
 use bitvec::prelude::*;
 use std::{
     fmt,
@@ -18,6 +21,10 @@ pub struct Cpu {
     pub interrupt: Arc<Mutex<Interrupt>>,
     pub halted: bool,
     pub ime: bool,
+    // M-cycles left before the CPU runs again, e.g. after a speed switch
+    pub stall: u16,
+    // M-cycles the rest of the machine already ran through in the current step
+    bus_cycles: u16,
 }
 
 #[allow(non_snake_case)]
@@ -61,6 +68,27 @@ impl Default for Register {
     }
 }
 impl Register {
+    /// all zero, as left by reset before a boot ROM runs
+    pub fn power_on() -> Self {
+        Self {
+            A: 0x00,
+            B: 0x00,
+            C: 0x00,
+            D: 0x00,
+            E: 0x00,
+            F: Flags {
+                z: false,
+                n: false,
+                h: false,
+                c: false,
+            },
+            H: 0x00,
+            L: 0x00,
+            SP: 0x0000,
+            PC: 0x0000,
+        }
+    }
+
     pub fn r(&self, r: &String) -> Byte {
         match r.as_str() {
             "A" => self.A,
@@ -233,10 +261,28 @@ impl Cpu {
             reg: Register::default(),
             halted: false,
             ime: false,
+            stall: 0,
+            bus_cycles: 0,
         }
     }
 
     pub fn step(&mut self) -> u16 {
+        self.bus_cycles = 0;
+        let cycles = self.exec();
+        // the rest of the machine runs through the other M-cycles too
+        let mut bus = self.bus.lock().unwrap();
+        for _ in self.bus_cycles..cycles {
+            bus.tick();
+        }
+        cycles
+    }
+
+    fn exec(&mut self) -> u16 {
+        if self.stall > 0 {
+            self.stall -= 1;
+            return 1;
+        }
+
         if self.halted{
             if self.interrupt.lock().unwrap().has() {
                 self.halted = false;
@@ -266,8 +312,40 @@ impl Cpu {
         return op.cycles as u16 + additional_cycle as u16;
     }
 
+    // the access sees the machine as it was at the start of its M-cycle,
+    // the rest of the machine then runs through it
+    pub fn read(&mut self, addr: Word) -> Byte {
+        self.read_with(addr, OamBugAccess::Read)
+    }
+
+    fn read_with(&mut self, addr: Word, access: OamBugAccess) -> Byte {
+        let mut bus = self.bus.lock().unwrap();
+        bus.trigger_oam_bug(addr, access);
+        let value = bus.read(addr);
+        bus.tick();
+        self.bus_cycles += 1;
+        value
+    }
+
+    pub fn write(&mut self, addr: Word, value: Byte) {
+        let mut bus = self.bus.lock().unwrap();
+        bus.trigger_oam_bug(addr, OamBugAccess::Write);
+        bus.write(addr, value);
+        bus.tick();
+        self.bus_cycles += 1;
+    }
+
+    /// An M-cycle where the IDU increments or decrements `addr`, a 16-bit
+    /// register, putting it on the bus like a write would.
+    pub fn idu_cycle(&mut self, addr: Word) {
+        let mut bus = self.bus.lock().unwrap();
+        bus.trigger_oam_bug(addr, OamBugAccess::Write);
+        bus.tick();
+        self.bus_cycles += 1;
+    }
+
     pub fn fetch(&mut self) -> Byte {
-        let buf = self.bus.lock().unwrap().read(self.reg.PC);
+        let buf = self.read(self.reg.PC);
         self.reg.PC = self.reg.PC.wrapping_add(1);
         return buf;
     }
@@ -280,26 +358,37 @@ impl Cpu {
 
     pub fn push(&mut self, buf: Byte) {
         self.reg.SP = self.reg.SP.wrapping_sub(1);
-        self.bus.lock().unwrap().write(self.reg.SP, buf)
+        self.write(self.reg.SP, buf)
+    }
+
+    // SP is decremented once before the first write
+    pub fn push16(&mut self, value: Word) {
+        self.idu_cycle(self.reg.SP);
+        self.push(extract_upper(value));
+        self.push(extract_lower(value));
     }
 
     // push PC
     pub fn push_pc(&mut self) {
-        self.push(extract_upper(self.reg.PC));
-        self.push(extract_lower(self.reg.PC));
+        self.push16(self.reg.PC);
     }
 
     pub fn pop(&mut self) -> Byte {
-        let d = self.bus.lock().unwrap().read(self.reg.SP);
+        let d = self.read(self.reg.SP);
         self.reg.SP = self.reg.SP.wrapping_add(1);
         return d;
     }
 
-    pub fn pop_pc(&mut self) {
-        let lower = self.pop();
+    // the first read happens while SP is incremented
+    pub fn pop16(&mut self) -> Word {
+        let lower = self.read_with(self.reg.SP, OamBugAccess::ReadIncrease);
+        self.reg.SP = self.reg.SP.wrapping_add(1);
         let upper = self.pop();
+        bytes_2_word(upper, lower)
+    }
 
-        self.reg.PC = bytes_2_word(upper, lower)
+    pub fn pop_pc(&mut self) {
+        self.reg.PC = self.pop16()
     }
 
     pub fn load(&mut self, reg: &String) -> Word {
@@ -309,7 +398,7 @@ impl Cpu {
             // m
             "(C)" => {
                 let r = self.reg.C;
-                self.bus.lock().unwrap().read(bytes_2_word(0xFF, r)) as Word
+                self.read(bytes_2_word(0xFF, r)) as Word
             },
             // d
             "d" => {
@@ -321,14 +410,14 @@ impl Cpu {
             // a
             "(a)" => {
                 let addr = self.fetch();
-                self.bus.lock().unwrap().read(bytes_2_word(0xFF as Byte, addr)) as Word
+                self.read(bytes_2_word(0xFF as Byte, addr)) as Word
             },
             "aa" => {
                 self.fetch16()
             },
             "(aa)" => {
                 let addr = self.fetch16();
-                self.bus.lock().unwrap().read(addr) as Word
+                self.read(addr) as Word
             },
             // rr
             "AF" | "BC" | "DE" | "HL" | "HLD" | "HLI" | "PC" | "SP" => self.reg.r16(reg),
@@ -336,7 +425,13 @@ impl Cpu {
             "(BC)" | "(DE)" | "(HL)" | "(HLI)" | "(HLD)" => {
                 let mut s = reg.replace("(", "");
                 s = s.replace(")", "");
-                self.bus.lock().unwrap().read(self.reg.r16(&s)) as Word
+                let addr = self.reg.r16(&s);
+                // HL+ and HL- are read while HL is stepped
+                let access = match reg.as_str() {
+                    "(HLI)" | "(HLD)" => OamBugAccess::ReadIncrease,
+                    _ => OamBugAccess::Read,
+                };
+                self.read_with(addr, access) as Word
             }, 
             &_ => unreachable!()
         }
@@ -351,16 +446,16 @@ impl Cpu {
                 let mut s = reg.replace("(", "");
                 s = s.replace(")", "");
                 let addr = bytes_2_word(0xFF, self.reg.r(&s));
-                self.bus.lock().unwrap().write(addr, value as Byte);
+                self.write(addr, value as Byte);
             },
             // a
             "(a)" => {
                 let addr = self.fetch();
-                self.bus.lock().unwrap().write(bytes_2_word(0xFF as Byte, addr), value as Byte);
+                self.write(bytes_2_word(0xFF as Byte, addr), value as Byte);
             },
             "(aa)" => {
                 let addr = self.fetch16();
-                self.bus.lock().unwrap().write(addr, value as Byte);
+                self.write(addr, value as Byte);
             },
             // rr
             "AF" | "BC" | "DE" | "HL" | "SP" | "PC" => self.reg.r16_mut(reg, value),
@@ -369,7 +464,7 @@ impl Cpu {
                 let mut s = reg.replace("(", "");
                 s = s.replace(")", "");
                 let addr = self.reg.r16(&s);
-                self.bus.lock().unwrap().write(addr, value as Byte);
+                self.write(addr, value as Byte);
             }, 
             &_ => unreachable!()
         }
@@ -446,6 +541,33 @@ impl Cpu {
     }
 }
 
+impl Savable for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.reg.af());
+        w.write_u16(self.reg.bc());
+        w.write_u16(self.reg.de());
+        w.write_u16(self.reg.hl());
+        w.write_u16(self.reg.SP);
+        w.write_u16(self.reg.PC);
+        w.write_bool(self.halted);
+        w.write_bool(self.ime);
+        w.write_u16(self.stall);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.reg.af_mut(r.read_u16()?);
+        self.reg.bc_mut(r.read_u16()?);
+        self.reg.de_mut(r.read_u16()?);
+        self.reg.hl_mut(r.read_u16()?);
+        self.reg.SP = r.read_u16()?;
+        self.reg.PC = r.read_u16()?;
+        self.halted = r.read_bool()?;
+        self.ime = r.read_bool()?;
+        self.stall = r.read_u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,246 @@
+use crate::{gameboy::GameBoy, types::*};
+use anyhow::{bail, Result};
+
+pub const MAX_LINKED: usize = 4;
+
+const DOTS_PER_FRAME: u32 = 70224;
+
+// ping packet header, and what a Game Boy answers to it
+const PING_HEADER: Byte = 0xFE;
+const PING_ACK: Byte = 0x88;
+// four of these from player 1 end the ping phase, acknowledged with four 0xCC
+const START_REQUEST: Byte = 0xAA;
+const START_ACK: Byte = 0xCC;
+// a whole packet of these from every player goes back to the ping phase
+const RESTART_REQUEST: Byte = 0xFF;
+
+const PING_PACKET_SIZE: usize = 4;
+// the adapter clocks every port once per byte
+const PING_BYTE_DOTS: u32 = 8192;
+const TRANSMISSION_BYTE_DOTS: u32 = 1024;
+// added per step of the RATE player 1 asked for
+const RATE_STEP_DOTS: u32 = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    /// Pinging the ports to see who's there, player 1 picks the rate and packet size.
+    Ping,
+    /// Acknowledging player 1's request to start.
+    Starting,
+    /// Relaying packets between the players.
+    Transmission,
+}
+
+/// DMG-07 four player adapter.
+///
+/// Links up to four Game Boys in one process, clocking every serial port
+/// itself; the games wait on an external clock. In the ping phase it sends
+/// each port 0xFE followed by three status bytes: the players that answered
+/// 0x88 in the high nibble and the port's player number in the low one.
+/// Player 1 answers the status bytes with 0x88, the rate and the packet size.
+/// Once player 1 sends 0xAA four times it answers 0xCC four times and starts
+/// transmitting. A transmission frame is four packets long: every player sends
+/// its packet during the first one, and the adapter buffers them to send all
+/// four, in player order, to everyone during the next frame.
+pub struct Dmg07 {
+    players: Vec<GameBoy>,
+    // dots each Game Boy ran past the last byte
+    ahead: Vec<u32>,
+    until_byte: u32,
+
+    phase: Phase,
+    // byte of the current packet, or of the 0xCC acknowledgement
+    position: usize,
+    // bit n: player n+1 answered the ping
+    connected: Byte,
+    answered: Byte,
+    start_requests: usize,
+    rate: Byte,
+    packet_size: usize,
+
+    // what each player sent in the current frame
+    incoming: Vec<Vec<Byte>>,
+    // everyone's packets from the last frame, being sent now
+    outgoing: Vec<Byte>,
+}
+
+impl Dmg07 {
+    /// Plug `players` into ports 1 to 4, in order.
+    pub fn new(players: Vec<GameBoy>) -> Result<Self> {
+        if players.is_empty() || players.len() > MAX_LINKED {
+            bail!("The DMG-07 links 1 to {} Game Boys, got {}", MAX_LINKED, players.len());
+        }
+        let count = players.len();
+        Ok(Self {
+            players,
+            ahead: vec![0; count],
+            until_byte: PING_BYTE_DOTS,
+            phase: Phase::Ping,
+            position: 0,
+            connected: 0,
+            answered: 0,
+            start_requests: 0,
+            rate: 0,
+            packet_size: 1,
+            incoming: vec![Vec::new(); MAX_LINKED],
+            outgoing: Vec::new(),
+        })
+    }
+
+    pub fn players(&self) -> &[GameBoy] {
+        &self.players
+    }
+
+    pub fn players_mut(&mut self) -> &mut [GameBoy] {
+        &mut self.players
+    }
+
+    pub fn into_players(self) -> Vec<GameBoy> {
+        self.players
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Bit n is set when player n+1 answered the last ping.
+    pub fn connected(&self) -> Byte {
+        self.connected
+    }
+
+    /// Bytes per player in each transmission frame, as asked by player 1.
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Run every Game Boy for one frame's worth of dots, clocking the link between them.
+    pub fn exec_frame(&mut self) {
+        self.run_dots(DOTS_PER_FRAME);
+    }
+
+    pub fn run_dots(&mut self, dots: u32) {
+        let mut left = dots;
+        while left > 0 {
+            let slice = left.min(self.until_byte);
+            for (gb, ahead) in self.players.iter_mut().zip(self.ahead.iter_mut()) {
+                *ahead = if *ahead >= slice {
+                    *ahead - slice
+                } else {
+                    let behind = slice - *ahead;
+                    gb.run_dots(behind) - behind
+                };
+            }
+            left -= slice;
+            self.until_byte -= slice;
+            if self.until_byte == 0 {
+                self.clock_byte();
+                self.until_byte = self.byte_dots();
+            }
+        }
+    }
+
+    fn byte_dots(&self) -> u32 {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_BYTE_DOTS,
+            Phase::Transmission => TRANSMISSION_BYTE_DOTS + (self.rate & 0x0F) as u32 * RATE_STEP_DOTS,
+        }
+    }
+
+    // shift one byte through every port at once
+    fn clock_byte(&mut self) {
+        let mut received = [RESTART_REQUEST; MAX_LINKED];
+        for (i, gb) in self.players.iter_mut().enumerate() {
+            let sent = match self.phase {
+                Phase::Ping if self.position == 0 => PING_HEADER,
+                Phase::Ping => self.connected << 4 | (i as Byte + 1),
+                Phase::Starting => START_ACK,
+                Phase::Transmission => self.outgoing[self.position],
+            };
+            // a port nobody is listening on reads as all ones
+            received[i] = gb.serial_exchange(sent).unwrap_or(0xFF);
+        }
+
+        match self.phase {
+            Phase::Ping => self.receive_ping(received),
+            Phase::Starting => {
+                self.position += 1;
+                if self.position == PING_PACKET_SIZE {
+                    self.start_transmission();
+                }
+            }
+            Phase::Transmission => self.receive_packet(received),
+        }
+    }
+
+    fn receive_ping(&mut self, received: [Byte; MAX_LINKED]) {
+        match self.position {
+            0 => {
+                for (i, b) in received.iter().enumerate().take(self.players.len()) {
+                    if *b == PING_ACK {
+                        self.answered |= 1 << i;
+                    }
+                }
+            }
+            2 if self.answered & 0x01 != 0 => self.rate = received[0],
+            3 if self.answered & 0x01 != 0 => self.packet_size = (received[0] as usize).max(1),
+            _ => (),
+        }
+
+        if received[0] == START_REQUEST {
+            self.start_requests += 1;
+        } else {
+            self.start_requests = 0;
+        }
+        if self.start_requests == PING_PACKET_SIZE {
+            // the players who answered before stay connected
+            self.phase = Phase::Starting;
+            self.position = 0;
+            self.answered = 0;
+            self.start_requests = 0;
+            return;
+        }
+
+        self.position += 1;
+        if self.position == PING_PACKET_SIZE {
+            self.position = 0;
+            self.connected = self.answered;
+            self.answered = 0;
+        }
+    }
+
+    fn start_transmission(&mut self) {
+        self.phase = Phase::Transmission;
+        self.position = 0;
+        self.outgoing = vec![0; self.packet_size * MAX_LINKED];
+        self.incoming.iter_mut().for_each(Vec::clear);
+    }
+
+    fn receive_packet(&mut self, received: [Byte; MAX_LINKED]) {
+        if self.position < self.packet_size {
+            for (i, incoming) in self.incoming.iter_mut().enumerate() {
+                // empty ports send zeros
+                let connected = i < self.players.len() && self.connected & (1 << i) != 0;
+                incoming.push(if connected { received[i] } else { 0x00 });
+            }
+        }
+
+        self.position += 1;
+        if self.position < self.outgoing.len() {
+            return;
+        }
+
+        let restart = (0..self.players.len())
+            .filter(|i| self.connected & (1 << i) != 0)
+            .all(|i| self.incoming[i].iter().all(|b| *b == RESTART_REQUEST));
+        if restart {
+            self.phase = Phase::Ping;
+            self.position = 0;
+            self.answered = 0;
+            return;
+        }
+
+        self.position = 0;
+        self.outgoing = self.incoming.concat();
+        self.incoming.iter_mut().for_each(Vec::clear);
+    }
+}
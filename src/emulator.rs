@@ -1,4 +1,11 @@
-use crate::{constant::*, gameboy::GameBoy};
+use crate::{
+    gameboy::{GameBoy, Snapshot},
+    movie::Movie,
+    palettes::Palettes,
+    ppu::LayerColors,
+    rewind::RewindBuffer,
+};
+use anyhow::{bail, Context, Result};
 use bevy::{
     prelude::*,
     render::render_resource::{Extent3d, TextureDimension, TextureFormat},
@@ -8,40 +15,33 @@ use bevy::{
     },
 };
 use bevy_tiled_camera::TiledCameraPlugin;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 pub struct EmulatorPlugin;
 
 impl Plugin for EmulatorPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Startup, setup_emulator_system)
-            .add_systems(Update, emulator_system);
+            .add_systems(Update, (emulator_system, run_ahead_system, palette_system, movie_system));
     }
 }
 
 fn setup_emulator_system(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
+    emulator: Res<Emulator>,
 ) {
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() == 1 {
-        println!("Please input rom file path as args 1");
-        return;
-    }
-
-    let bytes = std::fs::read(&args[1]).unwrap();
-    
-    let gb = GameBoy::new(&bytes);
-    let emulator = Emulator::new(gb);
-    commands.insert_resource(emulator);
-
+    let (width, height) = emulator.gb.screen_size();
     let img = Image::new(
         Extent3d {
-            width: SCREEN_WIDTH as u32,
-            height: SCREEN_HEIGHT as u32,
+            width,
+            height,
             depth_or_array_layers: 1,
         },
         TextureDimension::D2,
-        vec![0; (SCREEN_WIDTH as u32 * SCREEN_HEIGHT as u32 * 4) as usize],
+        vec![0; (width * height * 4) as usize],
         TextureFormat::Rgba8UnormSrgb,
     );
 
@@ -55,6 +55,91 @@ fn setup_emulator_system(
         .insert(ScreenSprite);
 
     commands.insert_resource(GameScreen(texture));
+
+    // slot thumbnail shown in the top right corner
+    let thumbnail = images.add(screen_image(&image::RgbaImage::new(width, height)));
+    commands
+        .spawn(SpriteBundle {
+            texture: thumbnail.clone(),
+            transform: Transform::from_xyz(
+                width as f32 / 4.0,
+                height as f32 / 4.0,
+                1.0,
+            )
+            .with_scale(Vec3::new(0.5, 0.5, 1.0)),
+            visibility: Visibility::Hidden,
+            ..Default::default()
+        })
+        .insert(ThumbnailSprite);
+    let mut timer = Timer::from_seconds(2.0, TimerMode::Once);
+    timer.tick(timer.duration());
+    commands.insert_resource(SlotThumbnail {
+        image: thumbnail,
+        timer,
+    });
+}
+
+fn screen_image(image_data: &image::RgbaImage) -> Image {
+    Image::new(
+        Extent3d {
+            width: image_data.width(),
+            height: image_data.height(),
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        image_data.as_raw().clone(),
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/// F2: cycle run-ahead frames 0-4, P: pause
+fn run_ahead_system(mut emulator: ResMut<Emulator>, keys: Res<Input<KeyCode>>) {
+    if keys.just_pressed(KeyCode::F2) {
+        let frames = (emulator.run_ahead() + 1) % (MAX_RUN_AHEAD + 1);
+        emulator.set_run_ahead(frames).unwrap();
+        println!("run-ahead: {} frames", frames);
+    }
+    if keys.just_pressed(KeyCode::P) {
+        emulator.paused = !emulator.paused;
+        println!("{}", if emulator.paused { "paused" } else { "resumed" });
+    }
+}
+
+/// F3: switch to the next DMG palette
+fn palette_system(mut emulator: ResMut<Emulator>, keys: Res<Input<KeyCode>>) {
+    if keys.just_pressed(KeyCode::F3) {
+        let name = emulator.next_palette();
+        println!("palette: {}", name);
+    }
+}
+
+/// F6: start/stop recording, F7: start/stop playback,
+/// F8 while playing: truncate the movie here and continue recording
+fn movie_system(mut emulator: ResMut<Emulator>, keys: Res<Input<KeyCode>>) {
+    let result = if keys.just_pressed(KeyCode::F6) {
+        match emulator.movie_mode {
+            MovieMode::Recording => emulator.stop_movie(),
+            _ => {
+                emulator.record_movie();
+                println!("recording movie");
+                Ok(())
+            }
+        }
+    } else if keys.just_pressed(KeyCode::F7) {
+        match emulator.movie_mode {
+            MovieMode::Playing(_) => emulator.stop_movie(),
+            _ => emulator.play_movie().map(|_| println!("playing movie")),
+        }
+    } else if keys.just_pressed(KeyCode::F8) {
+        emulator.resume_recording();
+        Ok(())
+    } else {
+        Ok(())
+    };
+
+    if let Err(e) = result {
+        println!("movie: {:#}", e);
+    }
 }
 
 #[derive(Component)]
@@ -63,23 +148,57 @@ pub struct ScreenSprite;
 #[derive(Resource)]
 pub struct GameScreen(pub Handle<Image>);
 
+#[derive(Component)]
+pub struct ThumbnailSprite;
+
+#[derive(Resource)]
+pub struct SlotThumbnail {
+    pub image: Handle<Image>,
+    pub timer: Timer,
+}
+
 fn emulator_system(
     screen: Res<GameScreen>,
     mut emulator: ResMut<Emulator>,
     mut images: ResMut<Assets<Image>>,
+    keys: Res<Input<KeyCode>>,
 ) {
-    emulator.gb.exec_frame();
-    emulator.frame = emulator.frame.wrapping_add(1);
+    let image_data;
 
-    let image_data = emulator.gb.display();
+    // hold Backspace to rewind
+    if keys.pressed(KeyCode::Back) {
+        if emulator.movie_mode != MovieMode::Off {
+            emulator.stop_movie().unwrap_or_else(|e| println!("movie: {:#}", e));
+        }
+        if let Some(state) = emulator.rewind.pop() {
+            emulator.gb.load_state(&state).unwrap();
+        }
+        image_data = emulator.gb.display();
+    } else if emulator.paused {
+        image_data = emulator.gb.display();
+    } else {
+        // run as many frames as the speed allows, only the last one is shown
+        emulator.speed_credit += emulator.speed;
+        let frames = emulator.speed_credit as u32;
+        emulator.speed_credit -= frames as f32;
+        for _ in 1..frames {
+            emulator.run_frame(0);
+        }
+        if frames > 0 {
+            let run_ahead = emulator.run_ahead;
+            image_data = emulator.run_frame(run_ahead);
+        } else {
+            image_data = emulator.gb.display();
+        }
+    }
 
     let image = images.get_mut(&screen.0).unwrap();
 
-    for y in 0..SCREEN_HEIGHT {
-        for x in 0..SCREEN_WIDTH {
-            let ix = y as usize * SCREEN_WIDTH as usize + x as usize;
+    for y in 0..image_data.height() {
+        for x in 0..image_data.width() {
+            let ix = (y * image_data.width() + x) as usize;
             let pixel = &mut image.data[ix * 4..ix * 4 + 4];
-            let image_pixel: &[u8] = &image_data.get_pixel(x as u32, y as u32).0;
+            let image_pixel: &[u8] = &image_data.get_pixel(x, y).0;
             pixel[0] = image_pixel[0];
             pixel[1] = image_pixel[1];
             pixel[2] = image_pixel[2];
@@ -89,24 +208,278 @@ fn emulator_system(
 }
 
 
+pub const SAVE_SLOT_NUM: u8 = 4;
+// take a rewind snapshot every N frames
+pub const REWIND_INTERVAL: u32 = 2;
+pub const REWIND_BUDGET: usize = 64 * 1024 * 1024;
+pub const MAX_RUN_AHEAD: u8 = 4;
+// run-ahead is disabled after this many consecutive frames over budget
+pub const RUN_AHEAD_SLOW_FRAMES: u32 = 60;
+pub const RUN_AHEAD_FRAME_BUDGET: Duration = Duration::from_millis(14);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MovieMode {
+    Off,
+    Recording,
+    /// next frame to play
+    Playing(usize),
+}
+
+pub struct EmulatorOptions {
+    pub rom_path: PathBuf,
+    pub scale: u32,
+    /// the first of the palettes F3 goes through, followed by `palettes`
+    pub colors: LayerColors,
+    /// built-in and palette file palettes for F3
+    pub palettes: Palettes,
+    /// save states and movies go here, next to the ROM if None
+    pub save_dir: Option<PathBuf>,
+    pub speed: f32,
+    pub paused: bool,
+}
+
 #[derive(Resource)]
 pub struct Emulator {
     pub gb: GameBoy,
     pub frame: u32,
+    pub rom_path: PathBuf,
+    pub save_dir: PathBuf,
+    pub slot: u8,
+    pub speed: f32,
+    pub paused: bool,
+    pub rewind: RewindBuffer,
+    pub movie: Option<Movie>,
+    pub movie_mode: MovieMode,
+    // the DMG palettes to pick from and the one in use
+    palettes: Vec<(String, LayerColors)>,
+    palette: usize,
+    run_ahead: u8,
+    // reused by run-ahead every frame
+    snapshot: Snapshot,
+    slow_frames: u32,
+    speed_credit: f32,
 }
 
 impl Emulator {
-    pub fn new(gb: GameBoy) -> Self {
-        Self { gb: gb, frame: 0 }
+    pub fn new(gb: GameBoy, rom_path: &Path) -> Self {
+        let save_dir = rom_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Self {
+            gb: gb,
+            frame: 0,
+            rom_path: rom_path.to_path_buf(),
+            save_dir,
+            slot: 1,
+            speed: 1.0,
+            paused: false,
+            rewind: RewindBuffer::new(REWIND_BUDGET),
+            movie: None,
+            movie_mode: MovieMode::Off,
+            palettes: Palettes::default().entries().to_vec(),
+            palette: 0,
+            run_ahead: 0,
+            snapshot: Snapshot::default(),
+            slow_frames: 0,
+            speed_credit: 0.0,
+        }
+    }
+
+    /// Run a real frame, showing `run_ahead` frames ahead of it.
+    fn run_frame(&mut self, run_ahead: u8) -> image::RgbaImage {
+        let start = Instant::now();
+        self.exec_frame();
+        self.frame = self.frame.wrapping_add(1);
+
+        if self.frame % REWIND_INTERVAL == 0 {
+            self.rewind.push(self.gb.save_state());
+        }
+
+        if run_ahead > 0 {
+            // show the frame N frames ahead with the current input
+            let image_data = self.gb.run_ahead(run_ahead, &mut self.snapshot);
+            self.check_run_ahead_speed(start.elapsed());
+            image_data
+        } else {
+            self.gb.display()
+        }
+    }
+
+    /// Switch to the next palette, returning its name.
+    pub fn next_palette(&mut self) -> &str {
+        self.palette = (self.palette + 1) % self.palettes.len();
+        let (name, colors) = &self.palettes[self.palette];
+        self.gb.set_layer_colors(*colors);
+        name
+    }
+
+    pub fn run_ahead(&self) -> u8 {
+        self.run_ahead
+    }
+
+    pub fn set_run_ahead(&mut self, frames: u8) -> Result<()> {
+        if frames > MAX_RUN_AHEAD {
+            bail!("run-ahead must be 0-{} frames, got {}", MAX_RUN_AHEAD, frames);
+        }
+        self.run_ahead = frames;
+        self.slow_frames = 0;
+        Ok(())
+    }
+
+    fn check_run_ahead_speed(&mut self, elapsed: Duration) {
+        if elapsed <= RUN_AHEAD_FRAME_BUDGET {
+            self.slow_frames = 0;
+            return;
+        }
+
+        self.slow_frames += 1;
+        if self.slow_frames >= RUN_AHEAD_SLOW_FRAMES {
+            println!(
+                "run-ahead disabled: {} frames took {:?} (budget {:?})",
+                self.run_ahead + 1,
+                elapsed,
+                RUN_AHEAD_FRAME_BUDGET
+            );
+            self.run_ahead = 0;
+            self.slow_frames = 0;
+        }
     }
 
-    pub fn run() {
+    /// Run one frame, recording or playing back the movie if active.
+    pub fn exec_frame(&mut self) {
+        match self.movie_mode {
+            MovieMode::Off => self.gb.exec_frame(),
+            MovieMode::Recording => {
+                let inputs = self.gb.joypad.lock().unwrap().states();
+                self.movie.as_mut().unwrap().record_frame_players(&mut self.gb, inputs);
+            }
+            MovieMode::Playing(i) => {
+                let movie = self.movie.as_ref().unwrap();
+                match movie.play_frame(&mut self.gb, i) {
+                    Ok(_) if i + 1 < movie.len() => self.movie_mode = MovieMode::Playing(i + 1),
+                    Ok(_) => {
+                        println!("movie finished");
+                        self.stop_movie().unwrap();
+                    }
+                    Err(e) => {
+                        println!("movie: {:#}", e);
+                        self.stop_movie().unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// `<save dir>/<rom file name>.<ext>`
+    fn save_path(&self, ext: &str) -> PathBuf {
+        let name = self.rom_path.file_name().unwrap_or_default().to_string_lossy();
+        self.save_dir.join(format!("{}.{}", name, ext))
+    }
+
+    pub fn movie_path(&self) -> PathBuf {
+        self.save_path("rbm")
+    }
+
+    /// Start recording a movie from the current state.
+    pub fn record_movie(&mut self) {
+        self.movie = Some(Movie::from_state(&self.gb));
+        self.movie_mode = MovieMode::Recording;
+    }
+
+    /// Restart from the beginning of the saved movie and play it back.
+    pub fn play_movie(&mut self) -> Result<()> {
+        let movie = Movie::load(&self.movie_path())?;
+        if movie.is_empty() {
+            bail!("{} has no frames", self.movie_path().display());
+        }
+        let rom = std::fs::read(&self.rom_path)?;
+        // same boot ROM, renderer and colours as now, in the movie's model
+        let colors = self.gb.layer_colors();
+        self.gb = movie.start(&rom, self.gb.config())?;
+        self.gb.set_layer_colors(colors);
+        self.rewind.clear();
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Playing(0);
+        Ok(())
+    }
+
+    /// Drop the rest of the playing movie and record from here.
+    pub fn resume_recording(&mut self) {
+        if let MovieMode::Playing(i) = self.movie_mode {
+            self.movie.as_mut().unwrap().truncate(i);
+            self.movie_mode = MovieMode::Recording;
+            println!("recording movie from frame {}", i);
+        }
+    }
+
+    /// Stop the movie, saving it if it was being recorded.
+    pub fn stop_movie(&mut self) -> Result<()> {
+        let mode = std::mem::replace(&mut self.movie_mode, MovieMode::Off);
+        let Some(movie) = &self.movie else {
+            return Ok(());
+        };
+        println!(
+            "movie: {} frames, {} lag frames",
+            movie.len(),
+            movie.lag_frames().len()
+        );
+        if mode == MovieMode::Recording {
+            movie.save(&self.movie_path())?;
+            println!("movie saved to {}", self.movie_path().display());
+        }
+        Ok(())
+    }
+
+    pub fn state_path(&self, slot: u8) -> PathBuf {
+        self.save_path(&format!("ss{}", slot))
+    }
+
+    pub fn thumbnail_path(&self, slot: u8) -> PathBuf {
+        self.save_path(&format!("ss{}.png", slot))
+    }
+
+    pub fn save_slot(&self) -> Result<()> {
+        std::fs::write(self.state_path(self.slot), self.gb.save_state())?;
+        self.gb.display().save(self.thumbnail_path(self.slot))?;
+        Ok(())
+    }
+
+    pub fn load_slot(&mut self) -> Result<()> {
+        if self.movie_mode != MovieMode::Off {
+            self.stop_movie()?;
+        }
+        let buf = std::fs::read(self.state_path(self.slot))
+            .with_context(|| format!("slot {} is empty", self.slot))?;
+        self.gb.load_state(&buf)
+    }
+
+    pub fn thumbnail(&self, slot: u8) -> Option<image::RgbaImage> {
+        image::open(self.thumbnail_path(slot)).ok().map(|i| i.to_rgba8())
+    }
+
+    pub fn run(mut gb: GameBoy, options: EmulatorOptions) -> Result<()> {
+        gb.set_layer_colors(options.colors);
+        let mut emulator = Emulator::new(gb, &options.rom_path);
+        emulator.palettes = options.palettes.entries().to_vec();
+        // the colours asked for come first, unless they're one of the named palettes
+        match emulator.palettes.iter().position(|(_, colors)| *colors == options.colors) {
+            Some(i) => emulator.palette = i,
+            None => emulator.palettes.insert(0, ("custom".to_string(), options.colors)),
+        }
+        if let Some(dir) = options.save_dir {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            emulator.save_dir = dir;
+        }
+        emulator.speed = options.speed;
+        emulator.paused = options.paused;
+
+        let scale = options.scale as f32;
+        let (width, height) = emulator.gb.screen_size();
         let window = Window {
             title: "rustboy".to_string(),
-            resolution: (SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32).into(),
+            resolution: (width as f32 * scale, height as f32 * scale).into(),
             resize_constraints: WindowResizeConstraints {
-                min_width: SCREEN_WIDTH as f32,
-                min_height: SCREEN_HEIGHT as f32,
+                min_width: width as f32,
+                min_height: height as f32,
                 ..default()
             },
             ..default()
@@ -120,24 +493,29 @@ impl Emulator {
             .add_plugins(LogDiagnosticsPlugin::default())
             .add_plugins(FrameTimeDiagnosticsPlugin::default())
             .add_plugins(TiledCameraPlugin)
+            .insert_resource(emulator)
             .add_plugins(EmulatorPlugin)
             .add_systems(Startup, setup)
             .add_plugins(JoypadPlugin)
+            .add_plugins(SaveStatePlugin)
             .run();
+        Ok(())
     }
 }
 fn setup(
     mut commands: Commands,
+    emulator: Res<Emulator>,
 ) {
     use bevy_tiled_camera::*;
-    commands.spawn(TiledCameraBundle::pixel_cam([SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32]).with_pixels_per_tile([1, 1]));
+    let (width, height) = emulator.gb.screen_size();
+    commands.spawn(TiledCameraBundle::pixel_cam([width, height]).with_pixels_per_tile([1, 1]));
 }
 
 pub struct JoypadPlugin;
 
 impl Plugin for JoypadPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, joypad_system);
+        app.add_systems(Update, (joypad_system, gamepad_system));
     }
 }
 
@@ -194,3 +572,102 @@ fn joypad_system(
         emulator.gb.joypad.lock().unwrap().release(crate::joypad::BUTTON_SELECT);
     } 
 }
+
+/// Gamepads, in the order they connected, are players 1-4 for SGB multiplayer.
+/// The keyboard is player 1 as well.
+fn gamepad_system(
+    emulator: Res<Emulator>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+) {
+    use crate::joypad::*;
+    let mapping = [
+        (GamepadButtonType::South, BUTTON_A),
+        (GamepadButtonType::East, BUTTON_B),
+        (GamepadButtonType::Select, BUTTON_SELECT),
+        (GamepadButtonType::Start, BUTTON_START),
+        (GamepadButtonType::DPadRight, BUTTON_RIGHT),
+        (GamepadButtonType::DPadLeft, BUTTON_LEFT),
+        (GamepadButtonType::DPadUp, BUTTON_UP),
+        (GamepadButtonType::DPadDown, BUTTON_DOWN),
+    ];
+
+    let mut pads: Vec<Gamepad> = gamepads.iter().collect();
+    pads.sort_by_key(|pad| pad.id);
+    let mut joypad = emulator.gb.joypad.lock().unwrap();
+    for (player, pad) in pads.into_iter().take(MAX_PLAYERS).enumerate() {
+        for (button_type, button) in mapping {
+            let gamepad_button = GamepadButton::new(pad, button_type);
+            if buttons.just_pressed(gamepad_button) {
+                joypad.press_player(player, button);
+            } else if buttons.just_released(gamepad_button) {
+                joypad.release_player(player, button);
+            }
+        }
+    }
+}
+
+pub struct SaveStatePlugin;
+
+impl Plugin for SaveStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (save_state_system, thumbnail_system));
+    }
+}
+
+/// 1-4: select slot, F5: save to slot, F9: load from slot
+fn save_state_system(
+    mut emulator: ResMut<Emulator>,
+    keys: Res<Input<KeyCode>>,
+    mut thumbnail: ResMut<SlotThumbnail>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let slot_keys = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4];
+    let mut show_thumbnail = false;
+    for (i, key) in slot_keys.iter().enumerate().take(SAVE_SLOT_NUM as usize) {
+        if keys.just_pressed(*key) {
+            emulator.slot = i as u8 + 1;
+            println!("slot {} selected", emulator.slot);
+            show_thumbnail = true;
+        }
+    }
+
+    if keys.just_pressed(KeyCode::F5) {
+        match emulator.save_slot() {
+            Ok(()) => println!("state saved to slot {}", emulator.slot),
+            Err(e) => println!("failed to save state: {:#}", e),
+        }
+        show_thumbnail = true;
+    }
+
+    if keys.just_pressed(KeyCode::F9) {
+        match emulator.load_slot() {
+            Ok(()) => println!("state loaded from slot {}", emulator.slot),
+            Err(e) => println!("failed to load state: {:#}", e),
+        }
+    }
+
+    if show_thumbnail {
+        let (width, height) = emulator.gb.screen_size();
+        let image_data = emulator
+            .thumbnail(emulator.slot)
+            .unwrap_or_else(|| image::RgbaImage::new(width, height));
+        *images.get_mut(&thumbnail.image).unwrap() = screen_image(&image_data);
+        thumbnail.timer.reset();
+    }
+}
+
+fn thumbnail_system(
+    time: Res<Time>,
+    mut thumbnail: ResMut<SlotThumbnail>,
+    mut query: Query<&mut Visibility, With<ThumbnailSprite>>,
+) {
+    thumbnail.timer.tick(time.delta());
+    for mut visibility in &mut query {
+        *visibility = if thumbnail.timer.finished() {
+            Visibility::Hidden
+        } else {
+            Visibility::Visible
+        };
+    }
+}
@@ -1,72 +1,388 @@
-use std::{sync::{Arc, Mutex}};
+use std::{sync::{Arc, Mutex, MutexGuard}};
+
+use anyhow::{bail, Result};
 
 use crate::{
-    bus::Bus, cartridge::Cartridge, cpu::Cpu, interrupt::Interrupt, mbc::*,
-    ppu::Ppu, timer::Timer, types::*, joypad::Joypad,
+    apu::Apu, bootrom::Bootrom, bus::Bus, cartridge::Cartridge, colorization::{self, CompatPalette}, cpu::{Cpu, Register}, hdma::{HDMA_BLOCK_CYCLES, HDMA_BLOCK_SIZE}, interrupt::Interrupt, mbc::*, model::Model,
+    constant::*, ppu::{DmgColors, LayerColors, Ppu, Renderer}, sgb::Sgb, timer::Timer, types::*, joypad::{Joypad, MAX_PLAYERS}, state::*,
 };
 
+/// Machine state kept in memory by `GameBoy::snapshot`.
+#[derive(Default)]
+pub struct Snapshot(Vec<Byte>);
+
+/// How to build a `GameBoy` besides the cartridge.
+#[derive(Default, Clone)]
+pub struct GameBoyConfig {
+    pub model: Model,
+    /// run this boot ROM from 0x0000 instead of starting in the post-boot state
+    pub boot_rom: Option<Vec<Byte>>,
+    /// palettes for a DMG game on CGB, like holding a button combo at boot,
+    /// instead of the ones looked up from the title. Without it the buttons
+    /// held during the first frame can still pick them.
+    pub compat_palette: Option<CompatPalette>,
+    pub renderer: Renderer,
+    /// let the CPU at VRAM and OAM in every PPU mode, for debugging
+    pub unlock_vram: bool,
+}
+
 pub struct GameBoy {
     pub cpu: Cpu,
     cycle: u32,
+    config: GameBoyConfig,
+    cgb_mode: bool,
+    rom_checksum: u32,
     ppu: Arc<Mutex<Ppu>>,
-    // apu: APU,
+    // frames the screen on an SGB
+    sgb: Option<Arc<Mutex<Sgb>>>,
+    // a button combo held during the first frame picks the compat palettes,
+    // as on the CGB boot logo
+    boot_combo: bool,
+    apu: Arc<Mutex<Apu>>,
     timer: Arc<Mutex<Timer>>,
     pub joypad: Arc<Mutex<Joypad>>,
 }
 
 impl GameBoy {
     pub fn new(buf: &[Byte]) -> Self {
-        let wraped_cartridge = Cartridge::new(buf);
-        let cartridge = wraped_cartridge.unwrap();
+        Self::try_new(buf).unwrap()
+    }
+
+    /// Like `new`, but reports a broken or unsupported ROM instead of panicking.
+    pub fn try_new(buf: &[Byte]) -> Result<Self> {
+        Self::with_config(buf, &GameBoyConfig::default())
+    }
+
+    pub fn with_config(buf: &[Byte], config: &GameBoyConfig) -> Result<Self> {
+        let cartridge = Cartridge::new(buf)?;
+        let header_checksum = cartridge.header_checksum;
+        let logo = cartridge.logo;
+        // a CGB only runs cartridges that ask for it in CGB mode
+        let cgb_mode = config.model.is_cgb() && cartridge.cgb_flag;
+        let compat_colors = (config.model.is_cgb() && !cgb_mode).then(|| match config.compat_palette {
+            Some(palette) => palette.colors(),
+            None => colorization::lookup(&cartridge),
+        });
+        let boot_combo = compat_colors.is_some() && config.compat_palette.is_none() && config.boot_rom.is_none();
+        // the SGB only takes commands from cartridges that declare support
+        let sgb_commands = cartridge.sgb_flag && cartridge.old_licensee_code == 0x33;
+        let mbc = new_mbc(cartridge)?;
+        let bootrom = config.boot_rom.as_deref().map(Bootrom::new).transpose()?;
 
         let interrupt = Arc::new(Mutex::new(Interrupt::new()));
         let joypad = Arc::new(Mutex::new(Joypad::new()));
-        let ppu = Arc::new(Mutex::new(Ppu::new(Arc::clone(&interrupt))));
+        let ppu = Arc::new(Mutex::new(Ppu::new(Arc::clone(&interrupt), cgb_mode)));
         let timer = Arc::new(Mutex::new(Timer::new(Arc::clone(&interrupt))));
-        let bus = Arc::new(Mutex::new(Bus::new(
-            new_mbc(cartridge),
+        let apu = Arc::new(Mutex::new(Apu::new(config.model.is_cgb())));
+        let bus = Arc::new(Mutex::new(Bus::new_shared(
+            mbc,
+            bootrom,
+            cgb_mode,
             Arc::clone(&timer),
             Arc::clone(&interrupt),
             Arc::clone(&ppu),
             Arc::clone(&joypad),
+            Arc::clone(&apu),
         )));
-        ppu.lock().unwrap().init(Arc::clone(&bus));
+        ppu.lock().unwrap().set_compat_colors(compat_colors);
+        ppu.lock().unwrap().set_renderer(config.renderer);
+        ppu.lock().unwrap().set_vram_unlocked(config.unlock_vram);
+        ppu.lock().unwrap().set_oam_bug(!config.model.is_cgb());
+
+        let sgb = config.model.is_sgb().then(|| Arc::new(Mutex::new(Sgb::new())));
+        ppu.lock().unwrap().set_sgb(sgb.clone());
+        if sgb_commands {
+            joypad.lock().unwrap().set_sgb(sgb.clone());
+        }
 
-        let cpu = Cpu::new(Arc::clone(&bus), Arc::clone(&interrupt));
+        let mut cpu = Cpu::new(Arc::clone(&bus), Arc::clone(&interrupt));
+        if config.boot_rom.is_some() {
+            // the boot ROM turns the LCD on by itself
+            cpu.reg = Register::power_on();
+            bus.lock().unwrap().write(ADDR_PPU_LCDC, 0x00);
+        } else {
+            cpu.reg = config.model.post_boot_registers(header_checksum, cgb_mode);
+            timer.lock().unwrap().set_counter(config.model.post_boot_div_counter(&buf[0x104..0x150]));
+            let (ly, dot) = config.model.post_boot_ppu_position();
+            ppu.lock().unwrap().set_position(ly, dot);
+            for (addr, value) in config.model.post_boot_vram(&logo) {
+                ppu.lock().unwrap().write_vram(0, addr, value);
+            }
+            let mut bus = bus.lock().unwrap();
+            for (addr, value) in config.model.post_boot_io() {
+                bus.write(addr, value);
+            }
+        }
 
-        Self {
+        Ok(Self {
             cpu,
             cycle: 0,
+            config: config.clone(),
+            cgb_mode,
+            rom_checksum: crc32(buf),
             ppu: Arc::clone(&ppu),
+            sgb,
+            boot_combo,
+            apu,
             timer: Arc::clone(&timer),
             joypad: Arc::clone(&joypad),
+        })
+    }
+
+    /// Execute one instruction. Returns true when it completed a frame.
+    pub fn step(&mut self) -> bool {
+        self.advance().1
+    }
+
+    // one instruction, returning the dots it took and whether it completed a frame
+    fn advance(&mut self) -> (u32, bool) {
+        let double_speed = self.cpu.bus.lock().unwrap().double_speed();
+        // the CPU is stalled while a VRAM DMA block is copied
+        let hdma_block = self.ppu.lock().unwrap().next_hdma_block();
+        let cycle = match hdma_block {
+            Some((src, dst)) => {
+                let mut bus = self.cpu.bus.lock().unwrap();
+                let block: Vec<Byte> = (0..HDMA_BLOCK_SIZE).map(|i| bus.read(src + i)).collect();
+                // VRAM DMA gets to VRAM even while the PPU locks the CPU out of it
+                let bank = bus.vram_bank();
+                let mut ppu = self.ppu.lock().unwrap();
+                for (i, b) in block.into_iter().enumerate() {
+                    ppu.write_vram(bank, dst + i as Word, b);
+                }
+                drop(ppu);
+                // a block takes the same time at either speed
+                let c = if double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+                for _ in 0..c {
+                    bus.tick();
+                }
+                c
+            }
+            None => self.cpu.step(),
+        };
+        let dots = if double_speed { cycle * 2 } else { cycle * 4 };
+        self.cycle += dots as u32;
+
+        if self.cycle >= 70224 {
+            self.cycle -= 70224;
+            if self.boot_combo {
+                self.boot_combo = false;
+                self.pick_compat_palette();
+            }
+            return (dots as u32, true);
         }
+        (dots as u32, false)
     }
 
-    pub fn step(&mut self) {
-        let cycle: u16;
-        if self.ppu.lock().unwrap().dma_started {
-            self.ppu.lock().unwrap().transfer_oam();
-            cycle = 162;
-        } else {
-            cycle = self.cpu.step();
+    fn pick_compat_palette(&mut self) {
+        let buttons = self.joypad.lock().unwrap().state();
+        if let Some(palette) = CompatPalette::from_buttons(buttons) {
+            self.ppu.lock().unwrap().set_compat_colors(Some(palette.colors()));
+        }
+    }
+
+    /// Run whole instructions for at least `dots` dots. Returns how many it ran.
+    pub fn run_dots(&mut self, dots: u32) -> u32 {
+        let mut ran = 0;
+        while ran < dots {
+            ran += self.advance().0;
         }
-        self.cycle += cycle as u32 * 4;
-        self.ppu.lock().unwrap().step(cycle * 4);
-        self.timer.lock().unwrap().tick(cycle);
+        ran
     }
 
     pub fn exec_frame(&mut self) {
-        loop {
-            self.step();
-            if self.cycle >= 70224 {
-                self.cycle -= 70224;
-                return;
-            }
+        while !self.step() {}
+    }
+
+    /// Run a frame with `buttons` (BUTTON_* flags) held.
+    /// Returns false for a lag frame, where the game never read the joypad.
+    pub fn exec_frame_with_input(&mut self, buttons: Byte) -> bool {
+        self.exec_frame_with_inputs([buttons, 0, 0, 0])
+    }
+
+    /// Like `exec_frame_with_input`, with the buttons of every SGB player.
+    pub fn exec_frame_with_inputs(&mut self, buttons: [Byte; MAX_PLAYERS]) -> bool {
+        {
+            let mut joypad = self.joypad.lock().unwrap();
+            joypad.set_states(buttons);
+            joypad.clear_polled();
         }
+        self.exec_frame();
+        self.joypad.lock().unwrap().polled()
     }
 
+    /// Clock `value` into the serial port from a link partner.
+    /// Returns what the game sent, or None if it wasn't waiting on an external clock.
+    pub fn serial_exchange(&mut self, value: Byte) -> Option<Byte> {
+        self.cpu.bus.lock().unwrap().serial_exchange(value)
+    }
+
+    /// Collect stereo samples at `rate` Hz for `take_samples`, or stop with None.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.apu.lock().unwrap().set_sample_rate(rate);
+    }
+
+    /// The samples played since the last call, interleaved left and right.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.apu.lock().unwrap().take_samples()
+    }
+
+    /// The current frame, 256x224 with the border on an SGB.
     pub fn display(&self) -> image::RgbaImage {
-        self.ppu.lock().unwrap().display()
+        match &self.sgb {
+            Some(sgb) => sgb.lock().unwrap().display(),
+            None => self.ppu.lock().unwrap().display(),
+        }
+    }
+
+    /// The PPU, e.g. for the uncoloured frame in `Ppu::pixels`, never the SGB border.
+    pub fn ppu(&self) -> MutexGuard<'_, Ppu> {
+        self.ppu.lock().unwrap()
+    }
+
+    /// Width and height of what `display` returns.
+    pub fn screen_size(&self) -> (u32, u32) {
+        match self.sgb {
+            Some(_) => (SGB_SCREEN_WIDTH as u32, SGB_SCREEN_HEIGHT as u32),
+            None => (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32),
+        }
+    }
+
+    /// Colors used for the four DMG shades.
+    pub fn set_dmg_colors(&mut self, colors: DmgColors) {
+        self.ppu.lock().unwrap().set_dmg_colors(colors);
+    }
+
+    /// Colors used for the DMG shades of the BG/window and each object palette.
+    /// Takes effect on the frame on screen too, unless the game runs in CGB mode.
+    pub fn set_layer_colors(&mut self, colors: LayerColors) {
+        self.ppu.lock().unwrap().set_layer_colors(colors);
+    }
+
+    pub fn layer_colors(&self) -> LayerColors {
+        self.ppu.lock().unwrap().layer_colors()
+    }
+
+    pub fn model(&self) -> Model {
+        self.config.model
+    }
+
+    /// The configuration the machine was built with.
+    pub fn config(&self) -> &GameBoyConfig {
+        &self.config
+    }
+
+    /// Running a CGB cartridge with colour, banked VRAM and banked WRAM.
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// CRC-32 of the loaded ROM, used to match save states against it.
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    /// Snapshot the whole machine.
+    ///
+    /// Format:
+    ///   magic "RBST" | version(u16) | rom checksum(u32) | cycle(u32)
+    ///   followed by tagged sections (tag(4) | length(u32) | payload)
+    pub fn save_state(&self) -> Vec<Byte> {
+        let mut w = StateWriter::new();
+        w.write_raw(&STATE_MAGIC);
+        w.write_u16(STATE_VERSION);
+        w.write_u32(self.rom_checksum);
+        self.save_sections(&mut w);
+        w.into_inner()
+    }
+
+    fn save_sections(&self, w: &mut StateWriter) {
+        w.write_u32(self.cycle);
+
+        w.section(b"CPU ", &self.cpu);
+        w.section(b"INTR", &*self.cpu.interrupt.lock().unwrap());
+        w.section(b"BUS ", &**self.cpu.bus.lock().unwrap());
+        w.section(b"PPU ", &*self.ppu.lock().unwrap());
+        w.section(b"TIMR", &*self.timer.lock().unwrap());
+        w.section(b"APU ", &*self.apu.lock().unwrap());
+        w.section(b"JOYP", &*self.joypad.lock().unwrap());
+        if let Some(sgb) = &self.sgb {
+            w.section(b"SGB ", &*sgb.lock().unwrap());
+        }
+    }
+
+    /// Restore a snapshot created by `save_state`.
+    /// On error the machine is left untouched.
+    pub fn load_state(&mut self, buf: &[Byte]) -> Result<()> {
+        let mut r = StateReader::new(buf);
+        r.read_magic(&STATE_MAGIC)?;
+        let version = r.read_u16()?;
+        if version != STATE_VERSION {
+            bail!("Unsupported state version {} (expected {})", version, STATE_VERSION);
+        }
+        let rom_checksum = r.read_u32()?;
+        if rom_checksum != self.rom_checksum {
+            bail!(
+                "State was saved from a different ROM (checksum {:08X}, loaded ROM {:08X})",
+                rom_checksum,
+                self.rom_checksum
+            );
+        }
+
+        let backup = self.save_state();
+        if let Err(e) = self.load_sections(&mut r) {
+            self.load_sections(&mut StateReader::new(&backup[STATE_HEADER_SIZE..]))
+                .expect("failed to restore state backup");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Keep the machine's state in `snapshot` to go back to it with `restore`.
+    /// Cheaper than `save_state`: no header, and the buffer is reused.
+    pub fn snapshot(&self, snapshot: &mut Snapshot) {
+        let mut w = StateWriter::reuse(std::mem::take(&mut snapshot.0));
+        self.save_sections(&mut w);
+        snapshot.0 = w.into_inner();
+    }
+
+    /// Go back to a `snapshot` of this machine. It can't be from another ROM
+    /// or state version, so unlike `load_state` there is nothing to check and
+    /// no backup to take.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.load_sections(&mut StateReader::new(&snapshot.0))
+            .expect("failed to restore snapshot");
+    }
+
+    /// Emulate `frames` frames ahead with the current input and return the
+    /// last one shown, then go back. `snapshot` is scratch space for the state.
+    /// What those frames played is dropped, it's played again for real.
+    pub fn run_ahead(&mut self, frames: u8, snapshot: &mut Snapshot) -> image::RgbaImage {
+        self.snapshot(snapshot);
+        let samples = self.apu.lock().unwrap().pending_samples();
+        for _ in 0..frames {
+            self.exec_frame();
+        }
+        let image_data = self.display();
+        self.restore(snapshot);
+        self.apu.lock().unwrap().truncate_samples(samples);
+        image_data
+    }
+
+    fn load_sections(&mut self, r: &mut StateReader) -> Result<()> {
+        self.cycle = r.read_u32()?;
+        r.section(b"CPU ", &mut self.cpu)?;
+        r.section(b"INTR", &mut *self.cpu.interrupt.lock().unwrap())?;
+        r.section(b"BUS ", &mut **self.cpu.bus.lock().unwrap())?;
+        r.section(b"PPU ", &mut *self.ppu.lock().unwrap())?;
+        r.section(b"TIMR", &mut *self.timer.lock().unwrap())?;
+        r.section(b"APU ", &mut *self.apu.lock().unwrap())?;
+        r.section(b"JOYP", &mut *self.joypad.lock().unwrap())?;
+        if let Some(sgb) = &self.sgb {
+            r.section(b"SGB ", &mut *sgb.lock().unwrap())?;
+        }
+        if !r.is_empty() {
+            bail!("State has trailing data");
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,121 @@
+use crate::{constant::*, state::*, traits::*, types::*};
+use anyhow::Result;
+
+/// M-cycles the CPU is stalled for while one block is copied
+pub const HDMA_BLOCK_CYCLES: u16 = 8;
+pub const HDMA_BLOCK_SIZE: Word = 0x10;
+
+/// CGB VRAM DMA (HDMA1-HDMA5).
+///
+/// A general-purpose transfer copies every block back to back, an HBlank
+/// transfer copies one block at the start of each HBlank. Either way the
+/// blocks are read through the bus and written straight into the VRAM bank
+/// selected by VBK while the CPU is stalled.
+#[derive(Default)]
+pub struct Hdma {
+    // HDMA1/HDMA2, lower 4 bits ignored
+    src: Word,
+    // HDMA3/HDMA4, offset into VRAM
+    dst: Word,
+    // blocks left minus one, the lower 7 bits of HDMA5
+    remaining: Byte,
+    active: bool,
+    hblank_mode: bool,
+    // an HBlank began and its block hasn't been copied yet
+    block_pending: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            // HDMA5 reads 0xFF while idle
+            remaining: 0x7F,
+            ..Default::default()
+        }
+    }
+
+    /// The PPU entered HBlank on a visible line.
+    pub fn hblank(&mut self) {
+        if self.active && self.hblank_mode {
+            self.block_pending = true;
+        }
+    }
+
+    /// Source and destination of the block to copy now, if any.
+    pub fn next_block(&mut self) -> Option<(Word, Word)> {
+        if !self.active || (self.hblank_mode && !self.block_pending) {
+            return None;
+        }
+        self.block_pending = false;
+
+        // E000-FFFF can't be a source; don't let the copy reach the IO registers
+        let src = if self.src >= 0xE000 { self.src - 0x2000 } else { self.src };
+        let dst = 0x8000 | (self.dst & 0x1FF0);
+        self.src = self.src.wrapping_add(HDMA_BLOCK_SIZE);
+        self.dst = self.dst.wrapping_add(HDMA_BLOCK_SIZE);
+
+        if self.remaining == 0 {
+            self.active = false;
+            self.remaining = 0x7F;
+        } else {
+            self.remaining -= 1;
+        }
+        Some((src, dst))
+    }
+}
+
+impl Reader for Hdma {
+    fn read(&self, addr: Word) -> Byte {
+        match addr {
+            // bit 7 is cleared while a transfer is running
+            ADDR_HDMA5 => (!self.active as Byte) << 7 | self.remaining,
+            // the address registers are write only
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Writer for Hdma {
+    fn write(&mut self, addr: Word, value: Byte) {
+        match addr {
+            ADDR_HDMA1 => self.src = (self.src & 0x00FF) | (value as Word) << 8,
+            ADDR_HDMA2 => self.src = (self.src & 0xFF00) | (value & 0xF0) as Word,
+            ADDR_HDMA3 => self.dst = (self.dst & 0x00FF) | ((value & 0x1F) as Word) << 8,
+            ADDR_HDMA4 => self.dst = (self.dst & 0xFF00) | (value & 0xF0) as Word,
+            ADDR_HDMA5 => {
+                if self.active && self.hblank_mode && value & 0x80 == 0 {
+                    // stop an HBlank transfer, HDMA5 keeps the blocks left
+                    self.active = false;
+                    self.block_pending = false;
+                    return;
+                }
+                self.remaining = value & 0x7F;
+                self.hblank_mode = value & 0x80 != 0;
+                self.block_pending = false;
+                self.active = true;
+            }
+            v => unreachable!("cannot write {:04X} for HDMA", v),
+        }
+    }
+}
+
+impl Savable for Hdma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.src);
+        w.write_u16(self.dst);
+        w.write_u8(self.remaining);
+        w.write_bool(self.active);
+        w.write_bool(self.hblank_mode);
+        w.write_bool(self.block_pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.src = r.read_u16()?;
+        self.dst = r.read_u16()?;
+        self.remaining = r.read_u8()? & 0x7F;
+        self.active = r.read_bool()?;
+        self.hblank_mode = r.read_bool()?;
+        self.block_pending = r.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fmt, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{constant::*, gameboy::GameBoy, types::*};
+
+// LD B,B : software breakpoint used by mooneye-gb and friends to signal the end
+const OPCODE_LD_B_B: Byte = 0x40;
+// blargg's tests keep their result at $A000 once this follows it, with the
+// text they printed from $A004 on
+const ADDR_RESULT: Word = 0xA000;
+const RESULT_SIGNATURE: [Byte; 3] = [0xDE, 0xB0, 0x61];
+// the result while the test is still running
+const RESULT_RUNNING: Byte = 0x80;
+
+/// Rate of the audio written by `run`, stereo 16-bit.
+pub const AUDIO_SAMPLE_RATE: u32 = 44100;
+
+/// What to run and what to write out after a headless run.
+#[derive(Default, Clone, Debug)]
+pub struct HeadlessOptions {
+    /// frame limit
+    pub frames: u64,
+    /// stop once the serial output contains any of these
+    pub until_serial: Vec<String>,
+    /// stop at the first LD B,B
+    pub until_breakpoint: bool,
+    /// stop once a result is left in cartridge RAM
+    pub until_result: bool,
+    /// final screenshot
+    pub screenshot: Option<PathBuf>,
+    /// also write `frame_NNNNNN.png` into this folder every `screenshot_interval` frames
+    pub screenshot_dir: Option<PathBuf>,
+    pub screenshot_interval: u64,
+    /// raw serial output
+    pub serial_out: Option<PathBuf>,
+    /// everything played, as WAV
+    pub audio_out: Option<PathBuf>,
+    /// final save state
+    pub state_out: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    FrameLimit,
+    Serial,
+    Breakpoint,
+    Result,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            StopReason::FrameLimit => "frame limit",
+            StopReason::Serial => "serial output matched",
+            StopReason::Breakpoint => "breakpoint (LD B,B)",
+            StopReason::Result => "result in cartridge RAM",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct HeadlessReport {
+    pub frames: u64,
+    pub reason: StopReason,
+    pub serial: Vec<Byte>,
+}
+
+impl HeadlessReport {
+    /// false when a stop condition was given but the frame limit was hit first
+    pub fn passed(&self, options: &HeadlessOptions) -> bool {
+        let waiting =
+            !options.until_serial.is_empty() || options.until_breakpoint || options.until_result;
+        !waiting || self.reason != StopReason::FrameLimit
+    }
+}
+
+/// Run `gb` without any window, audio device or GPU.
+///
+/// There is no link partner, so every serial transfer completes as soon as
+/// it is started and the sent byte is collected as output.
+pub fn run(gb: &mut GameBoy, options: &HeadlessOptions) -> Result<HeadlessReport> {
+    if let Some(dir) = &options.screenshot_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let mut audio = vec![];
+    if options.audio_out.is_some() {
+        gb.set_sample_rate(Some(AUDIO_SAMPLE_RATE));
+    }
+
+    let mut serial = vec![];
+    let mut frames = 0;
+    let reason = 'run: loop {
+        if frames >= options.frames {
+            break StopReason::FrameLimit;
+        }
+
+        loop {
+            if options.until_breakpoint && next_opcode(gb) == OPCODE_LD_B_B {
+                break 'run StopReason::Breakpoint;
+            }
+
+            let frame_done = gb.step();
+
+            if let Some(b) = take_serial(gb) {
+                serial.push(b);
+                let text = String::from_utf8_lossy(&serial);
+                if options.until_serial.iter().any(|s| text.contains(s.as_str())) {
+                    frames += frame_done as u64;
+                    break 'run StopReason::Serial;
+                }
+            }
+
+            if frame_done {
+                break;
+            }
+        }
+        frames += 1;
+        if options.audio_out.is_some() {
+            audio.extend(gb.take_samples());
+        }
+
+        if options.until_result && read_result(gb).is_some() {
+            break StopReason::Result;
+        }
+
+        if let Some(dir) = &options.screenshot_dir {
+            if options.screenshot_interval > 0 && frames % options.screenshot_interval == 0 {
+                let path = dir.join(format!("frame_{:06}.png", frames));
+                gb.display()
+                    .save(&path)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+        }
+    };
+
+    if let Some(path) = &options.screenshot {
+        gb.display()
+            .save(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    if let Some(path) = &options.serial_out {
+        std::fs::write(path, &serial)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    if let Some(path) = &options.state_out {
+        std::fs::write(path, gb.save_state())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    if let Some(path) = &options.audio_out {
+        audio.extend(gb.take_samples());
+        std::fs::write(path, wav(&audio, AUDIO_SAMPLE_RATE))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    Ok(HeadlessReport {
+        frames,
+        reason,
+        serial,
+    })
+}
+
+/// A 16-bit PCM WAV file of interleaved stereo `samples`.
+pub fn wav(samples: &[i16], rate: u32) -> Vec<Byte> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let data_size = (samples.len() * BYTES_PER_SAMPLE as usize) as u32;
+
+    let mut buf = Vec::with_capacity(44 + data_size as usize);
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(36 + data_size).to_le_bytes());
+    buf.extend_from_slice(b"WAVE");
+    buf.extend_from_slice(b"fmt ");
+    buf.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&CHANNELS.to_le_bytes());
+    buf.extend_from_slice(&rate.to_le_bytes());
+    buf.extend_from_slice(&(rate * (CHANNELS * BYTES_PER_SAMPLE) as u32).to_le_bytes());
+    buf.extend_from_slice(&(CHANNELS * BYTES_PER_SAMPLE).to_le_bytes());
+    buf.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+    buf.extend_from_slice(b"data");
+    buf.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        buf.extend_from_slice(&sample.to_le_bytes());
+    }
+    buf
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TestOutcome {
+    Passed,
+    Failed(String),
+    Timeout,
+}
+
+/// Run a test ROM until it reports a result.
+///
+/// Blargg's tests print "Passed" or "Failed" over serial, or leave 0 at
+/// $A000 on success. Mooneye-gb tests execute LD B,B with
+/// B,C,D,E,H,L = 3,5,8,13,21,34 on success.
+pub fn run_test(gb: &mut GameBoy, frames: u64) -> Result<TestOutcome> {
+    let options = HeadlessOptions {
+        frames,
+        until_serial: vec!["Passed".to_string(), "Failed".to_string()],
+        until_breakpoint: true,
+        until_result: true,
+        ..Default::default()
+    };
+    let report = run(gb, &options)?;
+    let serial = String::from_utf8_lossy(&report.serial).trim().to_string();
+
+    let outcome = match report.reason {
+        StopReason::FrameLimit => TestOutcome::Timeout,
+        StopReason::Serial if serial.contains("Passed") => TestOutcome::Passed,
+        StopReason::Serial => TestOutcome::Failed(serial),
+        StopReason::Result => match read_result(gb) {
+            Some((0, _)) => TestOutcome::Passed,
+            Some((_, text)) => TestOutcome::Failed(text.trim().to_string()),
+            None => TestOutcome::Timeout,
+        },
+        StopReason::Breakpoint => {
+            let r = &gb.cpu.reg;
+            if [r.B, r.C, r.D, r.E, r.H, r.L] == [3, 5, 8, 13, 21, 34] {
+                TestOutcome::Passed
+            } else {
+                TestOutcome::Failed(r.to_string())
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+// the result code and text, once the test has finished
+fn read_result(gb: &GameBoy) -> Option<(Byte, String)> {
+    let bus = gb.cpu.bus.lock().unwrap();
+    let signature = [1, 2, 3].map(|i| bus.read(ADDR_RESULT + i));
+    let code = bus.read(ADDR_RESULT);
+    if signature != RESULT_SIGNATURE || code == RESULT_RUNNING {
+        return None;
+    }
+    let text: Vec<Byte> = (ADDR_RESULT + 4..0xC000)
+        .map(|addr| bus.read(addr))
+        .take_while(|&b| b != 0)
+        .collect();
+    Some((code, String::from_utf8_lossy(&text).to_string()))
+}
+
+fn next_opcode(gb: &GameBoy) -> Byte {
+    gb.cpu.bus.lock().unwrap().read(gb.cpu.reg.PC)
+}
+
+// a transfer is pending while SC reads 0xFF (start + internal clock)
+fn take_serial(gb: &GameBoy) -> Option<Byte> {
+    let mut bus = gb.cpu.bus.lock().unwrap();
+    if bus.read(ADDR_SERIAL_SC) != 0xFF {
+        return None;
+    }
+    let b = bus.read(ADDR_SERIAL_SB);
+    bus.write(ADDR_SERIAL_SC, 0x00);
+    Some(b)
+}
@@ -2,7 +2,9 @@ use crate::{
     types::*,
     constant::*,
     traits::*,
+    state::*,
 };
+use anyhow::Result;
 use std::fmt;
 
 #[derive(Default)]
@@ -80,4 +82,17 @@ impl Writer for Interrupt {
             v => unreachable!("Invalid Addr {:04X} for Interrupt", v),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savable for Interrupt {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.r#if);
+        w.write_u8(self.ie);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.r#if = r.read_u8()?;
+        self.ie = r.read_u8()?;
+        Ok(())
+    }
+}
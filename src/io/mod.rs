@@ -1,31 +1,33 @@
-mod serial;
-mod apu;
+pub mod serial;
 
 use crate::{
     constant::*,
     types::*,
     traits::*,
+    state::*,
 };
+use anyhow::Result;
 
 pub struct Io {
     serial: serial::Serial,
-    apu: apu::Apu,
 }
 
 impl Io {
     pub fn new() -> Self {
         Io {
             serial: serial::Serial::new(),
-            apu: apu::Apu::new(),
         }
     }
+
+    pub fn serial_exchange(&mut self, value: Byte) -> Option<Byte> {
+        self.serial.exchange(value)
+    }
 }
 
 impl Reader for Io {
     fn read(&self, addr: Word) -> Byte {
         match addr {
             ADDR_SERIAL_SB..=ADDR_SERIAL_SC => self.serial.read(addr),
-            ADDR_APU_NR10..=ADDR_APU_NR52 => self.apu.read(addr),
             v => {
                 log::warn!("Cannot read addr {:04X} for Io",v);
                 0xFF
@@ -38,9 +40,18 @@ impl Writer for Io {
     fn write(&mut self, addr: Word, value: Byte) {
         match addr {
             ADDR_SERIAL_SB..=ADDR_SERIAL_SC => self.serial.write(addr, value),
-            ADDR_APU_NR10..=ADDR_APU_NR52 => self.apu.write(addr, value),
             v => log::warn!("Cannot write addr {:04X} for Io",v)
         }
 
     }
-}
\ No newline at end of file
+}
+
+impl Savable for Io {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.serial.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.serial.load_state(r)
+    }
+}
@@ -2,7 +2,9 @@ use crate::{
     types::*,
     traits::*,
     constant::*,
+    state::*,
 };
+use anyhow::Result;
 
 #[derive(Default)]
 pub struct Serial {
@@ -18,6 +20,18 @@ impl Serial {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// A link partner driving the clock shifts `value` in.
+    /// Returns the byte shifted out, or None when no transfer is waiting for an external clock.
+    pub fn exchange(&mut self, value: Byte) -> Option<Byte> {
+        if self.sc & 0x81 != 0x80 {
+            return None;
+        }
+        let out = self.sb;
+        self.sb = value;
+        self.sc &= 0x7F;
+        Some(out)
+    }
 }
 
 impl Reader for Serial {
@@ -38,4 +52,17 @@ impl Writer for Serial {
            v => unreachable!("Invalid Addr {:04X} for Serial", v),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savable for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sb);
+        w.write_u8(self.sc);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.sb = r.read_u8()?;
+        self.sc = r.read_u8()?;
+        Ok(())
+    }
+}
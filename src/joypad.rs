@@ -1,8 +1,18 @@
-use crate::{constant::*, traits::*, types::*, util::*};
+use crate::{sgb::Sgb, state::*, traits::*, types::*, util::*};
+use anyhow::Result;
+use std::{
+	cell::Cell,
+	sync::{Arc, Mutex},
+};
 
 pub struct Joypad {
 	p1: Byte,
-	state: Byte,
+	// one controller per player, more than one only through an SGB
+	state: [Byte; MAX_PLAYERS],
+	// set when the game reads P1, used to detect lag frames
+	polled: Cell<bool>,
+	// receives the command packets sent through P1
+	sgb: Option<Arc<Mutex<Sgb>>>,
 }
 
 pub const BUTTON_A: Byte = 0x01;
@@ -14,14 +24,23 @@ pub const BUTTON_LEFT: Byte = 0x20;
 pub const BUTTON_UP: Byte = 0x40;
 pub const BUTTON_DOWN: Byte = 0x80;
 
+/// Controllers an SGB can read with MLT_REQ
+pub const MAX_PLAYERS: usize = 4;
+
 impl Joypad {
 	pub fn new() -> Self {
 		Self {
 			p1: 0xCF, // all buttuns are not pressed
-			state: 0x00,
+			state: [0x00; MAX_PLAYERS],
+			polled: Cell::new(false),
+			sgb: None,
 		}
 	}
 
+	pub fn set_sgb(&mut self, sgb: Option<Arc<Mutex<Sgb>>>) {
+		self.sgb = sgb;
+	}
+
 	// button is start, select, A, B
 	fn button_pressed(&self) -> bool {
 		bit(&self.p1, &5) == 0
@@ -33,26 +52,69 @@ impl Joypad {
 	}
 
 	pub fn press(&mut self, button: Byte) {
-		self.state |= button;
+		self.press_player(0, button);
 	}
 
 	pub fn release(&mut self, button: Byte) {
-		self.state &= !button;
+		self.release_player(0, button);
+	}
+
+	pub fn press_player(&mut self, player: usize, button: Byte) {
+		self.state[player] |= button;
+	}
+
+	pub fn release_player(&mut self, player: usize, button: Byte) {
+		self.state[player] &= !button;
+	}
+
+	/// pressed buttons of player 1 as BUTTON_* flags
+	pub fn state(&self) -> Byte {
+		self.state[0]
+	}
+
+	pub fn set_state(&mut self, state: Byte) {
+		self.state[0] = state;
+	}
+
+	/// pressed buttons of every player
+	pub fn states(&self) -> [Byte; MAX_PLAYERS] {
+		self.state
+	}
+
+	pub fn set_states(&mut self, states: [Byte; MAX_PLAYERS]) {
+		self.state = states;
+	}
+
+	// the controller the SGB connects to P1 right now
+	fn player(&self) -> usize {
+		self.sgb.as_ref().map_or(0, |sgb| sgb.lock().unwrap().current_player())
+	}
+
+	pub fn polled(&self) -> bool {
+		self.polled.get()
+	}
+
+	pub fn clear_polled(&mut self) {
+		self.polled.set(false);
 	}
 }
 
 impl Reader for Joypad {
 	fn read(&self, _addr: Word) -> Byte {
+		self.polled.set(true);
+		let player = self.player();
+		let state = self.state[player];
+
 		if self.button_pressed() {
-			return self.p1 & !(self.state & 0x0F) | 0xC0;
+			return self.p1 & !(state & 0x0F) | 0xC0;
 		}
 
 		if self.direction_pressed() {
-			return self.p1 & !(self.state >> 4) | 0xC0;
+			return self.p1 & !(state >> 4) | 0xC0;
 		}
 
-		// all not pressed
-		return self.p1 | 0xCF;
+		// nothing selected: the SGB answers with the player ID, 0xF for player 1
+		(self.p1 & 0xF0) | 0xC0 | (0x0F - player as Byte)
 	}
 }
 
@@ -60,5 +122,21 @@ impl Writer for Joypad {
 	fn write(&mut self, _addr: Word, value: Byte) {
 		// because bit 3-0 is read only
 		self.p1 = (self.p1 & 0xCF) | (value & 0x30);
+		if let Some(sgb) = &self.sgb {
+			sgb.lock().unwrap().write_p1(value);
+		}
+	}
+}
+
+impl Savable for Joypad {
+	fn save_state(&self, w: &mut StateWriter) {
+		w.write_u8(self.p1);
+		w.write_bytes(&self.state);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+		self.p1 = r.read_u8()?;
+		r.read_bytes_into(&mut self.state)?;
+		Ok(())
 	}
 }
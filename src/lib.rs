@@ -1,16 +1,30 @@
+pub mod apu;
+pub mod bootrom;
 pub mod bus;
 pub mod cartridge;
+pub mod colorization;
 pub mod constant;
 pub mod cpu;
+pub mod dmg07;
+#[cfg(feature = "frontend")]
 pub mod emulator;
 pub mod gameboy;
+pub mod hdma;
+pub mod headless;
 pub mod interrupt;
 pub mod io;
 pub mod joypad;
 pub mod mbc;
 pub mod memory;
+pub mod model;
+pub mod movie;
+pub mod oam_dma;
 pub mod opcode;
+pub mod palettes;
 pub mod ppu;
+pub mod rewind;
+pub mod sgb;
+pub mod state;
 pub mod timer;
 pub mod traits;
 pub mod types;
@@ -1,5 +1,414 @@
-use rust_boy::gameboy::GameBoy;
+use std::{path::PathBuf, process::ExitCode};
 
-fn main() {
-    rust_boy::emulator::Emulator::run();
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rust_boy::{
+    cartridge::{Cartridge, DestinationCode},
+    colorization::CompatPalette,
+    gameboy::{GameBoy, GameBoyConfig},
+    headless::{self, HeadlessOptions, TestOutcome},
+    model::Model,
+    palettes::Palettes,
+    ppu::*,
+    state::crc32,
+};
+
+/// Game Boy emulator
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play a ROM in a window
+    Run(RunArgs),
+    /// Print the cartridge header of a ROM
+    Info {
+        rom: PathBuf,
+    },
+    /// Run a ROM without a window and dump what it produced
+    Headless(HeadlessArgs),
+    /// Run test ROMs (blargg, mooneye-gb) and report which pass
+    Test {
+        #[arg(required = true)]
+        roms: Vec<PathBuf>,
+        /// give up after this many frames
+        #[arg(long, default_value_t = 3600)]
+        frames: u64,
+        /// hardware model to emulate (default: CGB for CGB cartridges, DMG otherwise)
+        #[arg(long, value_enum)]
+        model: Option<ModelArg>,
+    },
+}
+
+#[derive(Args)]
+struct RunArgs {
+    rom: PathBuf,
+    /// window scale
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..=8))]
+    scale: u32,
+    #[command(flatten)]
+    palette: PaletteArgs,
+    /// boot ROM to run before the cartridge
+    #[arg(long, value_name = "FILE")]
+    boot_rom: Option<PathBuf>,
+    /// hardware model to emulate (default: CGB for CGB cartridges, DMG otherwise)
+    #[arg(long, value_enum)]
+    model: Option<ModelArg>,
+    /// palettes for a DMG game on CGB, instead of the ones picked by title
+    #[arg(long, value_enum)]
+    compat_palette: Option<CompatPaletteArg>,
+    /// draw lines in one go, or dot by dot to catch mid-line register writes
+    #[arg(long, value_enum, default_value_t = RendererArg::Scanline)]
+    renderer: RendererArg,
+    /// let the CPU at VRAM and OAM in every PPU mode, for debugging
+    #[arg(long)]
+    unlock_vram: bool,
+    /// where save states and movies go (default: next to the ROM)
+    #[arg(long, value_name = "DIR")]
+    save_dir: Option<PathBuf>,
+    /// emulation speed, 1.0 is 60 frames per second
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f32,
+    /// start paused (P to resume)
+    #[arg(long)]
+    paused: bool,
+}
+
+#[derive(Args)]
+struct PaletteArgs {
+    /// DMG colors: a palette name (green, gray, pocket or from --palettes) or
+    /// four colors like #FFFFFF,#AAAAAA,#555555,#000000, optionally three such
+    /// sets separated by ';' for the background, OBP0 and OBP1
+    #[arg(long, default_value = "green")]
+    palette: String,
+    /// DMG colors of objects using OBP0, a palette name or four colors (default: --palette)
+    #[arg(long)]
+    obj0_palette: Option<String>,
+    /// DMG colors of objects using OBP1, a palette name or four colors (default: --palette)
+    #[arg(long)]
+    obj1_palette: Option<String>,
+    /// file with more named palettes, one `name = colors` per line
+    #[arg(long, value_name = "FILE")]
+    palettes: Option<PathBuf>,
+}
+
+impl PaletteArgs {
+    /// The palettes to pick from and the colors asked for.
+    fn resolve(&self) -> Result<(Palettes, LayerColors)> {
+        let mut palettes = Palettes::default();
+        if let Some(path) = &self.palettes {
+            palettes.load(path)?;
+        }
+        let mut colors = palettes.resolve(&self.palette)?;
+        if let Some(obj0) = &self.obj0_palette {
+            colors = colors.with_layer(Layer::Obj0, &palettes.resolve(obj0)?);
+        }
+        if let Some(obj1) = &self.obj1_palette {
+            colors = colors.with_layer(Layer::Obj1, &palettes.resolve(obj1)?);
+        }
+        Ok((palettes, colors))
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ModelArg {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl From<ModelArg> for Model {
+    fn from(value: ModelArg) -> Self {
+        match value {
+            ModelArg::Dmg0 => Model::Dmg0,
+            ModelArg::Dmg => Model::Dmg,
+            ModelArg::Mgb => Model::Mgb,
+            ModelArg::Sgb => Model::Sgb,
+            ModelArg::Sgb2 => Model::Sgb2,
+            ModelArg::Cgb => Model::Cgb,
+            ModelArg::Agb => Model::Agb,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CompatPaletteArg {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl From<CompatPaletteArg> for CompatPalette {
+    fn from(value: CompatPaletteArg) -> Self {
+        match value {
+            CompatPaletteArg::Up => CompatPalette::Up,
+            CompatPaletteArg::UpA => CompatPalette::UpA,
+            CompatPaletteArg::UpB => CompatPalette::UpB,
+            CompatPaletteArg::Left => CompatPalette::Left,
+            CompatPaletteArg::LeftA => CompatPalette::LeftA,
+            CompatPaletteArg::LeftB => CompatPalette::LeftB,
+            CompatPaletteArg::Down => CompatPalette::Down,
+            CompatPaletteArg::DownA => CompatPalette::DownA,
+            CompatPaletteArg::DownB => CompatPalette::DownB,
+            CompatPaletteArg::Right => CompatPalette::Right,
+            CompatPaletteArg::RightA => CompatPalette::RightA,
+            CompatPaletteArg::RightB => CompatPalette::RightB,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RendererArg {
+    Scanline,
+    Fifo,
+}
+
+impl From<RendererArg> for Renderer {
+    fn from(value: RendererArg) -> Self {
+        match value {
+            RendererArg::Scanline => Renderer::Scanline,
+            RendererArg::Fifo => Renderer::Fifo,
+        }
+    }
+}
+
+#[derive(Args)]
+struct HeadlessArgs {
+    rom: PathBuf,
+    /// stop after this many frames
+    #[arg(long, default_value_t = 3600)]
+    frames: u64,
+    /// boot ROM to run before the cartridge
+    #[arg(long, value_name = "FILE")]
+    boot_rom: Option<PathBuf>,
+    /// hardware model to emulate (default: CGB for CGB cartridges, DMG otherwise)
+    #[arg(long, value_enum)]
+    model: Option<ModelArg>,
+    /// palettes for a DMG game on CGB, instead of the ones picked by title
+    #[arg(long, value_enum)]
+    compat_palette: Option<CompatPaletteArg>,
+    /// draw lines in one go, or dot by dot to catch mid-line register writes
+    #[arg(long, value_enum, default_value_t = RendererArg::Scanline)]
+    renderer: RendererArg,
+    /// let the CPU at VRAM and OAM in every PPU mode, for debugging
+    #[arg(long)]
+    unlock_vram: bool,
+    #[command(flatten)]
+    palette: PaletteArgs,
+    /// stop once the serial output contains TEXT (repeatable)
+    #[arg(long, value_name = "TEXT")]
+    until_serial: Vec<String>,
+    /// stop at the first LD B,B
+    #[arg(long)]
+    until_breakpoint: bool,
+    /// stop once a blargg test leaves its result at $A000
+    #[arg(long)]
+    until_result: bool,
+    /// write the last frame as PNG
+    #[arg(long, value_name = "FILE")]
+    screenshot: Option<PathBuf>,
+    /// write a PNG into DIR every --screenshot-interval frames
+    #[arg(long, value_name = "DIR")]
+    screenshot_dir: Option<PathBuf>,
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    screenshot_interval: u64,
+    /// write the serial output
+    #[arg(long, value_name = "FILE")]
+    serial: Option<PathBuf>,
+    /// write what was played as WAV
+    #[arg(long, value_name = "FILE")]
+    audio: Option<PathBuf>,
+    /// write a save state of the final machine
+    #[arg(long, value_name = "FILE")]
+    state: Option<PathBuf>,
+}
+
+fn parse_speed(s: &str) -> Result<f32> {
+    let speed: f32 = s.parse()?;
+    if !(0.25..=8.0).contains(&speed) {
+        bail!("speed must be between 0.25 and 8.0");
+    }
+    Ok(speed)
+}
+
+fn read_rom(path: &PathBuf) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn load(
+    path: &PathBuf,
+    model: Option<ModelArg>,
+    boot_rom: Option<&PathBuf>,
+    compat_palette: Option<CompatPaletteArg>,
+    renderer: RendererArg,
+    unlock_vram: bool,
+    colors: LayerColors,
+) -> Result<GameBoy> {
+    let rom = read_rom(path)?;
+    let model = match model {
+        Some(model) => model.into(),
+        // a broken header is reported by with_config below
+        None => Cartridge::new(&rom).map(|c| Model::for_cartridge(&c)).unwrap_or_default(),
+    };
+    let config = GameBoyConfig {
+        model,
+        boot_rom: boot_rom.map(read_rom).transpose()?,
+        compat_palette: compat_palette.map(Into::into),
+        renderer: renderer.into(),
+        unlock_vram,
+    };
+    let mut gb = GameBoy::with_config(&rom, &config)
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+    gb.set_layer_colors(colors);
+    Ok(gb)
+}
+
+#[cfg(feature = "frontend")]
+fn run(args: RunArgs) -> Result<ExitCode> {
+    use rust_boy::emulator::{Emulator, EmulatorOptions};
+
+    let (palettes, colors) = args.palette.resolve()?;
+    let gb = load(&args.rom, args.model, args.boot_rom.as_ref(), args.compat_palette, args.renderer, args.unlock_vram, colors)?;
+    Emulator::run(
+        gb,
+        EmulatorOptions {
+            rom_path: args.rom,
+            scale: args.scale,
+            colors,
+            palettes,
+            save_dir: args.save_dir,
+            speed: args.speed,
+            paused: args.paused,
+        },
+    )?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "frontend"))]
+fn run(_args: RunArgs) -> Result<ExitCode> {
+    bail!("Built without the `frontend` feature; use `headless` instead")
+}
+
+fn info(path: &PathBuf) -> Result<ExitCode> {
+    let bytes = read_rom(path)?;
+    let cart = Cartridge::new(&bytes).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let header_checksum = bytes[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+    let destination = match cart.destination_code {
+        DestinationCode::Japanese => "Japanese",
+        DestinationCode::NonJapanese => "Non-Japanese",
+    };
+
+    println!("title:            {}", cart.title.trim_end_matches('\0'));
+    println!("{}", cart.cartridge_type);
+    println!("rom size:         {} KiB", cart.rom_size / 1024);
+    println!("ram size:         {} KiB", cart.ram_size / 1024);
+    println!("cgb:              {}", cart.cgb_flag);
+    println!("sgb:              {}", cart.sgb_flag);
+    println!("destination:      {}", destination);
+    println!("old licensee:     {:02X}", cart.old_licensee_code);
+    println!("new licensee:     {}", String::from_utf8_lossy(&cart.new_licensee_code));
+    println!("version:          {}", cart.mask_rom_version_number);
+    println!(
+        "header checksum:  {:02X} ({})",
+        cart.header_checksum,
+        if header_checksum == cart.header_checksum { "ok" } else { "bad" }
+    );
+    println!(
+        "global checksum:  {:02X}{:02X}",
+        cart.global_checksum[0], cart.global_checksum[1]
+    );
+    println!("crc32:            {:08X}", crc32(&bytes));
+    Ok(ExitCode::SUCCESS)
+}
+
+fn headless(args: HeadlessArgs) -> Result<ExitCode> {
+    let (_, colors) = args.palette.resolve()?;
+    let mut gb = load(&args.rom, args.model, args.boot_rom.as_ref(), args.compat_palette, args.renderer, args.unlock_vram, colors)?;
+    let options = HeadlessOptions {
+        frames: args.frames,
+        until_serial: args.until_serial,
+        until_breakpoint: args.until_breakpoint,
+        until_result: args.until_result,
+        screenshot: args.screenshot,
+        screenshot_dir: args.screenshot_dir,
+        screenshot_interval: args.screenshot_interval,
+        serial_out: args.serial,
+        audio_out: args.audio,
+        state_out: args.state,
+    };
+    let report = headless::run(&mut gb, &options)?;
+
+    println!("{}: {} frames, stopped by {}", args.rom.display(), report.frames, report.reason);
+    println!("{}", gb.cpu.reg);
+    if !report.serial.is_empty() {
+        println!("serial: {}", String::from_utf8_lossy(&report.serial));
+    }
+
+    Ok(if report.passed(&options) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn test(roms: &[PathBuf], frames: u64, model: Option<ModelArg>) -> Result<ExitCode> {
+    let mut failed = 0;
+    for rom in roms {
+        let outcome = load(rom, model, None, None, RendererArg::Scanline, false, DMG_COLORS_GREEN.into()).and_then(|mut gb| headless::run_test(&mut gb, frames));
+        match outcome {
+            Ok(TestOutcome::Passed) => println!("PASS    {}", rom.display()),
+            Ok(TestOutcome::Failed(detail)) => {
+                failed += 1;
+                println!("FAIL    {}: {}", rom.display(), detail.replace('\n', " "));
+            }
+            Ok(TestOutcome::Timeout) => {
+                failed += 1;
+                println!("TIMEOUT {}", rom.display());
+            }
+            Err(e) => {
+                failed += 1;
+                println!("ERROR   {}: {:#}", rom.display(), e);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", roms.len() - failed, failed);
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::Info { rom } => info(&rom),
+        Command::Headless(args) => headless(args),
+        Command::Test { roms, frames, model } => test(&roms, frames, model),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {:#}", e);
+        ExitCode::FAILURE
+    })
 }
@@ -1,5 +1,7 @@
 use crate::cartridge::Cartridge;
+use crate::state::*;
 use crate::types::*;
+use anyhow::Result;
 
 const SIMPLE_ROMBANKING_MODE: u8 = 0x00;
 const RAMBANKING_MODE_ADVANCED_ROMBANKING_MODE: u8 = 0x01;
@@ -131,3 +133,23 @@ impl super::MbcTrait for Mbc1 {
         self.ram_bank = bank as u8;
     }
 }
+
+impl Savable for Mbc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.rom_bank_high);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.ram_enable);
+        w.write_u8(self.mode);
+        self.cartridge.ram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_bank = r.read_u8()?;
+        self.rom_bank_high = r.read_u8()?;
+        self.ram_bank = r.read_u8()?;
+        self.ram_enable = r.read_bool()?;
+        self.mode = r.read_u8()?;
+        self.cartridge.ram.load_state(r)
+    }
+}
@@ -1,5 +1,7 @@
 use crate::cartridge::Cartridge;
+use crate::state::*;
 use crate::types::*;
+use anyhow::Result;
 
 // https://gekkio.fi/files/gb-docs/gbctr.pdf
 pub struct Mbc2 {
@@ -80,3 +82,17 @@ impl super::MbcTrait for Mbc2 {
         self.rom_bank = bank2 as u8;
     }
 }
+
+impl Savable for Mbc2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank);
+        w.write_bool(self.ram_enable);
+        self.cartridge.ram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_bank = r.read_u8()?;
+        self.ram_enable = r.read_bool()?;
+        self.cartridge.ram.load_state(r)
+    }
+}
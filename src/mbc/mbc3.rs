@@ -1,5 +1,7 @@
 use crate::cartridge::Cartridge;
+use crate::state::*;
 use crate::types::*;
+use anyhow::Result;
 
 pub struct Mbc3 {
     cartridge: Cartridge,
@@ -92,3 +94,23 @@ impl super::MbcTrait for Mbc3 {
         self.rom_bank = bank2 as u8;
     }
 }
+
+impl Savable for Mbc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.rom_bank_high);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.ram_and_timer_enable);
+        w.write_u8(self.rtc);
+        self.cartridge.ram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_bank = r.read_u8()?;
+        self.rom_bank_high = r.read_u8()?;
+        self.ram_bank = r.read_u8()?;
+        self.ram_and_timer_enable = r.read_bool()?;
+        self.rtc = r.read_u8()?;
+        self.cartridge.ram.load_state(r)
+    }
+}
@@ -1,5 +1,7 @@
 use crate::cartridge::Cartridge;
+use crate::state::*;
 use crate::types::*;
+use anyhow::Result;
 
 // https://gekkio.fi/files/gb-docs/gbctr.pdf
 pub struct Mbc5 {
@@ -93,3 +95,21 @@ impl super::MbcTrait for Mbc5 {
         self.rom_bank = bank2 as u8;
     }
 }
+
+impl Savable for Mbc5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.rom_bank_high);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.ram_enable);
+        self.cartridge.ram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_bank = r.read_u8()?;
+        self.rom_bank_high = r.read_u8()?;
+        self.ram_bank = r.read_u8()?;
+        self.ram_enable = r.read_bool()?;
+        self.cartridge.ram.load_state(r)
+    }
+}
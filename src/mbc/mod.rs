@@ -5,8 +5,10 @@ mod mbc5;
 mod no_mbc;
 
 use crate::cartridge::Cartridge;
+use crate::state::*;
 use crate::types::*;
 use ambassador::{delegatable_trait, Delegate};
+use anyhow::{bail, Result};
 
 #[delegatable_trait]
 pub trait MbcTrait {
@@ -26,13 +28,36 @@ pub enum Mbc {
     Mbc5(mbc5::Mbc5),
 }
 
-pub fn new_mbc(cartridge: Cartridge) -> Mbc {
-    match cartridge.cartridge_type.mbc {
+pub fn new_mbc(cartridge: Cartridge) -> Result<Mbc> {
+    let mbc = match cartridge.cartridge_type.mbc {
         Some(crate::cartridge::Mbc::Mbc1) => Mbc::Mbc1(mbc1::Mbc1::new(cartridge)),
         Some(crate::cartridge::Mbc::Mbc2) => Mbc::Mbc2(mbc2::Mbc2::new(cartridge)),
         Some(crate::cartridge::Mbc::Mbc3) => Mbc::Mbc3(mbc3::Mbc3::new(cartridge)),
         Some(crate::cartridge::Mbc::Mbc5) => Mbc::Mbc5(mbc5::Mbc5::new(cartridge)),
         None => Mbc::NoMbc(no_mbc::NoMbc::new(cartridge)),
-        Some(v) => todo!("type {} hasn't implemented", v),
+        Some(v) => bail!("{} cartridges are not supported", v),
+    };
+    Ok(mbc)
+}
+
+impl Savable for Mbc {
+    fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            Mbc::NoMbc(m) => m.save_state(w),
+            Mbc::Mbc1(m) => m.save_state(w),
+            Mbc::Mbc2(m) => m.save_state(w),
+            Mbc::Mbc3(m) => m.save_state(w),
+            Mbc::Mbc5(m) => m.save_state(w),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        match self {
+            Mbc::NoMbc(m) => m.load_state(r),
+            Mbc::Mbc1(m) => m.load_state(r),
+            Mbc::Mbc2(m) => m.load_state(r),
+            Mbc::Mbc3(m) => m.load_state(r),
+            Mbc::Mbc5(m) => m.load_state(r),
+        }
     }
 }
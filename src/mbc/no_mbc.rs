@@ -1,6 +1,8 @@
 use crate::cartridge::Cartridge;
 use crate::traits::Reader;
+use crate::state::*;
 use crate::types::*;
+use anyhow::Result;
 
 pub struct NoMbc {
     cartridge: Cartridge,
@@ -16,6 +18,20 @@ impl NoMbc {
 
 impl super::MbcTrait for NoMbc {
     fn read(&self, addr: Word) -> Byte {
-        self.cartridge.rom.read(addr)
+        match addr {
+            0..=0x7FFF => self.cartridge.rom.read(addr),
+            // no RAM to answer
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Savable for NoMbc {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.cartridge.ram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.cartridge.ram.load_state(r)
     }
 }
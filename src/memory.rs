@@ -1,5 +1,7 @@
+use crate::state::*;
 use crate::traits::*;
 use crate::types::*;
+use anyhow::Result;
 
 #[derive(Default)]
 pub struct ROM {
@@ -40,3 +42,14 @@ impl Writer for RAM {
         self.buf[addr as usize] = value;
     }
 }
+
+
+impl Savable for RAM {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.buf);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_bytes_into(&mut self.buf)
+    }
+}
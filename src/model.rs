@@ -0,0 +1,207 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    cartridge::Cartridge,
+    constant::*,
+    cpu::{Flags, Register},
+    types::*,
+};
+
+// the ® next to the logo, one row per byte
+const REGISTERED_TILE: [Byte; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+/// Hardware revision to emulate.
+///
+/// Without a boot ROM the machine starts in the state the model's boot ROM
+/// leaves behind, which games and test ROMs use to detect the hardware.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Model {
+    /// original Game Boy, early boot ROM
+    Dmg0,
+    /// original Game Boy
+    #[default]
+    Dmg,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Super Game Boy 2
+    Sgb2,
+    /// Game Boy Color
+    Cgb,
+    /// Game Boy Advance
+    Agb,
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Model {
+    /// CGB for cartridges with CGB support, DMG otherwise.
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self {
+        if cartridge.cgb_flag {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// Inverse of `model as u8`, for files that record the model.
+    pub fn from_u8(value: u8) -> Result<Self> {
+        Ok(match value {
+            0 => Model::Dmg0,
+            1 => Model::Dmg,
+            2 => Model::Mgb,
+            3 => Model::Sgb,
+            4 => Model::Sgb2,
+            5 => Model::Cgb,
+            6 => Model::Agb,
+            v => bail!("Invalid model {}", v),
+        })
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    /// CPU registers when the boot ROM jumps to 0x0100.
+    ///
+    /// `header_checksum` is the cartridge byte at 0x014D, which decides the
+    /// H and C flags on DMG and MGB. CGB and AGB leave different values when
+    /// they run a DMG cartridge (`cgb_mode` false).
+    pub fn post_boot_registers(&self, header_checksum: Byte, cgb_mode: bool) -> Register {
+        let flags = |z, n, h, c| Flags { z, n, h, c };
+        let checksum_flags = header_checksum != 0;
+
+        let (a, f, b, c, d, e, h, l) = match self {
+            Model::Dmg0 => (0x01, flags(false, false, false, false), 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg => (0x01, flags(true, false, checksum_flags, checksum_flags), 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, flags(true, false, checksum_flags, checksum_flags), 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, flags(false, false, false, false), 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Sgb2 => (0xFF, flags(false, false, false, false), 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb if cgb_mode => (0x11, flags(true, false, false, false), 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Agb if cgb_mode => (0x11, flags(false, false, false, false), 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Cgb => (0x11, flags(true, false, false, false), 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C),
+            Model::Agb => (0x11, flags(false, false, false, false), 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C),
+        };
+
+        Register {
+            A: a,
+            F: f,
+            B: b,
+            C: c,
+            D: d,
+            E: e,
+            H: h,
+            L: l,
+            SP: 0xFFFE,
+            PC: 0x0100,
+        }
+    }
+
+    /// Internal 16 bit divider when the boot ROM hands over. DIV is its upper byte.
+    /// There are no CGB/AGB boot_div ROMs in the test suite to check those against.
+    ///
+    /// `header` is the cartridge header from 0x0104 to 0x014F. The SGB boot ROM
+    /// sends it to the SNES a bit at a time and a set bit takes one M-cycle less
+    /// than a clear one, so on SGB the boot ROM finishes earlier the more bits
+    /// are set.
+    pub fn post_boot_div_counter(&self, header: &[Byte]) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => {
+                let set_bits: u32 = header.iter().map(|byte| byte.count_ones()).sum();
+                0xDC88u16.wrapping_sub(4 * set_bits as u16)
+            }
+            Model::Cgb | Model::Agb => 0x2678,
+        }
+    }
+
+    /// LY and dot within the line when the boot ROM hands over.
+    /// DMG and later leave it late in line 153, right before the first frame.
+    pub fn post_boot_ppu_position(&self) -> (Byte, u16) {
+        match self {
+            Model::Dmg0 => (145, 176),
+            _ => (153, 360),
+        }
+    }
+
+    /// VRAM the DMG boot ROMs leave behind: the logo from the cartridge header
+    /// in tiles 1-0x18, the ® in tile 0x19 and both in the BG map. Tests like
+    /// mealybug's draw with these tiles. The SGB and CGB ones aren't modelled.
+    pub fn post_boot_vram(&self, logo: &[Byte; 0x30]) -> Vec<(Word, Byte)> {
+        if !matches!(self, Model::Dmg0 | Model::Dmg | Model::Mgb) {
+            return vec![];
+        }
+
+        // every bit of a logo nibble becomes 2x2 pixels, only in the low bitplane
+        let double = |nibble: Byte| (0..4).fold(0, |row, i| row | (((nibble >> i) & 1) * 0b11) << (i * 2));
+        let rows = logo
+            .iter()
+            .flat_map(|b| [double(b >> 4), double(b & 0x0F)])
+            .flat_map(|row| [row, row])
+            .chain(REGISTERED_TILE);
+        let mut vram: Vec<(Word, Byte)> = rows.enumerate().map(|(i, row)| (0x8010 + i as Word * 2, row)).collect();
+        for i in 0..12 {
+            vram.push((0x9904 + i, 0x01 + i as Byte));
+            vram.push((0x9924 + i, 0x0D + i as Byte));
+        }
+        vram.push((0x9910, 0x19));
+        vram
+    }
+
+    /// IO registers the boot ROM leaves behind, written in this order.
+    pub fn post_boot_io(&self) -> Vec<(Word, Byte)> {
+        // the chime leaves channel 1 playing, the SGB boot ROM is silent
+        let nr14 = if self.is_sgb() { 0x3F } else { 0xBF };
+        // the SGB boot ROM talks to the SNES through P1 and deselects both rows
+        let p1 = if self.is_sgb() { 0x30 } else { 0x00 };
+        vec![
+            (ADDR_JOYPAD, p1),
+            (ADDR_INTERRUPT_IF, 0x01),
+            // powered on first, the other APU registers ignore writes till then
+            (ADDR_APU_NR52, 0x80),
+            (ADDR_APU_NR10, 0x80),
+            (ADDR_APU_NR11, 0xBF),
+            (ADDR_APU_NR12, 0xF3),
+            (ADDR_APU_NR13, 0xFF),
+            (ADDR_APU_NR14, nr14),
+            (ADDR_APU_NR21, 0x3F),
+            (ADDR_APU_NR22, 0x00),
+            (ADDR_APU_NR23, 0xFF),
+            (ADDR_APU_NR24, 0xBF),
+            (ADDR_APU_NR30, 0x7F),
+            (ADDR_APU_NR31, 0xFF),
+            (ADDR_APU_NR32, 0x9F),
+            (ADDR_APU_NR33, 0xFF),
+            (ADDR_APU_NR34, 0xBF),
+            (ADDR_APU_NR41, 0xFF),
+            (ADDR_APU_NR42, 0x00),
+            (ADDR_APU_NR43, 0x00),
+            (ADDR_APU_NR44, 0xBF),
+            (ADDR_APU_NR50, 0x77),
+            (ADDR_APU_NR51, 0xF3),
+            (ADDR_PPU_LCDC, 0x91),
+            (ADDR_PPU_BGP, 0xFC),
+        ]
+    }
+}
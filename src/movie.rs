@@ -0,0 +1,250 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    gameboy::{GameBoy, GameBoyConfig},
+    joypad::MAX_PLAYERS,
+    model::Model,
+    state::*,
+    types::*,
+};
+
+// "RBMV" : RustBoy MoVie
+pub const MOVIE_MAGIC: [Byte; 4] = *b"RBMV";
+pub const MOVIE_VERSION: u16 = 3;
+
+const FRAME_LAG: Byte = 0x01;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MovieFrame {
+    /// held buttons as BUTTON_* flags, one per SGB player
+    pub inputs: [Byte; MAX_PLAYERS],
+    /// the game never read the joypad during this frame
+    pub lag: bool,
+}
+
+/// Input recording that replays bit-exactly from a known starting point.
+///
+/// Format:
+///   magic "RBMV" | version(u16) | rom checksum(u32) | model(u8)
+///   | boot ROM checksum(u32, 0 without one)
+///   | initial state(length prefixed, empty for power-on)
+///   | players(u8) | frame count(u32) | (input(u8) per player | flags(u8)) per frame
+pub struct Movie {
+    rom_checksum: u32,
+    model: Model,
+    // a power-on recording runs the boot ROM, so playback needs the same one
+    boot_rom_checksum: Option<u32>,
+    initial_state: Option<Vec<Byte>>,
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Start a recording from a freshly powered on machine.
+    pub fn from_power_on(gb: &GameBoy) -> Self {
+        Self {
+            rom_checksum: gb.rom_checksum(),
+            model: gb.model(),
+            boot_rom_checksum: gb.config().boot_rom.as_deref().map(crc32),
+            initial_state: None,
+            frames: vec![],
+        }
+    }
+
+    /// Start a recording from the current state of `gb`.
+    pub fn from_state(gb: &GameBoy) -> Self {
+        Self {
+            rom_checksum: gb.rom_checksum(),
+            model: gb.model(),
+            boot_rom_checksum: gb.config().boot_rom.as_deref().map(crc32),
+            initial_state: Some(gb.save_state()),
+            frames: vec![],
+        }
+    }
+
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn frames(&self) -> &[MovieFrame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// indices of frames where the game didn't poll the joypad
+    pub fn lag_frames(&self) -> Vec<usize> {
+        self.frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.lag)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Run a frame on `gb` with `input` held and append it to the recording.
+    pub fn record_frame(&mut self, gb: &mut GameBoy, input: Byte) -> MovieFrame {
+        self.record_frame_players(gb, [input, 0, 0, 0])
+    }
+
+    /// Like `record_frame`, with the buttons of every SGB player.
+    pub fn record_frame_players(&mut self, gb: &mut GameBoy, inputs: [Byte; MAX_PLAYERS]) -> MovieFrame {
+        let frame = MovieFrame {
+            inputs,
+            lag: !gb.exec_frame_with_inputs(inputs),
+        };
+        self.frames.push(frame);
+        frame
+    }
+
+    /// Drop every frame from `len` on, so recording can continue from there.
+    pub fn truncate(&mut self, len: usize) {
+        self.frames.truncate(len);
+    }
+
+    /// Build a machine at the start of the recording, with everything but the
+    /// model taken from `config`. Fails if `config` has a different boot ROM.
+    pub fn start(&self, rom: &[Byte], config: &GameBoyConfig) -> Result<GameBoy> {
+        let checksum = crc32(rom);
+        if checksum != self.rom_checksum {
+            bail!(
+                "Movie was recorded with a different ROM (checksum {:08X}, loaded ROM {:08X})",
+                self.rom_checksum,
+                checksum
+            );
+        }
+
+        let boot_rom_checksum = config.boot_rom.as_deref().map(crc32);
+        if boot_rom_checksum != self.boot_rom_checksum {
+            bail!(
+                "Movie was recorded with a different boot ROM (checksum {:08X}, loaded boot ROM {:08X})",
+                self.boot_rom_checksum.unwrap_or(0),
+                boot_rom_checksum.unwrap_or(0)
+            );
+        }
+
+        let config = GameBoyConfig {
+            model: self.model,
+            ..config.clone()
+        };
+        let mut gb = GameBoy::with_config(rom, &config)?;
+        if let Some(state) = &self.initial_state {
+            gb.load_state(state).context("Failed to load the movie's initial state")?;
+        }
+        Ok(gb)
+    }
+
+    /// Replay frame `index` on `gb`.
+    /// Fails if the game polled the joypad differently than while recording.
+    pub fn play_frame(&self, gb: &mut GameBoy, index: usize) -> Result<MovieFrame> {
+        let Some(frame) = self.frames.get(index) else {
+            bail!("Movie has only {} frames", self.frames.len());
+        };
+        let lag = !gb.exec_frame_with_inputs(frame.inputs);
+        if lag != frame.lag {
+            bail!("Movie desynced at frame {}", index);
+        }
+        Ok(*frame)
+    }
+
+    /// Replay the whole recording and return the machine at its end.
+    pub fn play(&self, rom: &[Byte], config: &GameBoyConfig) -> Result<GameBoy> {
+        let mut gb = self.start(rom, config)?;
+        for i in 0..self.frames.len() {
+            self.play_frame(&mut gb, i)?;
+        }
+        Ok(gb)
+    }
+
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let mut w = StateWriter::new();
+        w.write_raw(&MOVIE_MAGIC);
+        w.write_u16(MOVIE_VERSION);
+        w.write_u32(self.rom_checksum);
+        w.write_u8(self.model as u8);
+        w.write_u32(self.boot_rom_checksum.unwrap_or(0));
+        w.write_bytes(self.initial_state.as_deref().unwrap_or(&[]));
+        // only as many players as ever pressed something
+        let players = self
+            .frames
+            .iter()
+            .filter_map(|f| f.inputs.iter().rposition(|i| *i != 0))
+            .max()
+            .map_or(1, |i| i + 1);
+        w.write_u8(players as u8);
+        w.write_u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            for input in &frame.inputs[..players] {
+                w.write_u8(*input);
+            }
+            w.write_u8(if frame.lag { FRAME_LAG } else { 0 });
+        }
+        w.into_inner()
+    }
+
+    pub fn from_bytes(buf: &[Byte]) -> Result<Self> {
+        let mut r = StateReader::new(buf);
+        r.read_magic(&MOVIE_MAGIC)?;
+        let version = r.read_u16()?;
+        if version != MOVIE_VERSION {
+            bail!("Unsupported movie version {} (expected {})", version, MOVIE_VERSION);
+        }
+        let rom_checksum = r.read_u32()?;
+        let model = Model::from_u8(r.read_u8()?)?;
+        let boot_rom_checksum = Some(r.read_u32()?).filter(|&checksum| checksum != 0);
+        let initial_state = match r.read_bytes()? {
+            [] => None,
+            state => Some(state.to_vec()),
+        };
+
+        let players = r.read_u8()? as usize;
+        if !(1..=MAX_PLAYERS).contains(&players) {
+            bail!("Invalid movie player count {}", players);
+        }
+        let len = r.read_u32()? as usize;
+        let mut frames = Vec::with_capacity(len.min(buf.len()));
+        for _ in 0..len {
+            let mut inputs = [0; MAX_PLAYERS];
+            for input in &mut inputs[..players] {
+                *input = r.read_u8()?;
+            }
+            frames.push(MovieFrame {
+                inputs,
+                lag: r.read_u8()? & FRAME_LAG != 0,
+            });
+        }
+        if !r.is_empty() {
+            bail!("Movie has trailing data");
+        }
+
+        Ok(Self {
+            rom_checksum,
+            model,
+            boot_rom_checksum,
+            initial_state,
+            frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes())
+            .with_context(|| format!("Failed to write movie {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let buf = std::fs::read(path)
+            .with_context(|| format!("Failed to read movie {}", path.display()))?;
+        Self::from_bytes(&buf)
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{state::*, types::*};
+use anyhow::Result;
+
+pub const OAM_DMA_LENGTH: Word = 0xA0;
+// M-cycles between writing DMA and the first byte being copied
+const OAM_DMA_START_DELAY: Byte = 1;
+
+/// OAM DMA (0xFF46).
+///
+/// Copies 160 bytes from `value * 0x100` to OAM, one per M-cycle, starting
+/// the cycle after the one following the write. While it copies the CPU only
+/// gets to HRAM and the IO registers: OAM reads 0xFF and the bus the DMA
+/// reads from returns the byte being copied. Writing DMA again restarts the
+/// transfer, the old one keeps going until the new one takes over.
+/// The copying itself is done by the bus, which owns the memory.
+#[derive(Default)]
+pub struct OamDma {
+    // the last value written, DMA reads it back
+    reg: Byte,
+    src: Word,
+    // next byte to copy
+    index: Word,
+    active: bool,
+    // a transfer written but not started yet, and the M-cycles it waits
+    starting: Option<(Word, Byte)>,
+    // what was copied this M-cycle, seen by the CPU on the conflicting bus
+    value: Byte,
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self) -> Byte {
+        self.reg
+    }
+
+    pub fn write(&mut self, value: Byte) {
+        self.reg = value;
+        self.starting = Some((value as Word * 0x100, OAM_DMA_START_DELAY));
+    }
+
+    /// A byte is being copied this M-cycle, locking the CPU out of OAM.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Where the running transfer reads from.
+    pub fn src(&self) -> Word {
+        self.src
+    }
+
+    pub fn value(&self) -> Byte {
+        self.value
+    }
+
+    /// Move on to the next M-cycle.
+    /// Returns the source address and OAM offset of the byte to copy in it, if any.
+    pub fn tick(&mut self) -> Option<(Word, Word)> {
+        match self.starting {
+            Some((src, 0)) => {
+                self.src = src;
+                self.index = 0;
+                self.active = true;
+                self.starting = None;
+            }
+            Some((src, delay)) => self.starting = Some((src, delay - 1)),
+            None => (),
+        }
+        if !self.active {
+            return None;
+        }
+        if self.index == OAM_DMA_LENGTH {
+            self.active = false;
+            return None;
+        }
+
+        let offset = self.index;
+        self.index += 1;
+        // E000-FFFF reads the WRAM below it
+        let src = match self.src {
+            0xE000..=0xFFFF => self.src - 0x2000,
+            src => src,
+        };
+        Some((src + offset, offset))
+    }
+
+    pub fn copied(&mut self, value: Byte) {
+        self.value = value;
+    }
+}
+
+impl Savable for OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg);
+        w.write_u16(self.src);
+        w.write_u16(self.index);
+        w.write_bool(self.active);
+        w.write_bool(self.starting.is_some());
+        let (src, delay) = self.starting.unwrap_or_default();
+        w.write_u16(src);
+        w.write_u8(delay);
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.reg = r.read_u8()?;
+        self.src = r.read_u16()?;
+        self.index = r.read_u16()?.min(OAM_DMA_LENGTH);
+        self.active = r.read_bool()?;
+        let starting = r.read_bool()?;
+        let src = r.read_u16()?;
+        let delay = r.read_u8()?.min(OAM_DMA_START_DELAY);
+        self.starting = starting.then_some((src, delay));
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
-	constant::{COND_ARR, MM_ARR, R_ARR},
+	constant::{ADDR_TIMER_DIV, COND_ARR, MM_ARR, R_ARR, SPEED_SWITCH_CYCLES},
 	cpu::Cpu,
 	types::{Byte, Word},
-	util::{bytes_2_word, extract_lower, extract_upper},
+	util::{extract_lower, extract_upper},
 };
 use once_cell::sync::Lazy;
 use std::fmt;
@@ -308,8 +308,16 @@ fn nop(_: &mut Cpu, _: String, _: String) -> u8{
 	0
 }
 
-fn stop(_: &mut Cpu, _: String, _: String) -> u8{
-	log::info!("stop impl");
+fn stop(c: &mut Cpu, _: String, _: String) -> u8{
+	let mut bus = c.bus.lock().unwrap();
+	if !bus.switch_speed() {
+		log::info!("stop impl");
+		return 0;
+	}
+	// CGB speed switch: DIV is reset and the CPU waits for the clock to settle
+	bus.write(ADDR_TIMER_DIV, 0);
+	drop(bus);
+	c.stall = SPEED_SWITCH_CYCLES;
 	0
 }
 
@@ -322,8 +330,8 @@ fn ld(c: &mut Cpu, r1: String, r2: String) -> u8{
 fn ldnnsp(c: &mut Cpu, _: String, r2: String) -> u8{
 	let value = c.load(&r2);
 	let addr = c.fetch16();
-	c.bus.lock().unwrap().write(addr, extract_lower(value));
-	c.bus.lock().unwrap().write(addr + 1, extract_upper(value));
+	c.write(addr, extract_lower(value));
+	c.write(addr + 1, extract_upper(value));
 	0
 }
 
@@ -350,6 +358,8 @@ fn inc(c: &mut Cpu, r1: String, _: String) -> u8{
 		c.reg.F.z = value as Byte == 0;
 		c.reg.F.n = false;
 		c.reg.F.h = (r as Byte ^ value as Byte) & 0x10 != 0;
+	} else {
+		c.idu_cycle(r);
 	}
 	c.store(&r1, value);
 	0
@@ -363,6 +373,8 @@ fn dec(c: &mut Cpu, r1: String, _: String) -> u8{
 		c.reg.F.z = value as Byte == 0;
 		c.reg.F.n = true;
 		c.reg.F.h = (r as Byte ^ value as Byte) & 0x10 != 0;
+	} else {
+		c.idu_cycle(r);
 	}
 	c.store(&r1, value);
 	0
@@ -602,23 +614,18 @@ fn rst(c: &mut Cpu, n: String, _: String) -> u8{
 // -----push-----
 fn push(c: &mut Cpu, r: String, _: String) -> u8{
 	let buf = c.load(&r);
-	let upper = extract_upper(buf);
-	let lower = extract_lower(buf);
-	c.push(upper);
-	c.push(lower);
+	c.push16(buf);
 	0
 }
 
 // -----pop------
 fn pop(c: &mut Cpu, r: String, _: String) -> u8{
-	let mut lower = c.pop();
-	let upper = c.pop();
+	let mut value = c.pop16();
 
 	if r == "AF" {
-		lower &= 0xF0;
+		value &= 0xFFF0;
 	}
 
-	let value = bytes_2_word(upper, lower);
 	c.store(&r, value);
 	0
 }
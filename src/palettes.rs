@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::ppu::{DmgColors, LayerColors, DMG_PALETTES};
+
+/// Named DMG palettes: the built-in ones followed by those from palette files.
+///
+/// A palette file has one `name = colors` per line, where `colors` is four
+/// `#RRGGBB` shared by every layer, or three such sets separated by `;` for
+/// the BG/window, OBP0 objects and OBP1 objects. Blank lines and lines
+/// starting with `#` are skipped.
+///
+/// ```text
+/// # lighter objects on a dark background
+/// night = #404060,#303050,#202040,#101020 ; #FFFFFF,#C0C0FF,#8080C0,#000000 ; #FFFFFF,#FFC0C0,#C08080,#000000
+/// ```
+#[derive(Clone, Debug)]
+pub struct Palettes {
+    entries: Vec<(String, LayerColors)>,
+}
+
+impl Default for Palettes {
+    fn default() -> Self {
+        Self {
+            entries: DMG_PALETTES.iter().map(|(name, colors)| (name.to_string(), (*colors).into())).collect(),
+        }
+    }
+}
+
+impl Palettes {
+    /// Add the palettes of a file, replacing built-in or earlier ones of the same name.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        self.parse(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn parse(&mut self, text: &str) -> Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, colors)) = line.split_once('=') else {
+                bail!("line {}: expected name = colors", i + 1);
+            };
+            let name = name.trim();
+            if name.is_empty() {
+                bail!("line {}: missing palette name", i + 1);
+            }
+            let colors = parse_layer_colors(colors).with_context(|| format!("line {}", i + 1))?;
+            match self.entries.iter_mut().find(|(n, _)| n == name) {
+                Some(entry) => entry.1 = colors,
+                None => self.entries.push((name.to_string(), colors)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<LayerColors> {
+        self.entries.iter().find(|(n, _)| n == name).map(|(_, colors)| *colors)
+    }
+
+    /// A palette by name, or colours in the palette file syntax.
+    pub fn resolve(&self, s: &str) -> Result<LayerColors> {
+        match self.get(s.trim()) {
+            Some(colors) => Ok(colors),
+            None => parse_layer_colors(s).with_context(|| {
+                let names: Vec<&str> = self.entries.iter().map(|(n, _)| n.as_str()).collect();
+                format!("{:?} is neither a palette ({}) nor colors", s, names.join(", "))
+            }),
+        }
+    }
+
+    pub fn entries(&self) -> &[(String, LayerColors)] {
+        &self.entries
+    }
+}
+
+/// Four comma separated `#RRGGBB`, lightest first.
+pub fn parse_colors(s: &str) -> Result<DmgColors> {
+    let colors: Vec<&str> = s.split(',').collect();
+    if colors.len() != 4 {
+        bail!("expected 4 comma separated colors, got {:?}", s.trim());
+    }
+    let mut result = DmgColors::default();
+    for (dst, c) in result.iter_mut().zip(colors) {
+        let hex = c.trim().trim_start_matches('#');
+        let v = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6)
+            .with_context(|| format!("invalid color {:?}, expected #RRGGBB", c.trim()))?;
+        *dst = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+    }
+    Ok(result)
+}
+
+/// One set of colours for every layer, or `bg ; obj0 ; obj1`.
+pub fn parse_layer_colors(s: &str) -> Result<LayerColors> {
+    let sets: Vec<&str> = s.split(';').collect();
+    match sets[..] {
+        [all] => Ok(parse_colors(all)?.into()),
+        [bg, obj0, obj1] => Ok(LayerColors {
+            bg: parse_colors(bg)?,
+            obj0: parse_colors(obj0)?,
+            obj1: parse_colors(obj1)?,
+        }),
+        _ => bail!("expected 1 or 3 sets of colors separated by ';'"),
+    }
+}
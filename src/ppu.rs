@@ -1,11 +1,16 @@
 use bitvec::prelude::*;
 use image::RgbaImage;
 use std::{
+    collections::VecDeque,
     fmt,
     sync::{Arc, Mutex},
 };
 
-use crate::{constant::*, interrupt::Interrupt, memory::*, traits::*, types::*, util::*};
+use crate::{
+    constant::*, hdma::Hdma, interrupt::Interrupt, memory::*, sgb::Sgb, state::*,
+    traits::*, types::*, util::*,
+};
+use anyhow::{bail, Result};
 
 #[derive(PartialEq, Copy, Clone)]
 enum Mode {
@@ -21,11 +26,70 @@ impl Default for Mode {
     }
 }
 
+impl Mode {
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Mode::HBlank),
+            1 => Ok(Mode::VBlank),
+            2 => Ok(Mode::SearchingOAM),
+            3 => Ok(Mode::TransferringData),
+            v => bail!("Invalid PPU mode {}", v),
+        }
+    }
+}
+
+// a line starts with LY and STAT still showing the last line's HBlank
+// for this long, though the OAM scan interrupt fires right away
+const LINE_START_DOTS: u16 = 4;
+const OAM_SCAN_DOTS: u16 = 80;
+// the OAM scan reads a row of two objects per M-cycle
+const OAM_ROWS: usize = 20;
+// mode 3 and HBlank together
+const DRAWING_DOTS: u16 = LINE_DOTS - LINE_START_DOTS - OAM_SCAN_DOTS;
+// shortest mode 3, lengthened by SCX, the window and objects
+const TRANSFER_DOTS: u16 = 172;
+// a BG tile fetch; the first one of a line is done twice
+const TILE_FETCH_DOTS: u16 = 6;
+// the window restarts the fetcher
+const WINDOW_FETCH_DOTS: u16 = 6;
+// the pixel FIFO is paused while an object's tile is fetched
+const OBJ_FETCH_DOTS: u16 = 6;
+const LINE_DOTS: u16 = 456;
+// LY reads 153 for this long into the last line, then 0
+const LY_153_DOTS: u16 = 4;
+// the PPU is already this far into the first line when the LCD is turned
+// on, which also puts the CPU's bus accesses on the last dot of each M-cycle
+const LCD_ON_DOTS: u16 = 11;
+
+/// What the CPU did with an address in 0xFE00-0xFEFF, for the OAM bug.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OamBugAccess {
+    Read,
+    /// A write, or a 16-bit register stepped by the IDU.
+    Write,
+    /// A read from a register being stepped in the same M-cycle, as POP and LD A,(HL+) do.
+    ReadIncrease,
+}
+
+/// How the PPU turns VRAM into pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Renderer {
+    /// Draw each line in one go at the end of mode 3. Fast, but misses
+    /// register writes made while the line is being drawn.
+    #[default]
+    Scanline,
+    /// Fetch tiles and shift pixels out dot by dot like the hardware's pixel
+    /// FIFO, so writes during mode 3 take effect from the next pixel.
+    Fifo,
+}
+
 #[derive(Default)]
 pub struct Ppu {
     clock: u16,
     buf: RAM,
-    bus: Option<Arc<Mutex<Box<dyn BusTrait + Send>>>>,
+    // two 8 KiB banks, the second one CGB only
+    vram: RAM,
+    oam: RAM,
     lcdc: Lcdc,
     lcds: Lcds,
     scroll: Scroll,
@@ -33,13 +97,44 @@ pub struct Ppu {
     palette: Palette,
     scan_line: RgbaImage,
     image_data: RgbaImage,
-    dma: Byte,
-    pub dma_started: bool,
+    // shade and layer of every pixel in the frame, see `pixels`
+    pixels: Vec<Byte>,
+    // CGB VRAM DMA
+    hdma: Hdma,
     mode: Mode,
     prev_mode: Mode,
+    // the STAT interrupt line, requesting the interrupt when it goes high
     prev_lcd_interrupt: bool,
+    // STAT bit 2, only compared while the LCD is on
+    lyc_match: bool,
+    // LY already went back to 0 on line 153
+    ly_wrapped: bool,
+    // the first line after the LCD is turned on has no OAM scan mode
+    lcd_starting: bool,
+    // the first frame after the LCD is turned on isn't shown
+    skip_frame: bool,
+    // line of the window to draw next, counting only lines it was drawn on
     window_rendering_counter: u8,
+    // WY matched LY at the start of an OAM scan this frame, letting the window in
+    window_y_triggered: bool,
+    // the window was drawn on the current line
+    window_on_line: bool,
+    // the window caught by WX=166 on the last pixel covers the whole next line
+    window_wraps: bool,
     interrupt: Arc<Mutex<Interrupt>>,
+    // CGB mode: VRAM bank 1 attribute maps and colour palettes
+    cgb: bool,
+    // BG/window colour index of each pixel on the current line, for OBJ priority
+    bg_line: Vec<BgPixel>,
+    sgb: Option<Arc<Mutex<Sgb>>>,
+    renderer: Renderer,
+    fifo: Fifo,
+    // length of the current or last mode 3, which HBlank makes up for
+    transfer_dots: u16,
+    // let the CPU at VRAM and OAM whatever the mode, for debugging
+    vram_unlocked: bool,
+    // pre-CGB models corrupt OAM when the CPU gets at it during the OAM scan
+    oam_bug: bool,
 }
 
 impl fmt::Display for Ppu {
@@ -53,115 +148,296 @@ impl fmt::Display for Ppu {
 }
 
 impl Ppu {
-    pub fn new(interrupt: Arc<Mutex<Interrupt>>) -> Self {
+    pub fn new(interrupt: Arc<Mutex<Interrupt>>, cgb: bool) -> Self {
         let image_data = RgbaImage::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
         let scan_line = RgbaImage::new(SCREEN_WIDTH as u32, 1);
 
         Self {
             clock: 0,
-            buf: RAM::new(0xFFFF),
-            bus: None,
-            dma: 0x00,
-            dma_started: false,
+            // unused registers between LCDC and OCPD
+            buf: RAM::new((ADDR_PPU_OCPD - ADDR_PPU_LCDC + 1) as usize),
+            vram: RAM::new(0x4000),
+            oam: RAM::new(0x00A0),
+            hdma: Hdma::new(),
             interrupt,
             image_data,
             scan_line,
+            pixels: vec![0; SCREEN_WIDTH as usize * SCREEN_HEIGHT as usize],
+            cgb,
+            transfer_dots: TRANSFER_DOTS,
+            bg_line: vec![BgPixel::default(); SCREEN_WIDTH as usize],
             ..Default::default()
         }
     }
 
-    pub fn init(&mut self, bus: Arc<Mutex<Box<dyn BusTrait + Send>>>) {
-        self.bus = Option::Some(bus);
+    /// Hand every finished frame to the Super Game Boy.
+    pub fn set_sgb(&mut self, sgb: Option<Arc<Mutex<Sgb>>>) {
+        self.sgb = sgb;
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn set_vram_unlocked(&mut self, unlocked: bool) {
+        self.vram_unlocked = unlocked;
+    }
+
+    pub fn set_oam_bug(&mut self, oam_bug: bool) {
+        self.oam_bug = oam_bug;
+    }
+
+    /// Jump to dot `dot` of line `ly`, e.g. where the boot ROM leaves the PPU.
+    pub fn set_position(&mut self, ly: Byte, dot: u16) {
+        self.scroll.ly = ly;
+        self.transfer_dots = TRANSFER_DOTS + (self.scroll.scx % 8) as u16;
+        let scan_end = LINE_START_DOTS + OAM_SCAN_DOTS;
+        let transfer_end = scan_end + self.transfer_dots;
+        (self.mode, self.dots) = match dot {
+            _ if ly >= 144 => (Mode::VBlank, dot),
+            _ if dot < scan_end => (Mode::SearchingOAM, dot),
+            _ if dot < transfer_end => (Mode::TransferringData, dot - scan_end),
+            _ => (Mode::HBlank, dot - transfer_end),
+        };
+        self.prev_mode = self.mode;
+        self.update_lyc_match();
+        if self.mode == Mode::TransferringData && self.renderer == Renderer::Fifo {
+            self.start_fifo_line();
+        }
     }
 
     pub fn step(&mut self, cycle: u16) {
         self.dots = self.dots.wrapping_add(cycle);
 
         if !self.lcdc.lcd_ppu_enable {
-            self.mode = Mode::HBlank;
-            self.prev_lcd_interrupt = false;
             return
         }
 
-        if self.mode == Mode::SearchingOAM && self.dots >= 80 {
-            self.dots -= 80;
+        if self.mode == Mode::TransferringData && self.renderer == Renderer::Fifo {
+            self.run_fifo(self.dots);
+        }
+
+        if self.mode == Mode::SearchingOAM && self.dots >= LINE_START_DOTS + OAM_SCAN_DOTS {
+            self.dots -= LINE_START_DOTS + OAM_SCAN_DOTS;
             self.mode = Mode::TransferringData;
-        } else if self.mode == Mode::TransferringData && self.dots >= 168 {
-            self.dots -= 168;
+            self.lcd_starting = false;
+            match self.renderer {
+                Renderer::Scanline => self.transfer_dots = self.transfer_length(),
+                Renderer::Fifo => self.start_fifo_line(),
+            }
+            self.update_lcd_interrupt();
+        } else if self.mode == Mode::TransferringData && self.dots >= self.transfer_dots {
+            self.dots -= self.transfer_dots;
             self.mode = Mode::HBlank;
             self.update_lcd_interrupt();
             self.render_line();
+            self.hdma.hblank();
 
-            if self.scroll.wx.saturating_sub(7) < SCREEN_WIDTH && self.scroll.wy <= self.scroll.ly {
+            if self.window_on_line {
                 self.window_rendering_counter = self.window_rendering_counter.wrapping_add(1);
             }
-        } else if self.mode == Mode::HBlank && self.dots >= 208 {
-            self.dots -= 208;
+            self.window_wraps = self.window_on_line && self.scroll.wx == 166;
+        } else if self.mode == Mode::HBlank && self.dots >= DRAWING_DOTS - self.transfer_dots {
+            self.dots -= DRAWING_DOTS - self.transfer_dots;
             self.scroll.ly += 1;
+            self.update_lyc_match();
             if self.scroll.ly >= 144 {
                 self.mode = Mode::VBlank;
+                self.skip_frame = false;
                 self.interrupt.lock().unwrap().request(INT_VBLANK_FLG);
+                if let Some(sgb) = &self.sgb {
+                    sgb.lock().unwrap().frame(&self.pixels);
+                }
+                // the OAM scan interrupt fires on line 144 too
+                let oam = self.lcds.oam_interrupt_enable;
+                self.set_lcd_interrupt_line(self.lcd_interrupt_line() || oam);
             } else {
                 self.mode = Mode::SearchingOAM;
+                self.latch_window_y();
+                self.update_lcd_interrupt();
             }
-
+        } else if self.mode == Mode::VBlank && self.scroll.ly == 153 && !self.ly_wrapped && self.dots >= LY_153_DOTS {
+            self.scroll.ly = 0;
+            self.ly_wrapped = true;
+            self.update_lyc_match();
             self.update_lcd_interrupt();
-        } else if self.mode == Mode::VBlank && self.dots >= 456 {
-            self.dots -= 456;
-            self.scroll.ly += 1;
-            if self.scroll.ly == 154 {
-                self.scroll.ly = 0;
-                self.window_rendering_counter = 0;
+        } else if self.mode == Mode::VBlank && self.dots >= LINE_DOTS {
+            self.dots -= LINE_DOTS;
+            if self.ly_wrapped {
+                self.ly_wrapped = false;
+                self.reset_window();
                 self.mode = Mode::SearchingOAM;
-                self.update_lcd_interrupt();
+                self.latch_window_y();
+            } else {
+                self.scroll.ly += 1;
+                self.update_lyc_match();
             }
-
+            self.update_lcd_interrupt();
         }
 
         self.prev_mode = self.mode;
     }
 
-    fn update_lcd_interrupt(&mut self) {
-        let cur_lcd_interrupt = match self.mode {
+    // every enabled source is ORed into one line, so a source going high
+    // while another one already holds the line up requests nothing
+    fn lcd_interrupt_line(&self) -> bool {
+        let lyc = self.lcds.lyc_interrupt_enable && self.lyc_match;
+        // the frozen LYC flag keeps the line up while the LCD is off
+        if !self.lcdc.lcd_ppu_enable {
+            return lyc;
+        }
+        // the first line after the LCD is turned on has no OAM scan interrupt
+        let mode = if self.lcd_starting { Mode::HBlank } else { self.mode };
+        let mode = match mode {
             Mode::HBlank => self.lcds.hblank_interrupt_enable,
             Mode::VBlank => self.lcds.vblank_interrupt_enable,
             Mode::SearchingOAM => self.lcds.oam_interrupt_enable,
-            _ => false,
-        } || (self.lcds.lyc_interrupt_enable
-            && self.scroll.ly == self.scroll.lyc);
+            Mode::TransferringData => false,
+        };
+        mode || lyc
+    }
 
-        if !self.prev_lcd_interrupt && cur_lcd_interrupt {
+    fn set_lcd_interrupt_line(&mut self, line: bool) {
+        if !self.prev_lcd_interrupt && line {
             self.interrupt.lock().unwrap().request(INT_LCD_STAT_FLG);
         }
-        self.prev_lcd_interrupt = cur_lcd_interrupt;
+        self.prev_lcd_interrupt = line;
     }
 
-    fn render_line(&mut self) {
-        for x in 0..SCREEN_WIDTH {
-            self.scan_line.put_pixel(x as u32, 0, self.palette.get_palette(0));
+    fn update_lcd_interrupt(&mut self) {
+        self.set_lcd_interrupt_line(self.lcd_interrupt_line());
+    }
+
+    fn update_lyc_match(&mut self) {
+        self.lyc_match = self.scroll.ly == self.scroll.lyc;
+    }
+
+    // LY hasn't caught up with a line after the first yet
+    fn line_starting(&self) -> bool {
+        self.mode == Mode::SearchingOAM && self.dots < LINE_START_DOTS && self.scroll.ly != 0
+    }
+
+    // the mode STAT shows: 0 until the first line after LCD on gets to mode 3,
+    // and at the start of every line
+    fn stat_mode(&self) -> Mode {
+        if self.lcd_starting || (self.mode == Mode::SearchingOAM && self.dots < LINE_START_DOTS) {
+            Mode::HBlank
+        } else {
+            self.mode
         }
-        if self.lcdc.bg_window_enable {
-            self.draw_bg_win_line();
+    }
+
+    // LY and the mode stay 0 and the screen goes blank until it's back on
+    fn turn_lcd_off(&mut self) {
+        self.scroll.ly = 0;
+        self.dots = 0;
+        self.mode = Mode::HBlank;
+        self.ly_wrapped = false;
+        self.lcd_starting = false;
+        self.update_lcd_interrupt();
+        self.blank_screen();
+    }
+
+    fn turn_lcd_on(&mut self) {
+        self.scroll.ly = 0;
+        self.dots = LCD_ON_DOTS;
+        self.mode = Mode::SearchingOAM;
+        self.reset_window();
+        self.latch_window_y();
+        self.lcd_starting = true;
+        self.skip_frame = true;
+        self.update_lyc_match();
+        self.update_lcd_interrupt();
+    }
+
+    // the window starts every frame from its first line
+    fn reset_window(&mut self) {
+        self.window_rendering_counter = 0;
+        self.window_y_triggered = false;
+        self.window_wraps = false;
+    }
+
+    // WY is only compared as the OAM scan starts, and once it matched the
+    // window stays in reach for the rest of the frame
+    fn latch_window_y(&mut self) {
+        if self.scroll.ly == self.scroll.wy {
+            self.window_y_triggered = true;
         }
+    }
 
-        if self.lcdc.obj_enable {
-            self.draw_sprite_line();
+    // what the LCD shows while it's off, lighter than any shade on DMG
+    fn blank_screen(&mut self) {
+        let white = if self.cgb {
+            image::Rgba([0xFF, 0xFF, 0xFF, 0xFF])
+        } else {
+            self.palette.get_color(0, Layer::Bg)
+        };
+        for p in self.image_data.pixels_mut() {
+            *p = white;
+        }
+        self.pixels.fill(0);
+    }
+
+    fn render_line(&mut self) {
+        if self.renderer == Renderer::Fifo {
+            self.finish_fifo_line();
+        } else {
+            self.draw_line();
         }
 
+        if self.skip_frame {
+            return;
+        }
         for x in 0..SCREEN_WIDTH {
             let c = self.scan_line.get_pixel(x as u32, 0);
             self.image_data.put_pixel(x as u32, self.scroll.ly as u32, *c);
         }
     }
 
+    fn draw_line(&mut self) {
+        let shade = self.palette.bg_shade(0);
+        for x in 0..SCREEN_WIDTH as usize {
+            self.put_pixel(x, self.palette.get_palette(0), shade, Layer::Bg);
+        }
+        self.bg_line.fill(BgPixel::default());
+        // fetched even while DMG's LCDC bit 0 blanks it
+        self.window_on_line = self.lcdc.window_enable
+            && self.window_y_triggered
+            && (self.window_wraps || self.scroll.wx <= 166);
+        // on CGB, LCDC bit 0 only takes the BG's priority over objects away
+        if self.lcdc.bg_window_enable || self.cgb {
+            self.draw_bg_win_line();
+        }
+
+        if self.lcdc.obj_enable {
+            self.draw_sprite_line();
+        }
+    }
+
     fn draw_bg_win_line(&mut self) {
         for x in 0..SCREEN_WIDTH {
-            let c = self.get_bg_win_tile_color(x);
-            self.scan_line.put_pixel(x as u32, 0, c);
+            let (color, attr) = self.get_bg_win_tile_color(x);
+            let c = if self.cgb {
+                self.palette.get_cgb_bg_color(attr.palette, color)
+            } else {
+                self.palette.get_palette(color)
+            };
+            self.bg_line[x as usize] = BgPixel {
+                color,
+                priority: attr.priority,
+            };
+            self.put_pixel(x as usize, c, self.palette.bg_shade(color), Layer::Bg);
         }
     }
 
-    fn draw_sprite_line(&mut self) {
+    // a pixel of the current line, with the DMG shade and layer it came from
+    fn put_pixel(&mut self, x: usize, c: image::Rgba<u8>, shade: Byte, layer: Layer) {
+        self.pixels[self.scroll.ly as usize * SCREEN_WIDTH as usize + x] = layer.pixel(shade);
+        self.scan_line.put_pixel(x as u32, 0, c);
+    }
+
+    // up to 10 objects on the current line, in OAM order
+    fn scan_oam(&self) -> Vec<Sprite> {
         let obj_height = if self.lcdc.obj_size { 16 } else { 8 };
         let mut obj_count = 0;
         let mut writable_objs: Vec<Sprite> = vec![];
@@ -172,7 +448,7 @@ impl Ppu {
                 let addr = ADDR_OAM_START
                     .wrapping_add(i.wrapping_mul(4))
                     .wrapping_add(j);
-                bytes4[j as usize] = self.bus_read(addr);
+                bytes4[j as usize] = self.read_oam(addr);
             }
             let s = Sprite::new(&bytes4);
 
@@ -188,9 +464,56 @@ impl Ppu {
                 }
             }
         }
+        writable_objs
+    }
+
+    // mode 3 takes 172 dots plus the fine scroll dropped off the first tile,
+    // the window restarting the fetcher and every object fetched
+    fn transfer_length(&self) -> u16 {
+        let mut dots = TRANSFER_DOTS + (self.scroll.scx % 8) as u16;
+        let window = self.lcdc.window_enable && self.window_y_triggered && (self.scroll.wx < 167 || self.window_wraps);
+        if window {
+            dots += WINDOW_FETCH_DOTS;
+        }
+        if self.lcdc.obj_enable {
+            let mut objs = self.scan_oam();
+            objs.sort_by_key(|s| s.x);
+            let mut tiles = vec![];
+            // objects past the right edge are never fetched
+            for obj in objs.iter().filter(|s| s.x < SCREEN_WIDTH + 8) {
+                let in_window = window && obj.x > self.scroll.wx;
+                dots += self.obj_penalty(obj.x, in_window, &mut tiles);
+            }
+        }
+        dots
+    }
+
+    // the first object over a BG or window tile also waits for that tile's
+    // fetch, longer the further left in the tile it starts
+    fn obj_penalty(&self, obj_x: Byte, in_window: bool, tiles: &mut Vec<(bool, u16)>) -> u16 {
+        let offset = if in_window { 255 - self.scroll.wx } else { self.scroll.scx };
+        let pos = obj_x as u16 + offset as u16;
+        let tile = (in_window, pos / 8);
+        if tiles.contains(&tile) {
+            return OBJ_FETCH_DOTS;
+        }
+        tiles.push(tile);
+        OBJ_FETCH_DOTS + 5u16.saturating_sub(pos % 8)
+    }
+
+    fn draw_sprite_line(&mut self) {
+        let obj_height = if self.lcdc.obj_size { 16 } else { 8 };
+        let mut writable_objs = self.scan_oam();
 
-        writable_objs.sort_by_key(|s| s.x);
+        // drawn back to front: on CGB the object earlier in OAM is on top,
+        // on DMG the one further left, then the one earlier in OAM
+        if !self.cgb {
+            writable_objs.sort_by_key(|s| s.x);
+        }
+        writable_objs.reverse();
 
+        // colour, shade and layer, and BG-over-OBJ flag of the topmost object pixel
+        let mut obj_line: Vec<Option<(image::Rgba<u8>, Byte, bool)>> = vec![None; SCREEN_WIDTH as usize];
         for obj in writable_objs {
             for x in 0..8 {
                 let x_pos = (obj.x as i16) - 8 + x as i16;
@@ -200,44 +523,76 @@ impl Ppu {
                 }
 
                 let offset_x = if obj.x_flip() { 7 - x } else { x };
-                let mut offset_y: i16 = self.scroll.ly as i16 + 16 - obj.y as i16;
-                if obj.y_flip() {
-                    offset_y = obj_height as i16 - 1 - offset_y;
-                };
-
-                let tile_idx = if obj_height == 16 { obj.tile_idx & !1} else {obj.tile_idx};
-
-                let tile_color_base_addr = (0x8000 as Word)
-                    .wrapping_add((tile_idx as i16).wrapping_mul(16) as Word)
-                    .wrapping_add(offset_y.wrapping_mul(2) as Word);
-
-                let lower = self.bus_read(tile_color_base_addr);
-                let upper = self.bus_read(tile_color_base_addr + 1);
+                let (lower, upper) = self.obj_tile_row(&obj, obj_height);
                 let lb = bit(&lower, &(7 - offset_x));
                 let ub = bit(&upper, &(7 - offset_x));
 
                 let color = (ub << 1) + lb;
                 if color != 0 {
-                    let c = self.palette.get_obj_palette(color, obj.mgb_palette_no());
-                    // self.image_data
-                    //     .put_pixel(x_pos as u32, self.scroll.ly as u32, c);
-                    self.scan_line.put_pixel(x_pos as u32, 0, c);
+                    let c = if self.cgb {
+                        self.palette.get_cgb_obj_color(obj.cgb_palette_no(), color)
+                    } else {
+                        self.palette.get_obj_palette(color, obj.mgb_palette_no())
+                    };
+                    let shade = self.palette.obj_shade(color, obj.mgb_palette_no());
+                    let pixel = Layer::obj(obj.mgb_palette_no()).pixel(shade);
+                    obj_line[x_pos as usize] = Some((c, pixel, obj.behind_bg()));
                 }
             }
         }
+
+        for (x, pixel) in obj_line.into_iter().enumerate() {
+            let Some((c, pixel, behind_bg)) = pixel else {
+                continue;
+            };
+            let hidden = if self.cgb {
+                self.bg_covers_obj(x, behind_bg)
+            } else {
+                behind_bg && self.bg_line[x].color != 0
+            };
+            if hidden {
+                continue;
+            }
+            let (shade, layer) = Layer::split(pixel);
+            self.put_pixel(x, c, shade, layer);
+        }
     }
 
-    fn get_bg_win_tile_color(&mut self, lx: u8) -> image::Rgba<u8> {
+    // both bytes of the object's tile row on the current line
+    fn obj_tile_row(&self, obj: &Sprite, obj_height: i16) -> (Byte, Byte) {
+        let mut offset_y: i16 = self.scroll.ly as i16 + 16 - obj.y as i16;
+        if obj.y_flip() {
+            offset_y = obj_height - 1 - offset_y;
+        };
+
+        let tile_idx = if obj_height == 16 { obj.tile_idx & !1} else {obj.tile_idx};
+
+        let tile_color_base_addr = (0x8000 as Word)
+            .wrapping_add((tile_idx as i16).wrapping_mul(16) as Word)
+            .wrapping_add(offset_y.wrapping_mul(2) as Word);
+
+        let bank = if self.cgb { obj.vram_bank() as Byte } else { 0 };
+        (self.read_vram(bank, tile_color_base_addr), self.read_vram(bank, tile_color_base_addr + 1))
+    }
+
+    // CGB: BG colours 1-3 are drawn over the object when either the tile
+    // attribute or the object asks for it, unless LCDC bit 0 is cleared
+    fn bg_covers_obj(&self, x: usize, behind_bg: bool) -> bool {
+        let bg = self.bg_line[x];
+        self.lcdc.bg_window_enable && bg.color != 0 && (bg.priority || behind_bg)
+    }
+
+    fn get_bg_win_tile_color(&mut self, lx: u8) -> (Byte, TileAttr) {
         let window_writable = self.lcdc.window_enable
-            && (self.scroll.ly >= self.scroll.wy)
-            && (lx + 7 >= self.scroll.wx);
+            && self.window_y_triggered
+            && (self.window_wraps || lx as u16 + 7 >= self.scroll.wx as u16);
         let y_pos: Byte;
         let x_pos: Byte;
         let base_addr: Word;
 
         if window_writable {
             y_pos = self.window_rendering_counter;
-            x_pos = lx.wrapping_sub(self.scroll.wx.wrapping_sub(7));
+            x_pos = if self.window_wraps { lx } else { lx.wrapping_sub(self.scroll.wx.wrapping_sub(7)) };
             base_addr = if self.lcdc.window_tile_map_area {
                 WINDOW_TILE_MAP_AREA_1
             } else {
@@ -256,58 +611,387 @@ impl Ppu {
         self.get_tile_color(x_pos, y_pos, base_addr)
     }
 
-    fn get_tile_color(&self, x_pos: u8, y_pos: u8, base_addr: Word) -> image::Rgba<u8> {
+    /// Colour index of the BG/window pixel and the attributes of its tile.
+    fn get_tile_color(&self, x_pos: u8, y_pos: u8, base_addr: Word) -> (Byte, TileAttr) {
         let addr = Tile::get_tile_addr(y_pos, x_pos, base_addr);
-        let tile_idx = self.bus_read(addr);
+        let tile_idx = self.read_vram(0, addr);
+        let attr = if self.cgb {
+            TileAttr::from(self.read_vram(1, addr))
+        } else {
+            TileAttr::default()
+        };
+        let tile_x = if attr.x_flip { 7 - x_pos % 8 } else { x_pos % 8 };
+
+        let tile_color_base_addr = self.tile_data_addr(tile_idx, y_pos, attr);
+        let lower = self.read_vram(attr.bank, tile_color_base_addr);
+        let upper = self.read_vram(attr.bank, tile_color_base_addr + 1);
+        let lb = bit(&lower, &(7 - tile_x));
+        let ub = bit(&upper, &(7 - tile_x));
+
+        ((ub << 1) + lb, attr)
+    }
+
+    // first byte of the BG/window tile row at y_pos
+    fn tile_data_addr(&self, tile_idx: Byte, y_pos: Byte, attr: TileAttr) -> Word {
+        let tile_y = if attr.y_flip { 7 - y_pos % 8 } else { y_pos % 8 };
 
         let offset;
         if !self.lcdc.bg_window_tile_data_area {
             offset = (0x1000 as i16)
                 .wrapping_add((tile_idx as i8 as i16).wrapping_mul(16))
-                .wrapping_add((y_pos % 8).wrapping_mul(2) as i16) as Word;
+                .wrapping_add(tile_y.wrapping_mul(2) as i16) as Word;
         } else {
             offset = (tile_idx as i16)
                 .wrapping_mul(16)
-                .wrapping_add((y_pos % 8).wrapping_mul(2) as i16) as Word;
+                .wrapping_add(tile_y.wrapping_mul(2) as i16) as Word;
+        }
+
+        (0x8000 as Word).wrapping_add(offset)
+    }
+
+    fn start_fifo_line(&mut self) {
+        // known once the last pixel is out
+        self.transfer_dots = u16::MAX;
+        self.fifo = Fifo {
+            stall: TILE_FETCH_DOTS,
+            // the fine scroll is dropped off the first tile
+            discard: self.scroll.scx % 8,
+            sprites: self.scan_oam().into_iter().enumerate().map(|(i, s)| (i as Byte, s)).collect(),
+            ..Default::default()
+        };
+        self.window_on_line = false;
+        if self.window_wraps && self.lcdc.window_enable {
+            self.start_window();
+            self.fifo.discard = 0;
+        }
+    }
+
+    // catch up with mode 3 until `dots` into it
+    fn run_fifo(&mut self, dots: u16) {
+        while self.fifo.dots < dots && self.fifo.lx < SCREEN_WIDTH {
+            self.fifo.dots += 1;
+            self.tick_fifo();
+            if self.fifo.lx == SCREEN_WIDTH {
+                self.transfer_dots = self.fifo.dots;
+            }
+        }
+    }
+
+    fn finish_fifo_line(&mut self) {
+        while self.fifo.lx < SCREEN_WIDTH {
+            self.tick_fifo();
+        }
+    }
+
+    // one dot: advance the fetcher and shift out a pixel if there is one
+    fn tick_fifo(&mut self) {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+        self.tick_fetcher();
+        if self.fifo.bg.is_empty() {
+            return;
+        }
+
+        // an object starting at this pixel stops the output while it is fetched
+        if self.lcdc.obj_enable {
+            let lx = self.fifo.lx as u16;
+            let next = self.fifo.sprites.iter().enumerate()
+                .filter(|(_, (_, s))| s.x as u16 <= lx + 8)
+                .min_by_key(|(_, (i, s))| (s.x, *i))
+                .map(|(n, _)| n);
+            if let Some(n) = next {
+                let (oam_index, sprite) = self.fifo.sprites.remove(n);
+                self.fetch_obj(oam_index, &sprite);
+                let mut tiles = std::mem::take(&mut self.fifo.penalized_tiles);
+                // this dot is part of the penalty
+                self.fifo.stall = self.obj_penalty(sprite.x, self.fifo.window, &mut tiles) - 1;
+                self.fifo.penalized_tiles = tiles;
+                return;
+            }
+        }
+
+        // turning the window off sends the fetcher back to the BG, on from the tile it got to
+        if self.fifo.window && !self.lcdc.window_enable {
+            self.fifo.window = false;
+        }
+
+        if !self.fifo.window && self.window_starts() {
+            // the window restarts the fetcher at its first tile
+            self.start_window();
+            // WX below 7 scrolls the window's left edge off screen, and
+            // WX=0 also takes what's left of the fine scroll off it
+            let fine_scroll = if self.scroll.wx == 0 { self.fifo.discard } else { 0 };
+            self.fifo.discard = 7u8.saturating_sub(self.scroll.wx) + fine_scroll;
+            self.tick_fetcher();
+            return;
+        }
+
+        if self.fifo.discard > 0 {
+            self.fifo.bg.pop_front();
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let bg = self.fifo.bg.pop_front().unwrap();
+        let obj = self.fifo.obj.pop_front();
+        self.put_fifo_pixel(bg, obj);
+        self.fifo.lx += 1;
+    }
+
+    // WX is compared with each pixel as it goes out, so the window is missed
+    // if it's enabled or WX moved after that pixel
+    fn window_starts(&self) -> bool {
+        if !self.lcdc.window_enable || !self.window_y_triggered {
+            return false;
+        }
+        let lx = self.fifo.lx as u16;
+        match self.scroll.wx {
+            // caught while the fine scroll is still being dropped
+            0 => lx == 0,
+            wx => self.fifo.discard == 0 && lx + 7 == (wx as u16).max(7),
+        }
+    }
+
+    fn start_window(&mut self) {
+        self.fifo.window = true;
+        self.fifo.tile_x = 0;
+        self.fifo.step = FetchStep::Tile;
+        self.fifo.step_dot = 0;
+        self.fifo.bg.clear();
+        self.window_on_line = true;
+    }
+
+    fn tick_fetcher(&mut self) {
+        if self.fifo.step == FetchStep::Push {
+            // waits until the FIFO ran dry
+            if self.fifo.bg.is_empty() {
+                let (low, high, attr) = (self.fifo.low, self.fifo.high, self.fifo.attr);
+                for i in 0..8 {
+                    let x = if attr.x_flip { i } else { 7 - i };
+                    self.fifo.bg.push_back(FifoPixel {
+                        color: bit(&high, &x) << 1 | bit(&low, &x),
+                        palette: attr.palette,
+                        priority: attr.priority,
+                        oam_index: 0,
+                    });
+                }
+                self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+                self.fifo.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        // every other step takes 2 dots, VRAM is read on the second
+        self.fifo.step_dot += 1;
+        if self.fifo.step_dot < 2 {
+            return;
         }
+        self.fifo.step_dot = 0;
 
-        let tile_color_base_addr = (0x8000 as Word).wrapping_add(offset);
+        let y_pos = if self.fifo.window {
+            self.window_rendering_counter
+        } else {
+            self.scroll.ly.wrapping_add(self.scroll.scy)
+        };
+        match self.fifo.step {
+            FetchStep::Tile => {
+                let (x_pos, base_addr) = if self.fifo.window {
+                    let base_addr = if self.lcdc.window_tile_map_area {
+                        WINDOW_TILE_MAP_AREA_1
+                    } else {
+                        WINDOW_TILE_MAP_AREA_0
+                    };
+                    (self.fifo.tile_x.wrapping_mul(8), base_addr)
+                } else {
+                    let base_addr = if self.lcdc.bg_tile_map_area {
+                        BG_TILE_MAP_AREA_1
+                    } else {
+                        BG_TILE_MAP_AREA_0
+                    };
+                    ((self.scroll.scx & !7).wrapping_add(self.fifo.tile_x.wrapping_mul(8)), base_addr)
+                };
+                let addr = Tile::get_tile_addr(y_pos, x_pos, base_addr);
+                self.fifo.tile_idx = self.read_vram(0, addr);
+                self.fifo.attr = if self.cgb {
+                    TileAttr::from(self.read_vram(1, addr))
+                } else {
+                    TileAttr::default()
+                };
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                let addr = self.tile_data_addr(self.fifo.tile_idx, y_pos, self.fifo.attr);
+                self.fifo.low = self.read_vram(self.fifo.attr.bank, addr);
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                let addr = self.tile_data_addr(self.fifo.tile_idx, y_pos, self.fifo.attr);
+                self.fifo.high = self.read_vram(self.fifo.attr.bank, addr + 1);
+                self.fifo.step = FetchStep::Push;
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
 
-        let lower = self.bus_read(tile_color_base_addr);
-        let upper = self.bus_read(tile_color_base_addr + 1);
-        let lb = bit(&lower, &(7 - (x_pos % 8)));
-        let ub = bit(&upper, &(7 - (x_pos % 8)));
+    // mix the object's row into the OBJ FIFO, where it only fills
+    // transparent pixels, or on CGB beats objects later in OAM
+    fn fetch_obj(&mut self, oam_index: Byte, obj: &Sprite) {
+        let obj_height = if self.lcdc.obj_size { 16 } else { 8 };
+        let (low, high) = self.obj_tile_row(obj, obj_height);
+        // an object hanging off the left edge loses its first pixels
+        let skip = (self.fifo.lx as usize + 8).saturating_sub(obj.x as usize).min(8);
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(FifoPixel::default());
+        }
+        for i in skip..8 {
+            let x = if obj.x_flip() { i as u8 } else { 7 - i as u8 };
+            let pixel = FifoPixel {
+                color: bit(&high, &x) << 1 | bit(&low, &x),
+                palette: if self.cgb { obj.cgb_palette_no() } else { obj.mgb_palette_no() },
+                priority: obj.behind_bg(),
+                oam_index,
+            };
+            let slot = &mut self.fifo.obj[i - skip];
+            if pixel.color != 0 && (slot.color == 0 || (self.cgb && pixel.oam_index < slot.oam_index)) {
+                *slot = pixel;
+            }
+        }
+    }
 
-        let color = (ub << 1) + lb;
-        self.palette.get_palette(color)
+    fn put_fifo_pixel(&mut self, bg: FifoPixel, obj: Option<FifoPixel>) {
+        let x = self.fifo.lx as usize;
+        // on DMG, LCDC bit 0 blanks the BG and window
+        let bg_color = if self.lcdc.bg_window_enable || self.cgb { bg.color } else { 0 };
+        self.bg_line[x] = BgPixel {
+            color: bg_color,
+            priority: bg.priority,
+        };
+        let mut c = if self.cgb {
+            self.palette.get_cgb_bg_color(bg.palette, bg_color)
+        } else {
+            self.palette.get_palette(bg_color)
+        };
+        let mut shade = self.palette.bg_shade(bg_color);
+        let mut layer = Layer::Bg;
+
+        if let Some(obj) = obj.filter(|o| o.color != 0 && self.lcdc.obj_enable) {
+            let hidden = if self.cgb {
+                self.bg_covers_obj(x, obj.priority)
+            } else {
+                obj.priority && bg_color != 0
+            };
+            if !hidden {
+                c = if self.cgb {
+                    self.palette.get_cgb_obj_color(obj.palette, obj.color)
+                } else {
+                    self.palette.get_obj_palette(obj.color, obj.palette)
+                };
+                shade = self.palette.obj_shade(obj.color, obj.palette);
+                layer = Layer::obj(obj.palette);
+            }
+        }
+
+        self.put_pixel(x, c, shade, layer);
+    }
+
+    /// Source and destination of the VRAM DMA block due now, if any.
+    pub fn next_hdma_block(&mut self) -> Option<(Word, Word)> {
+        self.hdma.next_block()
+    }
+
+    /// The CPU can get to VRAM, which the PPU holds on to in mode 3.
+    pub fn vram_accessible(&self) -> bool {
+        self.vram_unlocked || self.stat_mode() != Mode::TransferringData
     }
 
-    pub fn transfer_oam(&mut self) {
-        let addr = self.dma as Word * 0x100;
-        for i in 0..0xA0 {
-            let b = self.bus_read(addr + i);
-            self.bus_write(ADDR_OAM_START + i, b);
+    /// The CPU put an address in 0xFE00-0xFEFF on the bus. During the OAM
+    /// scan, on a model with the OAM bug, this corrupts the row the PPU is reading.
+    pub fn trigger_oam_bug(&mut self, access: OamBugAccess) {
+        if !self.oam_bug || self.stat_mode() != Mode::SearchingOAM {
+            return;
+        }
+        let dots = self.dots - LINE_START_DOTS;
+        if dots < OAM_SCAN_DOTS {
+            corrupt_oam(&mut self.oam.buf, dots as usize / 4, access);
         }
+    }
+
+    /// The CPU can get to OAM, which the PPU holds on to in modes 2 and 3.
+    pub fn oam_accessible(&self) -> bool {
+        self.vram_unlocked || !matches!(self.stat_mode(), Mode::SearchingOAM | Mode::TransferringData)
+    }
+
+    /// Read VRAM bank `bank` regardless of which bank the CPU has selected.
+    pub fn read_vram(&self, bank: Byte, addr: Word) -> Byte {
+        self.vram.read(bank as Word * 0x2000 + (addr - 0x8000))
+    }
+
+    pub fn write_vram(&mut self, bank: Byte, addr: Word, value: Byte) {
+        self.vram.write(bank as Word * 0x2000 + (addr - 0x8000), value)
+    }
+
+    /// Read OAM as the PPU sees it, even while the CPU is locked out.
+    pub fn read_oam(&self, addr: Word) -> Byte {
+        self.oam.read(addr - ADDR_OAM_START)
+    }
+
+    pub fn write_oam(&mut self, addr: Word, value: Byte) {
+        self.oam.write(addr - ADDR_OAM_START, value)
+    }
+
+    /// Use the same colours for the BG/window and both object palettes.
+    pub fn set_dmg_colors(&mut self, colors: DmgColors) {
+        self.set_layer_colors(colors.into());
+    }
 
-        self.dma_started = false;
+    /// Colours of the DMG shades, applied to the frame on screen as well.
+    pub fn set_layer_colors(&mut self, colors: LayerColors) {
+        self.palette.colors = colors;
+        self.recolor();
     }
 
-    fn bus_read(&self, addr: Word) -> Byte {
-        self.bus.as_ref().unwrap().lock().unwrap().read(addr)
+    pub fn layer_colors(&self) -> LayerColors {
+        self.palette.colors
     }
 
-    fn bus_write(&self, addr: Word, value: Byte) {
-        self.bus
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .write(addr, value);
+    /// Colours a CGB uses for a DMG game, overriding the DMG shades.
+    /// Applied to the frame on screen as well.
+    pub fn set_compat_colors(&mut self, colors: Option<LayerColors>) {
+        self.palette.compat = colors;
+        self.recolor();
+    }
+
+    // colour the frame again from its shades, in CGB mode it has none
+    fn recolor(&mut self) {
+        if !self.cgb {
+            self.image_data = Self::render_pixels(&self.pixels, self.palette.layer_colors());
+        }
     }
 
     pub fn display(&self) -> image::RgbaImage {
         self.image_data.clone()
     }
+
+    /// The current frame before it's coloured, a byte per pixel: the DMG shade
+    /// in bits 0-1 and the `Layer` whose palette picked it in bits 2-3.
+    /// Only DMG games have shades, in CGB mode they come from BGP, OBP0 and OBP1.
+    /// The first frame after the LCD is turned on is drawn here, as the SGB
+    /// sees it, though the screen doesn't show it.
+    pub fn pixels(&self) -> &[Byte] {
+        &self.pixels
+    }
+
+    /// Colour a frame from `pixels` with `colors`.
+    pub fn render_pixels(pixels: &[Byte], colors: &LayerColors) -> RgbaImage {
+        let mut image = RgbaImage::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        for (p, &pixel) in image.pixels_mut().zip(pixels) {
+            let (shade, layer) = Layer::split(pixel);
+            *p = PaletteEnum::get_color(PaletteEnum::from_u8(shade), colors.layer(layer));
+        }
+        image
+    }
 }
 
 impl Reader for Ppu {
@@ -334,15 +1018,16 @@ impl Reader for Ppu {
                 v.set(5, self.lcds.oam_interrupt_enable);
                 v.set(4, self.lcds.vblank_interrupt_enable);
                 v.set(3, self.lcds.hblank_interrupt_enable);
-                v.set(2, self.scroll.lyc == self.scroll.ly);
-                v.set(1, (self.mode as u8 & 0b10) == 0b10);
-                v.set(0, (self.mode as u8 & 0b01) == 0b01);
+                v.set(2, self.lyc_match);
+                v.set(1, (self.stat_mode() as u8 & 0b10) == 0b10);
+                v.set(0, (self.stat_mode() as u8 & 0b01) == 0b01);
                 value
             }
+            ADDR_PPU_LY if self.line_starting() => self.scroll.ly - 1,
             ADDR_PPU_SCY..=ADDR_PPU_LYC | ADDR_PPU_WY | ADDR_PPU_WX => self.scroll.read(addr),
             ADDR_PPU_BGP..=ADDR_PPU_OBP1 | ADDR_PPU_BCPS..=ADDR_PPU_OCPD => self.palette.read(addr),
-            ADDR_PPU_DMA => self.dma,
-            v => self.buf.read(addr),
+            ADDR_HDMA1..=ADDR_HDMA5 => self.hdma.read(addr),
+            _ => self.buf.read(addr - ADDR_PPU_LCDC),
         }
     }
 }
@@ -357,42 +1042,121 @@ impl Writer for Ppu {
                     log::warn!("Stopping LCD operation (Bit 7 from 1 to 0) may be performed during VBlank ONLY");
                 }
 
-                // initialize scrolling data
-                if !self.lcdc.lcd_ppu_enable && v[7] {
-                    self.scroll.ly = 0;
-                    self.dots = 0;
+                let was_on = self.lcdc.lcd_ppu_enable;
+                self.lcdc = Lcdc::from(value);
+                if was_on && !v[7] {
+                    self.turn_lcd_off();
+                } else if !was_on && v[7] {
+                    self.turn_lcd_on();
                 }
-                self.lcdc.lcd_ppu_enable = v[7];
-                self.lcdc.window_tile_map_area = v[6];
-                self.lcdc.window_enable = v[5];
-                self.lcdc.bg_window_tile_data_area = v[4];
-                self.lcdc.bg_tile_map_area = v[3];
-                self.lcdc.obj_size = v[2];
-                self.lcdc.obj_enable = v[1];
-                self.lcdc.bg_window_enable = v[0];
             }
             ADDR_PPU_LCDS => {
-                let v = value.view_bits::<Lsb0>();
-                self.lcds.lyc_interrupt_enable = v[6];
-                self.lcds.oam_interrupt_enable = v[5];
-                self.lcds.vblank_interrupt_enable = v[4];
-                self.lcds.hblank_interrupt_enable = v[3];
+                self.lcds = Lcds::from(value);
+                self.update_lcd_interrupt();
+            }
+            ADDR_PPU_LYC => {
+                self.scroll.write(addr, value);
+                if self.lcdc.lcd_ppu_enable {
+                    self.update_lyc_match();
+                    self.update_lcd_interrupt();
+                }
             }
-            ADDR_PPU_SCY..=ADDR_PPU_LYC | ADDR_PPU_WY | ADDR_PPU_WX => {
+            ADDR_PPU_SCY..=ADDR_PPU_LY | ADDR_PPU_WY | ADDR_PPU_WX => {
                 self.scroll.write(addr, value)
             }
             ADDR_PPU_BGP..=ADDR_PPU_OBP1 | ADDR_PPU_BCPS..=ADDR_PPU_OCPD => {
                 self.palette.write(addr, value)
             }
-            ADDR_PPU_DMA => {
-                self.dma_started = true;
-                self.dma = value;
-            }
-            v => self.buf.write(addr, value),
+            ADDR_HDMA1..=ADDR_HDMA5 => self.hdma.write(addr, value),
+            _ => self.buf.write(addr - ADDR_PPU_LCDC, value),
         }
     }
 }
 
+impl Savable for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.clock);
+        self.buf.save_state(w);
+        self.vram.save_state(w);
+        self.oam.save_state(w);
+        w.write_u8(self.read(ADDR_PPU_LCDC));
+        w.write_u8(self.read(ADDR_PPU_LCDS));
+        for addr in [ADDR_PPU_SCY, ADDR_PPU_SCX, ADDR_PPU_LY, ADDR_PPU_LYC, ADDR_PPU_WY, ADDR_PPU_WX] {
+            w.write_u8(self.scroll.read(addr));
+        }
+        w.write_u16(self.dots);
+        for addr in [ADDR_PPU_BGP, ADDR_PPU_OBP0, ADDR_PPU_OBP1, ADDR_PPU_BCPS, ADDR_PPU_OCPS] {
+            w.write_u8(self.palette.read(addr));
+        }
+        w.write_bytes(&self.palette.bg_ram);
+        w.write_bytes(&self.palette.obj_ram);
+        w.write_bytes(self.image_data.as_raw());
+        w.write_bytes(&self.pixels);
+        self.hdma.save_state(w);
+        w.write_u8(self.mode as u8);
+        w.write_u8(self.prev_mode as u8);
+        w.write_bool(self.prev_lcd_interrupt);
+        w.write_u8(self.window_rendering_counter);
+        w.write_bool(self.window_y_triggered);
+        w.write_bool(self.window_wraps);
+        w.write_u16(self.transfer_dots);
+        w.write_bool(self.lyc_match);
+        w.write_bool(self.ly_wrapped);
+        w.write_bool(self.lcd_starting);
+        w.write_bool(self.skip_frame);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.clock = r.read_u16()?;
+        self.buf.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.oam.load_state(r)?;
+        self.lcdc = Lcdc::from(r.read_u8()?);
+        self.lcds = Lcds::from(r.read_u8()?);
+        for addr in [ADDR_PPU_SCY, ADDR_PPU_SCX, ADDR_PPU_LY, ADDR_PPU_LYC, ADDR_PPU_WY, ADDR_PPU_WX] {
+            self.scroll.write(addr, r.read_u8()?);
+        }
+        self.dots = r.read_u16()?;
+        for addr in [ADDR_PPU_BGP, ADDR_PPU_OBP0, ADDR_PPU_OBP1, ADDR_PPU_BCPS, ADDR_PPU_OCPS] {
+            self.palette.write(addr, r.read_u8()?);
+        }
+        r.read_bytes_into(&mut self.palette.bg_ram)?;
+        r.read_bytes_into(&mut self.palette.obj_ram)?;
+        let mut image_data = vec![0; self.image_data.as_raw().len()];
+        r.read_bytes_into(&mut image_data)?;
+        self.image_data = RgbaImage::from_raw(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, image_data).unwrap();
+        r.read_bytes_into(&mut self.pixels)?;
+        // the state may have been saved with other colours
+        self.recolor();
+        self.hdma.load_state(r)?;
+        self.mode = Mode::from_u8(r.read_u8()?)?;
+        self.prev_mode = Mode::from_u8(r.read_u8()?)?;
+        self.prev_lcd_interrupt = r.read_bool()?;
+        self.window_rendering_counter = r.read_u8()?;
+        self.window_y_triggered = r.read_bool()?;
+        self.window_wraps = r.read_bool()?;
+        self.transfer_dots = r.read_u16()?;
+        // mode 3 of the FIFO renderer doesn't know its length until it's over
+        let unknown = self.mode == Mode::TransferringData && self.transfer_dots == u16::MAX;
+        if self.transfer_dots > DRAWING_DOTS && !unknown {
+            bail!("Invalid mode 3 length {}", self.transfer_dots);
+        }
+        self.lyc_match = r.read_bool()?;
+        self.ly_wrapped = r.read_bool()?;
+        self.lcd_starting = r.read_bool()?;
+        self.skip_frame = r.read_bool()?;
+        // a line half way through mode 3 is fetched again from its start
+        if self.renderer == Renderer::Fifo && self.mode == Mode::TransferringData {
+            self.start_fifo_line();
+        }
+        // the scanline renderer works out the length the FIFO renderer left open
+        if self.renderer == Renderer::Scanline && unknown {
+            self.transfer_dots = self.transfer_length();
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 struct Scroll {
     scy: Byte,
@@ -477,6 +1241,22 @@ struct Lcdc {
     pub bg_window_enable: bool,
 }
 
+impl From<Byte> for Lcdc {
+    fn from(value: Byte) -> Self {
+        let v = value.view_bits::<Lsb0>();
+        Self {
+            lcd_ppu_enable: v[7],
+            window_tile_map_area: v[6],
+            window_enable: v[5],
+            bg_window_tile_data_area: v[4],
+            bg_tile_map_area: v[3],
+            obj_size: v[2],
+            obj_enable: v[1],
+            bg_window_enable: v[0],
+        }
+    }
+}
+
 impl fmt::Display for Lcdc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Lcdc: {:?}", self)
@@ -506,6 +1286,18 @@ struct Lcds {
     pub hblank_interrupt_enable: bool,
 }
 
+impl From<Byte> for Lcds {
+    fn from(value: Byte) -> Self {
+        let v = value.view_bits::<Lsb0>();
+        Self {
+            lyc_interrupt_enable: v[6],
+            oam_interrupt_enable: v[5],
+            vblank_interrupt_enable: v[4],
+            hblank_interrupt_enable: v[3],
+        }
+    }
+}
+
 impl fmt::Display for Lcds {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Lcds: {:?}", self)
@@ -520,13 +1312,9 @@ enum PaletteEnum {
 }
 
 impl PaletteEnum {
-    pub fn get_color(palette: PaletteEnum) -> image::Rgba<u8> {
-        match palette {
-            PaletteEnum::White => image::Rgba([175, 197, 160, 255]),
-            PaletteEnum::LightGray => image::Rgba([93, 147, 66, 255]),
-            PaletteEnum::DarkGray => image::Rgba([22, 63, 48, 255]),
-            PaletteEnum::Black => image::Rgba([0, 40, 0, 255]),
-        }
+    pub fn get_color(palette: PaletteEnum, colors: &DmgColors) -> image::Rgba<u8> {
+        let [r, g, b] = colors[palette as usize];
+        image::Rgba([r, g, b, 255])
     }
 
     pub fn from_u8(value: u8) -> Self {
@@ -540,8 +1328,139 @@ impl PaletteEnum {
     }
 }
 
-#[derive(Default)]
+/// RGB of the four DMG shades: white, light gray, dark gray, black
+pub type DmgColors = [[Byte; 3]; 4];
+
+pub const DMG_COLORS_GREEN: DmgColors = [[175, 197, 160], [93, 147, 66], [22, 63, 48], [0, 40, 0]];
+pub const DMG_COLORS_GRAY: DmgColors = [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]];
+// Game Boy Pocket
+pub const DMG_COLORS_POCKET: DmgColors = [[196, 207, 161], [139, 149, 109], [77, 83, 60], [31, 31, 31]];
+
+/// The built-in DMG colours by name.
+pub const DMG_PALETTES: [(&str, DmgColors); 3] = [
+    ("green", DMG_COLORS_GREEN),
+    ("gray", DMG_COLORS_GRAY),
+    ("pocket", DMG_COLORS_POCKET),
+];
+
+/// Convert a little endian RGB555 colour as used by CGB and SGB palettes.
+pub(crate) fn rgb555(value: Word) -> image::Rgba<u8> {
+    // 5 bit to 8 bit, so that 0x1F becomes 0xFF
+    let channel = |shift: u16| {
+        let v = ((value >> shift) & 0x1F) as u8;
+        (v << 3) | (v >> 2)
+    };
+    image::Rgba([channel(0), channel(5), channel(10), 255])
+}
+
+/// Apply the OAM bug to `oam` while the PPU reads row `row` (8 bytes, two objects).
+/// The corrupted words are mixed from the row before it; the first row is left alone.
+fn corrupt_oam(oam: &mut [Byte], row: usize, access: OamBugAccess) {
+    let word = |oam: &[Byte], row: usize, i: usize| {
+        let at = row * 8 + i * 2;
+        bytes_2_word(oam[at + 1], oam[at])
+    };
+    let set_word = |oam: &mut [Byte], row: usize, i: usize, value: Word| {
+        let at = row * 8 + i * 2;
+        oam[at] = extract_lower(value);
+        oam[at + 1] = extract_upper(value);
+    };
+    let copy_row = |oam: &mut [Byte], from: usize, to: usize, start: usize| {
+        oam.copy_within(from * 8 + start..from * 8 + 8, to * 8 + start);
+    };
+
+    if access == OamBugAccess::ReadIncrease && (4..OAM_ROWS - 1).contains(&row) {
+        let (a, b, c, d) = (word(oam, row - 2, 0), word(oam, row - 1, 0), word(oam, row, 0), word(oam, row - 1, 2));
+        set_word(oam, row - 1, 0, (b & (a | c | d)) | (a & c & d));
+        copy_row(oam, row - 1, row, 0);
+        copy_row(oam, row - 1, row - 2, 0);
+    }
+    if row == 0 {
+        return;
+    }
+
+    let (a, b, c) = (word(oam, row, 0), word(oam, row - 1, 0), word(oam, row - 1, 2));
+    let first = match access {
+        OamBugAccess::Write => ((a ^ c) & (b ^ c)) ^ c,
+        OamBugAccess::Read | OamBugAccess::ReadIncrease => b | (a & c),
+    };
+    set_word(oam, row, 0, first);
+    copy_row(oam, row - 1, row, 2);
+}
+
+/// Where a pixel came from: the BG/window or an object using OBP0 or OBP1
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Bg,
+    Obj0,
+    Obj1,
+}
+
+impl Layer {
+    fn obj(obp: Byte) -> Self {
+        if obp == 1 {
+            Layer::Obj1
+        } else {
+            Layer::Obj0
+        }
+    }
+
+    // a pixel as `Ppu::pixels` holds it
+    fn pixel(self, shade: Byte) -> Byte {
+        (self as Byte) << 2 | shade
+    }
+
+    /// Shade and layer of a pixel from `Ppu::pixels`.
+    pub fn split(pixel: Byte) -> (Byte, Layer) {
+        let layer = match (pixel >> 2) & 0x03 {
+            1 => Layer::Obj0,
+            2 => Layer::Obj1,
+            _ => Layer::Bg,
+        };
+        (pixel & 0x03, layer)
+    }
+}
+
+/// Separate shades for the BG/window and both object palettes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LayerColors {
+    pub bg: DmgColors,
+    pub obj0: DmgColors,
+    pub obj1: DmgColors,
+}
+
+impl From<DmgColors> for LayerColors {
+    fn from(colors: DmgColors) -> Self {
+        Self { bg: colors, obj0: colors, obj1: colors }
+    }
+}
+
+impl LayerColors {
+    pub fn layer(&self, layer: Layer) -> &DmgColors {
+        match layer {
+            Layer::Bg => &self.bg,
+            Layer::Obj0 => &self.obj0,
+            Layer::Obj1 => &self.obj1,
+        }
+    }
+
+    /// These colours with `layer` taken from `other`.
+    pub fn with_layer(mut self, layer: Layer, other: &LayerColors) -> Self {
+        let colors = *other.layer(layer);
+        match layer {
+            Layer::Bg => self.bg = colors,
+            Layer::Obj0 => self.obj0 = colors,
+            Layer::Obj1 => self.obj1 = colors,
+        }
+        self
+    }
+}
+
 struct Palette {
+    colors: LayerColors,
+    // a CGB colourising a DMG game, replaces `colors`
+    compat: Option<LayerColors>,
+
     // FF47
     bgp: Byte,
     // FF48
@@ -550,31 +1469,77 @@ struct Palette {
     obp1: Byte,
 
     // CGB Only
-    // FF68
+    // FF68: bit 7 auto-increment, bit 0-5 index into bg_ram
     bcps: Byte,
-    // FF69
-    bcpd: Byte,
-    // FF6A
+    // FF6A: same for obj_ram
     ocps: Byte,
-    // FF6B
-    ocpd: Byte,
+    // 8 palettes of 4 little endian RGB555 colours, accessed through FF69 / FF6B
+    bg_ram: [Byte; 64],
+    obj_ram: [Byte; 64],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            colors: DMG_COLORS_GREEN.into(),
+            compat: None,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            bcps: 0,
+            ocps: 0,
+            // the CGB boot ROM leaves every colour white
+            bg_ram: [0xFF; 64],
+            obj_ram: [0xFF; 64],
+        }
+    }
 }
 
 impl Palette {
+    // the compat colours if a CGB colours the game, the DMG ones otherwise
+    fn layer_colors(&self) -> &LayerColors {
+        self.compat.as_ref().unwrap_or(&self.colors)
+    }
+
+    fn get_color(&self, shade: Byte, layer: Layer) -> image::Rgba<u8> {
+        PaletteEnum::get_color(PaletteEnum::from_u8(shade), self.layer_colors().layer(layer))
+    }
+
+    fn bg_shade(&self, idx: u8) -> Byte {
+        (self.bgp >> (idx * 2)) & 0x03
+    }
+
+    fn obj_shade(&self, idx: u8, obp: u8) -> Byte {
+        let obp = if obp == 1 { self.obp1 } else { self.obp0 };
+        (obp >> (idx * 2)) & 0x03
+    }
+
     fn get_palette(&self, idx: u8) -> image::Rgba<u8> {
-        let color: Byte = (self.bgp >> (idx * 2)) & 0x03;
-        PaletteEnum::get_color(PaletteEnum::from_u8(color))
+        self.get_color(self.bg_shade(idx), Layer::Bg)
     }
 
     fn get_obj_palette(&self, idx: u8, obp: u8) -> image::Rgba<u8> {
-        let color: Byte;
-        if obp == 1 {
-            color = (self.obp1 >> (idx * 2)) & 0x03;
-        } else {
-            color = (self.obp0 >> (idx * 2)) & 0x03;
-        }
+        self.get_color(self.obj_shade(idx, obp), Layer::obj(obp))
+    }
 
-        PaletteEnum::get_color(PaletteEnum::from_u8(color))
+    fn get_cgb_bg_color(&self, palette: Byte, idx: Byte) -> image::Rgba<u8> {
+        Self::get_cgb_color(&self.bg_ram, palette, idx)
+    }
+
+    fn get_cgb_obj_color(&self, palette: Byte, idx: Byte) -> image::Rgba<u8> {
+        Self::get_cgb_color(&self.obj_ram, palette, idx)
+    }
+
+    fn get_cgb_color(ram: &[Byte; 64], palette: Byte, idx: Byte) -> image::Rgba<u8> {
+        let i = palette as usize * 8 + idx as usize * 2;
+        rgb555(ram[i] as u16 | (ram[i + 1] as u16) << 8)
+    }
+
+    // step the index after a data write when auto-increment is on
+    fn increment(spec: &mut Byte) {
+        if *spec & 0x80 != 0 {
+            *spec = 0x80 | (spec.wrapping_add(1) & 0x3F);
+        }
     }
 }
 
@@ -584,10 +1549,10 @@ impl Reader for Palette {
             ADDR_PPU_BGP => self.bgp,
             ADDR_PPU_OBP0 => self.obp0,
             ADDR_PPU_OBP1 => self.obp1,
-            ADDR_PPU_BCPS => self.bcps,
-            ADDR_PPU_BCPD => self.bcpd,
-            ADDR_PPU_OCPS => self.ocps,
-            ADDR_PPU_OCPD => self.ocpd,
+            ADDR_PPU_BCPS => self.bcps | 0x40,
+            ADDR_PPU_BCPD => self.bg_ram[(self.bcps & 0x3F) as usize],
+            ADDR_PPU_OCPS => self.ocps | 0x40,
+            ADDR_PPU_OCPD => self.obj_ram[(self.ocps & 0x3F) as usize],
             v => unreachable!("cannot read {:04X} for PPU Palette", v),
         }
     }
@@ -599,15 +1564,99 @@ impl Writer for Palette {
             ADDR_PPU_BGP => self.bgp = value,
             ADDR_PPU_OBP0 => self.obp0 = value,
             ADDR_PPU_OBP1 => self.obp1 = value,
-            ADDR_PPU_BCPS => self.bcps = value,
-            ADDR_PPU_BCPD => self.bcpd = value,
-            ADDR_PPU_OCPS => self.ocps = value,
-            ADDR_PPU_OCPD => self.ocpd = value,
+            ADDR_PPU_BCPS => self.bcps = value & 0xBF,
+            ADDR_PPU_BCPD => {
+                self.bg_ram[(self.bcps & 0x3F) as usize] = value;
+                Self::increment(&mut self.bcps);
+            }
+            ADDR_PPU_OCPS => self.ocps = value & 0xBF,
+            ADDR_PPU_OCPD => {
+                self.obj_ram[(self.ocps & 0x3F) as usize] = value;
+                Self::increment(&mut self.ocps);
+            }
             v => unreachable!("cannot write {:04X} for PPU Palette", v),
         }
     }
 }
 
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+enum FetchStep {
+    #[default]
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Default, Clone, Copy)]
+struct FifoPixel {
+    color: Byte,
+    // BG: CGB attribute palette, OBJ: OBP0/OBP1 on DMG, CGB palette
+    palette: Byte,
+    // BG: CGB tile drawn over objects, OBJ: BG colours 1-3 drawn over it
+    priority: bool,
+    // OBJ: position among the line's objects, lower wins on CGB
+    oam_index: Byte,
+}
+
+/// Pixel FIFO state for the line in mode 3
+#[derive(Default)]
+struct Fifo {
+    bg: VecDeque<FifoPixel>,
+    obj: VecDeque<FifoPixel>,
+    step: FetchStep,
+    // dots spent in `step`
+    step_dot: u8,
+    // tile the fetcher is on, from the left of the line or the window
+    tile_x: u8,
+    window: bool,
+    tile_idx: Byte,
+    attr: TileAttr,
+    low: Byte,
+    high: Byte,
+    // x of the next pixel out
+    lx: u8,
+    // pixels still dropped before the first one is shown
+    discard: u8,
+    // dots into mode 3
+    dots: u16,
+    // dots left of a fetch holding up the output
+    stall: u16,
+    // the line's objects not fetched yet, with their order in OAM
+    sprites: Vec<(Byte, Sprite)>,
+    // tiles an object already waited for
+    penalized_tiles: Vec<(bool, u16)>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct BgPixel {
+    color: Byte,
+    // CGB: the tile is drawn over objects
+    priority: bool,
+}
+
+/// BG map attributes, stored in VRAM bank 1 at the tile index' address (CGB only)
+#[derive(Default, Clone, Copy)]
+struct TileAttr {
+    palette: Byte,
+    bank: Byte,
+    x_flip: bool,
+    y_flip: bool,
+    priority: bool,
+}
+
+impl From<Byte> for TileAttr {
+    fn from(value: Byte) -> Self {
+        Self {
+            palette: value & 0x07,
+            bank: bit(&value, &3),
+            x_flip: bit(&value, &5) == 1,
+            y_flip: bit(&value, &6) == 1,
+            priority: bit(&value, &7) == 1,
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 struct Tile;
 
@@ -624,6 +1673,7 @@ impl Tile {
     }
 }
 
+#[derive(Clone, Copy)]
 struct Sprite {
     y: Byte,
     x: Byte,
@@ -641,6 +1691,11 @@ impl Sprite {
         }
     }
 
+    /// BG colours 1-3 are drawn over this object
+    pub fn behind_bg(&self) -> bool {
+        bit(&self.attr, &7) == 1
+    }
+
     pub fn y_flip(&self) -> bool {
         bit(&self.attr, &6) == 1
     }
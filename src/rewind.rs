@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use crate::types::*;
+
+/// Bounded history of save states for rewinding.
+///
+/// Only the newest state is kept as is. Every older state is stored as
+/// the XOR against the state that followed it, run-length encoded, so
+/// consecutive frames that barely differ take a few bytes each.
+/// When the memory budget is exceeded the oldest states are dropped.
+pub struct RewindBuffer {
+    latest: Option<Vec<Byte>>,
+    deltas: VecDeque<Vec<Byte>>,
+    size: usize,
+    budget: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+            budget,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<Byte>) {
+        if let Some(prev) = self.latest.take() {
+            let delta = encode_delta(&state, &prev);
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(state);
+
+        while self.size + self.latest_len() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Take the newest state out of the buffer.
+    pub fn pop(&mut self) -> Option<Vec<Byte>> {
+        let latest = self.latest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.size -= delta.len();
+            self.latest = Some(decode_delta(&latest, &delta));
+        }
+        Some(latest)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+
+    /// number of stored states
+    pub fn len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// bytes used by stored states
+    pub fn memory_usage(&self) -> usize {
+        self.size + self.latest_len()
+    }
+
+    fn latest_len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |v| v.len())
+    }
+}
+
+// Delta format, repeated until the end:
+//   zero run length(varint) | literal length(varint) | literal bytes
+// A leading 0xFF marks a raw copy, used when the sizes differ.
+const DELTA_XOR: Byte = 0x00;
+const DELTA_RAW: Byte = 0xFF;
+
+fn encode_delta(base: &[Byte], target: &[Byte]) -> Vec<Byte> {
+    let mut out = vec![];
+    if base.len() != target.len() {
+        out.push(DELTA_RAW);
+        out.extend_from_slice(target);
+        return out;
+    }
+
+    out.push(DELTA_XOR);
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && base[i] == target[i] {
+            i += 1;
+        }
+        let zeros = i - start;
+
+        let start = i;
+        while i < target.len() && base[i] != target[i] {
+            i += 1;
+        }
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, i - start);
+        out.extend(base[start..i].iter().zip(&target[start..i]).map(|(b, t)| b ^ t));
+    }
+    out
+}
+
+fn decode_delta(base: &[Byte], delta: &[Byte]) -> Vec<Byte> {
+    if delta[0] == DELTA_RAW {
+        return delta[1..].to_vec();
+    }
+
+    let mut out = base.to_vec();
+    let mut pos = 1;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let len = read_varint(delta, &mut pos);
+        for v in &delta[pos..pos + len] {
+            out[i] ^= v;
+            i += 1;
+        }
+        pos += len;
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<Byte>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as Byte & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as Byte);
+}
+
+fn read_varint(buf: &[Byte], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        value |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
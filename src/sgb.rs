@@ -0,0 +1,543 @@
+use image::RgbaImage;
+
+use crate::{constant::*, ppu::rgb555, state::*, types::*};
+use anyhow::{bail, Result};
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+// *_TRN commands copy this much from the next frame
+const TRANSFER_SIZE: usize = 0x1000;
+
+// the palette attributes are kept per 8x8 cell of the Game Boy screen
+const CELLS_X: usize = SCREEN_WIDTH as usize / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT as usize / 8;
+const ATTR_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+const ATTR_FILE_NUM: usize = 45;
+
+// top left corner of the Game Boy screen inside the border
+const SCREEN_X: usize = (SGB_SCREEN_WIDTH as usize - SCREEN_WIDTH as usize) / 2;
+const SCREEN_Y: usize = (SGB_SCREEN_HEIGHT as usize - SCREEN_HEIGHT as usize) / 2;
+
+// what the SGB BIOS sets palette 0 to
+const DEFAULT_PALETTE: [Word; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mask {
+    Off,
+    // keep showing the last frame
+    Freeze,
+    Black,
+    Color0,
+}
+
+impl Mask {
+    fn from_u8(value: u8) -> Self {
+        match value & 0x03 {
+            0 => Mask::Off,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            _ => Mask::Color0,
+        }
+    }
+}
+
+/// What the next frame is copied into, requested by a *_TRN command
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    // CHR_TRN: border tiles 0x00-0x7F, or 0x80-0xFF if true
+    Tiles(bool),
+    Border,
+}
+
+impl Transfer {
+    fn to_u8(self) -> u8 {
+        match self {
+            Transfer::Palettes => 1,
+            Transfer::Attributes => 2,
+            Transfer::Tiles(false) => 3,
+            Transfer::Tiles(true) => 4,
+            Transfer::Border => 5,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Option<Self>> {
+        Ok(match value {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Attributes),
+            3 => Some(Transfer::Tiles(false)),
+            4 => Some(Transfer::Tiles(true)),
+            5 => Some(Transfer::Border),
+            v => bail!("Invalid SGB transfer {}", v),
+        })
+    }
+}
+
+/// Super Game Boy.
+///
+/// Games talk to it with 16 byte command packets sent bit by bit through P1,
+/// and with VRAM transfers: after a *_TRN command the SGB copies 4 KiB out of
+/// the next frame, reading the tiles the game put on screen. It colours the
+/// Game Boy screen with four palettes chosen per 8x8 cell and draws it in the
+/// middle of a 256x224 border.
+pub struct Sgb {
+    // packet receiver
+    receiving: bool,
+    // P1 went back to 0x30 since the last bit
+    bit_ready: bool,
+    bits: usize,
+    packet: [Byte; PACKET_SIZE],
+    // packets of a command that spans several
+    command: Vec<Byte>,
+    // last value written to P1
+    p1: Byte,
+
+    // MLT_REQ: 1, 2 or 4 controllers, taking turns on P1
+    player_count: u8,
+    current_player: u8,
+
+    // colour 0 is shared by all four
+    palettes: [[Word; 4]; 4],
+    // palette index per screen cell
+    attr_map: [Byte; CELLS_X * CELLS_Y],
+    // PAL_TRN: 512 palettes of 4 colours for PAL_SET
+    system_palettes: Vec<Byte>,
+    // ATTR_TRN: 45 attribute maps for ATTR_SET and PAL_SET
+    attr_files: Vec<Byte>,
+    // CHR_TRN: 256 4bpp tiles
+    border_tiles: Vec<Byte>,
+    // PCT_TRN: 32x32 tile map followed by palettes 4-7
+    border: Vec<Byte>,
+    mask: Mask,
+    transfer: Option<Transfer>,
+
+    // shades of the Game Boy screen last shown
+    screen: Vec<Byte>,
+    image_data: RgbaImage,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        let mut sgb = Self {
+            receiving: false,
+            bit_ready: false,
+            bits: 0,
+            packet: [0; PACKET_SIZE],
+            command: vec![],
+            p1: 0x30,
+            player_count: 1,
+            current_player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            attr_map: [0; CELLS_X * CELLS_Y],
+            system_palettes: vec![0; TRANSFER_SIZE],
+            attr_files: vec![0; ATTR_FILE_NUM * ATTR_FILE_SIZE],
+            border_tiles: vec![0; TRANSFER_SIZE * 2],
+            border: vec![0; TRANSFER_SIZE],
+            mask: Mask::Off,
+            transfer: None,
+            screen: vec![0; SCREEN_WIDTH as usize * SCREEN_HEIGHT as usize],
+            image_data: RgbaImage::new(SGB_SCREEN_WIDTH as u32, SGB_SCREEN_HEIGHT as u32),
+        };
+        sgb.render();
+        sgb
+    }
+
+    /// The game wrote P1. Both lines low starts a packet, then each bit is
+    /// a pulse on P14 (0) or P15 (1) followed by both lines high.
+    pub fn write_p1(&mut self, value: Byte) {
+        // the next controller is connected when P15 goes high
+        if self.p1 & 0x20 == 0 && value & 0x20 != 0 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+        self.p1 = value;
+
+        match value & 0x30 {
+            0x00 => {
+                self.receiving = true;
+                self.bit_ready = false;
+                self.bits = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x30 => self.bit_ready = true,
+            v if self.receiving && self.bit_ready => {
+                self.bit_ready = false;
+                let one = v == 0x10;
+                if self.bits < PACKET_BITS {
+                    if one {
+                        self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+                    }
+                    self.bits += 1;
+                    return;
+                }
+
+                // a packet ends with a 0 bit
+                self.receiving = false;
+                if !one {
+                    self.receive_packet();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        // the first packet says how many packets the command has
+        let length = (self.command[0] & 0x07) as usize;
+        if length == 0 {
+            self.command.clear();
+        } else if self.command.len() >= length * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[Byte]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x11 => self.mlt_req(data[1]),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x13 => self.transfer = Some(Transfer::Tiles(data[1] & 0x01 != 0)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.attr_set(data[1]),
+            0x17 => self.mask = Mask::from_u8(data[1]),
+            cmd => log::debug!("unsupported SGB command {:02X}", cmd),
+        }
+    }
+
+    // PAL01, PAL23, PAL03, PAL12
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[Byte]) {
+        let color0 = word(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = word(data, 1 + i * 2);
+            self.palettes[b][i] = word(data, 7 + i * 2);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[Byte]) {
+        let count = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // with only one of inside and outside, the border goes with it
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                c if c & 0x02 != 0 => Some((set[1] >> 2) & 0x03),
+                _ => None,
+            };
+            let (x1, y1, x2, y2) = (set[2] & 0x1F, set[3] & 0x1F, set[4] & 0x1F, set[5] & 0x1F);
+
+            for (i, cell) in self.attr_map.iter_mut().enumerate() {
+                let (x, y) = ((i % CELLS_X) as Byte, (i / CELLS_X) as Byte);
+                let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                    (control & 0x01 != 0).then_some(inside)
+                } else if x < x1 || x > x2 || y < y1 || y > y2 {
+                    (control & 0x04 != 0).then_some(outside)
+                } else {
+                    border
+                };
+                if let Some(palette) = palette {
+                    *cell = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[Byte]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if n < CELLS_Y {
+                    self.attr_map[n * CELLS_X..(n + 1) * CELLS_X].fill(palette);
+                }
+            } else if n < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attr_map[y * CELLS_X + n] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[Byte]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = (data[2] & 0x1F) as usize;
+
+        for (i, cell) in self.attr_map.iter_mut().enumerate() {
+            let pos = if horizontal { i / CELLS_X } else { i % CELLS_X };
+            *cell = match pos.cmp(&line) {
+                std::cmp::Ordering::Less => before,
+                std::cmp::Ordering::Equal => on,
+                std::cmp::Ordering::Greater => after,
+            };
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[Byte]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count {
+            let Some(b) = data.get(6 + i / 4) else {
+                break;
+            };
+            if x < CELLS_X && y < CELLS_Y {
+                self.attr_map[y * CELLS_X + x] = (b >> (6 - (i % 4) * 2)) & 0x03;
+            }
+            if vertical {
+                y += 1;
+                if y >= CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x >= CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[Byte]) {
+        for i in 0..4 {
+            let n = (word(data, 1 + i * 2) & 0x1FF) as usize;
+            for c in 0..4 {
+                self.palettes[i][c] = word(&self.system_palettes, (n * 4 + c) * 2);
+            }
+        }
+        // colour 0 comes from the first palette
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.attr_set(data[9]);
+        } else if data[9] & 0x40 != 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    fn mlt_req(&mut self, value: Byte) {
+        self.player_count = match value & 0x03 {
+            1 => 2,
+            3 => 4,
+            _ => 1,
+        };
+        self.current_player = 0;
+    }
+
+    /// Index of the controller P1 reads, 0 unless MLT_REQ asked for more.
+    pub fn current_player(&self) -> usize {
+        self.current_player as usize
+    }
+
+    // ATTR_SET, and PAL_SET with bit 7: load an ATTR_TRN file, bit 6 cancels the mask
+    fn attr_set(&mut self, value: Byte) {
+        let n = (value & 0x3F) as usize;
+        if n < ATTR_FILE_NUM {
+            let file = &self.attr_files[n * ATTR_FILE_SIZE..(n + 1) * ATTR_FILE_SIZE];
+            for (i, cell) in self.attr_map.iter_mut().enumerate() {
+                *cell = (file[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    /// The PPU finished a frame, `pixels` is `Ppu::pixels` with the shade of
+    /// every pixel in bits 0-1.
+    pub fn frame(&mut self, pixels: &[Byte]) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = Self::vram_data(pixels);
+            match transfer {
+                Transfer::Palettes => self.system_palettes.copy_from_slice(&data),
+                Transfer::Attributes => {
+                    let len = self.attr_files.len();
+                    self.attr_files.copy_from_slice(&data[..len]);
+                }
+                Transfer::Tiles(high) => {
+                    let start = if high { TRANSFER_SIZE } else { 0 };
+                    self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::Border => self.border.copy_from_slice(&data),
+            }
+        }
+
+        if self.mask != Mask::Freeze {
+            for (shade, pixel) in self.screen.iter_mut().zip(pixels) {
+                *shade = pixel & 0x03;
+            }
+        }
+        self.render();
+    }
+
+    // the screen read back as 256 2bpp tiles, 20 per row
+    fn vram_data(pixels: &[Byte]) -> Vec<Byte> {
+        let width = SCREEN_WIDTH as usize;
+        let mut data = vec![0; TRANSFER_SIZE];
+        for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+            let (tile_x, tile_y) = (tile % CELLS_X, tile / CELLS_X);
+            for row in 0..8 {
+                let start = (tile_y * 8 + row) * width + tile_x * 8;
+                for (i, shade) in pixels[start..start + 8].iter().enumerate() {
+                    bytes[row * 2] |= (shade & 0x01) << (7 - i);
+                    bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - i);
+                }
+            }
+        }
+        data
+    }
+
+    fn render(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_SCREEN_HEIGHT as usize {
+            for x in 0..SGB_SCREEN_WIDTH as usize {
+                let color = match self.border_color(x, y) {
+                    0 => backdrop,
+                    c => c,
+                };
+                self.image_data.put_pixel(x as u32, y as u32, rgb555(color));
+            }
+        }
+
+        let width = SCREEN_WIDTH as usize;
+        for (i, &shade) in self.screen.iter().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let color = match self.mask {
+                Mask::Black => 0x0000,
+                Mask::Color0 => backdrop,
+                _ => {
+                    let palette = self.attr_map[(y / 8) * CELLS_X + x / 8];
+                    self.palettes[palette as usize][shade as usize]
+                }
+            };
+            self.image_data
+                .put_pixel((SCREEN_X + x) as u32, (SCREEN_Y + y) as u32, rgb555(color));
+        }
+    }
+
+    // RGB555 colour of the border pixel, 0 where it is transparent
+    fn border_color(&self, x: usize, y: usize) -> Word {
+        let entry = ((y / 8) * 32 + x / 8) * 2;
+        let tile = self.border[entry] as usize;
+        let attr = self.border[entry + 1];
+        let tile_x = if attr & 0x40 != 0 { 7 - x % 8 } else { x % 8 };
+        let tile_y = if attr & 0x80 != 0 { 7 - y % 8 } else { y % 8 };
+
+        // SNES 4bpp: planes 0/1 interleaved per row, then planes 2/3
+        let bytes = &self.border_tiles[tile * 32..(tile + 1) * 32];
+        let color = [bytes[tile_y * 2], bytes[tile_y * 2 + 1], bytes[16 + tile_y * 2], bytes[17 + tile_y * 2]]
+            .iter()
+            .enumerate()
+            .fold(0, |c, (plane, b)| c | ((b >> (7 - tile_x)) & 0x01) << plane) as usize;
+        if color == 0 {
+            return 0;
+        }
+        // palettes 4-7 follow the tile map
+        let palette = ((attr >> 2) & 0x03) as usize;
+        word(&self.border, 0x800 + (palette * 16 + color) * 2)
+    }
+
+    pub fn display(&self) -> RgbaImage {
+        self.image_data.clone()
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn word(data: &[Byte], i: usize) -> Word {
+    data[i] as Word | (data[i + 1] as Word) << 8
+}
+
+impl Savable for Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.receiving);
+        w.write_bool(self.bit_ready);
+        w.write_u8(self.bits as u8);
+        w.write_bytes(&self.packet);
+        w.write_bytes(&self.command);
+        w.write_u8(self.p1);
+        w.write_u8(self.player_count);
+        w.write_u8(self.current_player);
+        for palette in &self.palettes {
+            for color in palette {
+                w.write_u16(*color);
+            }
+        }
+        w.write_bytes(&self.attr_map);
+        w.write_bytes(&self.system_palettes);
+        w.write_bytes(&self.attr_files);
+        w.write_bytes(&self.border_tiles);
+        w.write_bytes(&self.border);
+        w.write_u8(self.mask as u8);
+        w.write_u8(self.transfer.map_or(0, Transfer::to_u8));
+        w.write_bytes(&self.screen);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.receiving = r.read_bool()?;
+        self.bit_ready = r.read_bool()?;
+        self.bits = r.read_u8()? as usize;
+        if self.bits > PACKET_BITS {
+            bail!("Invalid SGB packet position {}", self.bits);
+        }
+        r.read_bytes_into(&mut self.packet)?;
+        let command = r.read_bytes()?;
+        if command.len() >= 7 * PACKET_SIZE || command.len() % PACKET_SIZE != 0 {
+            bail!("Invalid SGB command length {}", command.len());
+        }
+        self.command = command.to_vec();
+        self.p1 = r.read_u8()?;
+        self.player_count = r.read_u8()?;
+        self.current_player = r.read_u8()?;
+        if ![1, 2, 4].contains(&self.player_count) || self.current_player >= self.player_count {
+            bail!("Invalid SGB players {}/{}", self.current_player, self.player_count);
+        }
+        for palette in self.palettes.iter_mut() {
+            for color in palette.iter_mut() {
+                *color = r.read_u16()?;
+            }
+        }
+        r.read_bytes_into(&mut self.attr_map)?;
+        r.read_bytes_into(&mut self.system_palettes)?;
+        r.read_bytes_into(&mut self.attr_files)?;
+        r.read_bytes_into(&mut self.border_tiles)?;
+        r.read_bytes_into(&mut self.border)?;
+        self.mask = Mask::from_u8(r.read_u8()?);
+        self.transfer = Transfer::from_u8(r.read_u8()?)?;
+        r.read_bytes_into(&mut self.screen)?;
+        if self.attr_map.iter().chain(&self.screen).any(|v| *v > 3) {
+            bail!("Invalid SGB palette index");
+        }
+        self.render();
+        Ok(())
+    }
+}
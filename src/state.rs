@@ -0,0 +1,188 @@
+use crate::types::*;
+use anyhow::{bail, Result};
+
+// "RBST" : RustBoy STate
+pub const STATE_MAGIC: [Byte; 4] = *b"RBST";
+pub const STATE_VERSION: u16 = 1;
+// magic(4) + version(2) + rom checksum(4)
+pub const STATE_HEADER_SIZE: usize = 10;
+
+pub trait Savable {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<Byte>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Write into `buf` after clearing it, keeping its allocation.
+    pub fn reuse(mut buf: Vec<Byte>) -> Self {
+        buf.clear();
+        Self { buf }
+    }
+
+    pub fn into_inner(self) -> Vec<Byte> {
+        self.buf
+    }
+
+    pub fn write_u8(&mut self, value: Byte) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as Byte);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_raw(&mut self, value: &[Byte]) {
+        self.buf.extend_from_slice(value);
+    }
+
+    /// length prefixed bytes
+    pub fn write_bytes(&mut self, value: &[Byte]) {
+        self.write_u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+    }
+
+    /// Write a tagged section.
+    /// tag(4 bytes) + length(u32) + payload
+    pub fn section<S: Savable + ?Sized>(&mut self, tag: &[Byte; 4], s: &S) {
+        let mut w = StateWriter::new();
+        s.save_state(&mut w);
+
+        self.write_raw(tag);
+        self.write_bytes(&w.buf);
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [Byte],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [Byte]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [Byte]> {
+        if self.buf.len() - self.pos < len {
+            bail!(
+                "Unexpected end of state: need {} bytes at {}, but only {} left",
+                len,
+                self.pos,
+                self.buf.len() - self.pos
+            );
+        }
+        let v = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(v)
+    }
+
+    pub fn read_u8(&mut self) -> Result<Byte> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [Byte]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Read length prefixed bytes into `dst`, which must have the same length.
+    pub fn read_bytes_into(&mut self, dst: &mut [Byte]) -> Result<()> {
+        let v = self.read_bytes()?;
+        if v.len() != dst.len() {
+            bail!("State size mismatch: expected {} bytes, got {}", dst.len(), v.len());
+        }
+        dst.copy_from_slice(v);
+        Ok(())
+    }
+
+    pub fn read_magic(&mut self, magic: &[Byte; 4]) -> Result<()> {
+        let v = self.take(4)?;
+        if v != magic {
+            bail!(
+                "Invalid magic: expected {:?}, got {:?}",
+                String::from_utf8_lossy(magic),
+                String::from_utf8_lossy(v)
+            );
+        }
+        Ok(())
+    }
+
+    /// Read a tagged section written by `StateWriter::section`.
+    pub fn section<S: Savable + ?Sized>(&mut self, tag: &[Byte; 4], s: &mut S) -> Result<()> {
+        self.read_magic(tag)?;
+        let mut r = StateReader::new(self.read_bytes()?);
+        s.load_state(&mut r)?;
+        if !r.is_empty() {
+            bail!("Section {:?} has trailing data", String::from_utf8_lossy(tag));
+        }
+        Ok(())
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 (IEEE 802.3) used to identify the ROM a state belongs to.
+pub fn crc32(buf: &[Byte]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in buf {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize];
+    }
+    !crc
+}
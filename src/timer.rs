@@ -10,7 +10,9 @@ use crate::{
     traits::*,
     types::*,
     interrupt::Interrupt,
+    state::*,
 };
+use anyhow::Result;
 
 #[derive(Default)]
 pub struct Timer {
@@ -42,6 +44,17 @@ impl Timer {
         }
     }
 
+    /// Set the internal divider, DIV being its upper byte.
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+        self.div = (counter >> 8) as Byte;
+    }
+
+    /// The internal divider, DIV being its upper byte.
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
     pub fn tick(&mut self, cycle: u16) {
         for _ in 0..cycle {
             log::trace!("{}", self);
@@ -121,4 +134,25 @@ impl Writer for Timer {
             v => unreachable!("Non Supported addr {:04X}", v),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savable for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.div);
+        w.write_u8(self.tima);
+        w.write_bool(self.tima_overflowed);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.counter = r.read_u16()?;
+        self.div = r.read_u8()?;
+        self.tima = r.read_u8()?;
+        self.tima_overflowed = r.read_bool()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        Ok(())
+    }
+}
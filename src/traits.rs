@@ -1,4 +1,4 @@
-use crate::types::*;
+use crate::{ppu::OamBugAccess, state::Savable, types::*};
 
 macro_rules! trait_alias {
     (pub trait $name:ident = $($traits:tt)+) => {
@@ -17,4 +17,34 @@ pub trait Writer {
     fn write(&mut self, addr: Word, value: Byte);
 }
 
-trait_alias!(pub trait BusTrait = Reader + Writer);
+pub trait SpeedSwitch {
+    /// The CPU and timer run at twice the normal rate (CGB).
+    fn double_speed(&self) -> bool;
+    /// Executed STOP: toggle the speed if KEY1 asked for it. Returns whether it did.
+    fn switch_speed(&mut self) -> bool;
+}
+
+pub trait SerialLink {
+    /// A link partner clocked a byte into the serial port, finishing a transfer
+    /// that waits for an external clock. Returns the byte shifted out, if any.
+    fn serial_exchange(&mut self, value: Byte) -> Option<Byte>;
+}
+
+pub trait Clock {
+    /// An M-cycle passed: copy the next byte of a running OAM DMA and run
+    /// the PPU, timer and APU through it.
+    fn tick(&mut self);
+}
+
+pub trait OamBug {
+    /// The CPU put `addr` on the bus this M-cycle, corrupting OAM if it
+    /// points there during the OAM scan.
+    fn trigger_oam_bug(&mut self, addr: Word, access: OamBugAccess);
+}
+
+pub trait VramBank {
+    /// VRAM bank selected by VBK (CGB), which VRAM DMA copies into.
+    fn vram_bank(&self) -> Byte;
+}
+
+trait_alias!(pub trait BusTrait = Reader + Writer + Savable + SpeedSwitch + SerialLink + Clock + OamBug + VramBank);
@@ -0,0 +1,183 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rstest::*;
+use rust_boy::{
+    gameboy::GameBoy,
+    headless::{run_test, TestOutcome},
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+const SAMPLE_RATE: u32 = 44100;
+const DOTS_PER_FRAME: f64 = 70224.0;
+const CLOCK_RATE: f64 = 4194304.0;
+
+fn sound_test(folder: &str, file: &str, model: Model) {
+    let mut gb = boot(&load_rom(folder, file), model);
+    assert_eq!(run_test(&mut gb, 3000).unwrap(), TestOutcome::Passed, "{} on {}", file, model);
+}
+
+// channel 2 playing a square wave of about 1 kHz at full volume
+fn play_square(gb: &GameBoy, nr51: u8) {
+    write(gb, 0xFF24, 0x77);
+    write(gb, 0xFF25, nr51);
+    write(gb, 0xFF16, 0x80);
+    write(gb, 0xFF17, 0xF0);
+    write(gb, 0xFF18, 0x7D);
+    write(gb, 0xFF19, 0x87);
+}
+
+fn channel_on(gb: &GameBoy, channel: u8) -> bool {
+    read(gb, 0xFF26) & (1 << channel) != 0
+}
+
+speculate! {
+    describe "apu" {
+        #[rstest(file,
+            case("01-registers"),
+            case("02-len ctr"),
+            case("03-trigger"),
+            case("04-sweep"),
+            case("05-sweep details"),
+            case("06-overflow on trigger"),
+            case("07-len sweep period sync"),
+            case("08-len ctr during power"),
+            case("09-wave read while on"),
+            case("10-wave trigger while on"),
+            case("11-regs after power"),
+            case("12-wave write while on"),
+        )]
+        fn passes_dmg_sound(file: &str) {
+            sound_test("blargg/dmg_sound/rom_singles", file, Model::Dmg);
+        }
+
+        #[rstest(file,
+            case("01-registers"),
+            case("02-len ctr"),
+            case("03-trigger"),
+            case("04-sweep"),
+            case("05-sweep details"),
+            case("06-overflow on trigger"),
+            case("07-len sweep period sync"),
+            case("08-len ctr during power"),
+            case("09-wave read while on"),
+            case("10-wave trigger while on"),
+            case("11-regs after power"),
+            case("12-wave"),
+        )]
+        fn passes_cgb_sound(file: &str) {
+            sound_test("blargg/cgb_sound/rom_singles", file, Model::Cgb);
+        }
+
+        it "leaves channel 1 on after the boot chime" {
+            assert_eq!(read(&boot(&idle_rom(&[]), Model::Dmg), 0xFF26), 0xF1);
+            assert_eq!(read(&boot(&idle_rom(&[]), Model::Sgb), 0xFF26), 0xF0);
+        }
+
+        it "runs the length counters off DIV" {
+            // 64 steps of the 256 Hz length clock take a quarter of a second
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            play_square(&gb, 0xFF);
+            write(&gb, 0xFF16, 0x80);
+            write(&gb, 0xFF19, 0xC7);
+            for _ in 0..14 {
+                gb.exec_frame();
+            }
+            assert!(channel_on(&gb, 1));
+            for _ in 0..2 {
+                gb.exec_frame();
+            }
+            assert!(!channel_on(&gb, 1));
+
+            // DIV bit 4 never falls while DIV keeps being reset
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            play_square(&gb, 0xFF);
+            write(&gb, 0xFF16, 0x80);
+            write(&gb, 0xFF19, 0xC7);
+            let mut frames = 0;
+            while frames < 30 {
+                write(&gb, 0xFF04, 0x00);
+                frames += gb.step() as u32;
+            }
+            assert!(channel_on(&gb, 1));
+        }
+
+        it "clears the registers on power off and ignores writes till power on" {
+            let gb = boot(&idle_rom(&[]), Model::Dmg);
+            for (i, addr) in (0xFF30..=0xFF3F).enumerate() {
+                write(&gb, addr, i as u8 * 0x11);
+            }
+            write(&gb, 0xFF26, 0x00);
+            assert_eq!(read(&gb, 0xFF26), 0x70);
+            assert_eq!(read(&gb, 0xFF24), 0x00);
+            assert_eq!(read(&gb, 0xFF25), 0x00);
+            assert_eq!(read(&gb, 0xFF11), 0x3F);
+            write(&gb, 0xFF24, 0x77);
+            assert_eq!(read(&gb, 0xFF24), 0x00);
+
+            // wave RAM is kept and stays writable
+            write(&gb, 0xFF3F, 0x5A);
+            let wave: Vec<u8> = (0xFF30..=0xFF3F).map(|addr| read(&gb, addr)).collect();
+            assert_eq!(wave[..15], (0..15).map(|i| i * 0x11).collect::<Vec<u8>>()[..]);
+            assert_eq!(wave[15], 0x5A);
+
+            write(&gb, 0xFF26, 0x80);
+            write(&gb, 0xFF24, 0x77);
+            assert_eq!(read(&gb, 0xFF24), 0x77);
+            assert_eq!(read(&gb, 0xFF26), 0xF0);
+        }
+
+        it "plays samples at the sample rate" {
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            gb.set_sample_rate(Some(SAMPLE_RATE));
+            play_square(&gb, 0x22);
+            for _ in 0..60 {
+                gb.exec_frame();
+            }
+            let samples = gb.take_samples();
+            let expected = 60.0 * DOTS_PER_FRAME / CLOCK_RATE * SAMPLE_RATE as f64;
+            assert!((samples.len() as f64 / 2.0 - expected).abs() <= 2.0, "{} samples", samples.len());
+            assert!(gb.take_samples().is_empty());
+
+            let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+            let right: Vec<i16> = samples.iter().skip(1).step_by(2).copied().collect();
+            assert_eq!(left, right);
+            assert!(left.iter().any(|&s| s > 8000) && left.iter().any(|&s| s < -8000));
+            // 131072 / (2048 - 0x77D) Hz
+            let periods = left.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count();
+            assert!((995..=1005).contains(&periods), "{} periods", periods);
+        }
+
+        it "only plays a channel on the sides NR51 routes it to" {
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            gb.set_sample_rate(Some(SAMPLE_RATE));
+            play_square(&gb, 0x20);
+            for _ in 0..10 {
+                gb.exec_frame();
+            }
+            let samples = gb.take_samples();
+            assert!(samples.iter().step_by(2).any(|&s| s.abs() > 8000));
+            assert!(samples.iter().skip(1).step_by(2).all(|&s| s.abs() < 100));
+        }
+
+        it "keeps the channels in save states" {
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            play_square(&gb, 0x22);
+            write(&gb, 0xFF19, 0xC7);
+            gb.exec_frame();
+            let state = gb.save_state();
+            gb.exec_frame();
+
+            let mut other = boot(&idle_rom(&[]), Model::Dmg);
+            other.load_state(&state).unwrap();
+            assert!(channel_on(&other, 1));
+            other.exec_frame();
+            assert_eq!(other.save_state(), gb.save_state());
+        }
+    }
+}
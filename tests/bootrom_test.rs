@@ -0,0 +1,71 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::gameboy::{GameBoy, GameBoyConfig};
+use common::fixture::*;
+use speculate::speculate;
+
+// stores 0x42 to 0xC000, then unmaps itself right before 0x0100
+fn boot_rom() -> Vec<u8> {
+    let mut rom = vec![0x00; 0x100];
+    rom[..11].copy_from_slice(&[
+        0x31, 0xFE, 0xFF, // LD SP,0xFFFE
+        0x3E, 0x42, // LD A,0x42
+        0xEA, 0x00, 0xC0, // LD (0xC000),A
+        0xC3, 0xFC, 0x00, // JP 0x00FC
+    ]);
+    rom[0xFC..].copy_from_slice(&[
+        0x3E, 0x01, // LD A,1
+        0xE0, 0x50, // LDH (0x50),A
+    ]);
+    rom
+}
+
+speculate! {
+    describe "boot rom" {
+        it "runs from 0x0000 and hands over to the cartridge" {
+            let rom = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let config = GameBoyConfig { boot_rom: Some(boot_rom()), ..Default::default() };
+            let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+
+            assert_eq!(gb.cpu.reg.PC, 0x0000);
+            assert_eq!(gb.cpu.reg.A, 0x00);
+            assert_eq!(read(&gb, 0x0000), 0x31);
+            // the cartridge header is never covered
+            assert_eq!(read(&gb, 0x0104), rom[0x0104]);
+
+            for _ in 0..10 {
+                if gb.cpu.reg.PC == 0x0100 {
+                    break;
+                }
+                gb.step();
+            }
+            assert_eq!(gb.cpu.reg.PC, 0x0100);
+            assert_eq!(read(&gb, 0x0000), rom[0x0000]);
+            assert_eq!(read(&gb, 0xC000), 0x42);
+        }
+
+        it "rejects a boot ROM of the wrong size" {
+            let rom = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let config = GameBoyConfig { boot_rom: Some(vec![0; 0x200]), ..Default::default() };
+            assert!(GameBoy::with_config(&rom, &config).is_err());
+        }
+
+        it "keeps the boot ROM mapping in save states" {
+            let rom = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let config = GameBoyConfig { boot_rom: Some(boot_rom()), ..Default::default() };
+            let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+            gb.step();
+            let state = gb.save_state();
+
+            assert!(GameBoy::new(&rom).load_state(&state).is_err());
+
+            let mut other = GameBoy::with_config(&rom, &config).unwrap();
+            other.load_state(&state).unwrap();
+            assert_eq!(read(&other, 0x0000), 0x31);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::GameBoy,
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// LD A,1 / LDH (KEY1),A / STOP / NOP, then spin
+fn speed_switch_rom() -> Vec<u8> {
+    let mut rom = idle_rom(&[CGB_FLAG]);
+    let code = [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE];
+    rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+    rom
+}
+
+fn pixel(gb: &GameBoy, x: u32, y: u32) -> [u8; 3] {
+    let p = gb.display().get_pixel(x, y).0;
+    [p[0], p[1], p[2]]
+}
+
+speculate! {
+    describe "cgb" {
+        it "is enabled when the header asks for it on a CGB" {
+            assert!(boot(&idle_rom(&[CGB_FLAG]), Model::Cgb).cgb_mode());
+            assert!(boot(&idle_rom(&[(0x0143, 0xC0)]), Model::Agb).cgb_mode());
+            assert!(!boot(&idle_rom(&[]), Model::Cgb).cgb_mode());
+
+            let gb = boot(&idle_rom(&[CGB_FLAG]), Model::Dmg);
+            assert!(!gb.cgb_mode());
+            write(&gb, 0xFF4F, 0x01);
+            assert_eq!(read(&gb, 0xFF4F), 0xFF);
+            assert_eq!(read(&gb, 0xFF70), 0xFF);
+        }
+
+        it "banks VRAM and WRAM" {
+            let gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            write(&gb, 0xC000, 0x11);
+            write(&gb, 0xFF4F, 0x01);
+            write(&gb, 0x8000, 0xAA);
+            assert_eq!(read(&gb, 0xFF4F), 0xFF);
+            write(&gb, 0xFF4F, 0x00);
+            assert_eq!(read(&gb, 0x8000), 0x00);
+            assert_eq!(read(&gb, 0xFF4F), 0xFE);
+
+            write(&gb, 0xFF70, 0x07);
+            write(&gb, 0xD000, 0x77);
+            // bank 0 maps bank 1
+            write(&gb, 0xFF70, 0x00);
+            write(&gb, 0xD000, 0x01);
+            write(&gb, 0xFF70, 0x01);
+            assert_eq!(read(&gb, 0xD000), 0x01);
+            write(&gb, 0xFF70, 0x07);
+            assert_eq!(read(&gb, 0xD000), 0x77);
+            assert_eq!(read(&gb, 0xFF70), 0xFF);
+            assert_eq!(read(&gb, 0xC000), 0x11);
+
+            let state = gb.save_state();
+            let mut other = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            other.load_state(&state).unwrap();
+            write(&other, 0xFF4F, 0x01);
+            assert_eq!(read(&other, 0x8000), 0xAA);
+            assert_eq!(read(&other, 0xD000), 0x77);
+            assert!(boot(&idle_rom(&[CGB_FLAG]), Model::Dmg).load_state(&state).is_err());
+        }
+
+        it "auto-increments the palette index" {
+            let gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            write(&gb, 0xFF68, 0x80 | 0x3E);
+            for v in [0x12, 0x34, 0x56] {
+                write(&gb, 0xFF69, v);
+            }
+            assert_eq!(read(&gb, 0xFF68), 0xC1);
+            write(&gb, 0xFF68, 0x3E);
+            assert_eq!(read(&gb, 0xFF69), 0x12);
+            write(&gb, 0xFF69, 0x9A);
+            assert_eq!(read(&gb, 0xFF68), 0x7E);
+            write(&gb, 0xFF68, 0x00);
+            assert_eq!(read(&gb, 0xFF69), 0x56);
+
+            write(&gb, 0xFF6A, 0x85);
+            write(&gb, 0xFF6B, 0x42);
+            write(&gb, 0xFF6A, 0x05);
+            assert_eq!(read(&gb, 0xFF6B), 0x42);
+        }
+
+        it "switches to double speed on STOP" {
+            let mut gb = boot(&speed_switch_rom(), Model::Cgb);
+            assert_eq!(read(&gb, 0xFF4D), 0x7E);
+            gb.step();
+            gb.step();
+            assert_eq!(read(&gb, 0xFF4D), 0x7F);
+
+            // STOP, then the CPU sits still while the clock settles
+            gb.step();
+            assert_eq!(read(&gb, 0xFF4D), 0xFE);
+            assert_eq!(read(&gb, 0xFF04), 0x00);
+            let pc = gb.cpu.reg.PC;
+            let mut stalled = 0;
+            while gb.cpu.reg.PC == pc {
+                gb.step();
+                stalled += 1;
+            }
+            assert_eq!(stalled, 2050 + 1);
+            gb.exec_frame();
+
+            // the timer counts twice as fast against the PPU's frames
+            let timer_per_frame = |gb: &mut GameBoy| {
+                write(gb, 0xFF07, 0x04);
+                write(gb, 0xFF05, 0x00);
+                gb.exec_frame();
+                read(gb, 0xFF05)
+            };
+            let fast = timer_per_frame(&mut gb);
+            let mut normal = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            let slow = timer_per_frame(&mut normal);
+            assert!((68..=69).contains(&slow), "{}", slow);
+            assert!((136..=138).contains(&fast), "{}", fast);
+
+            let state = gb.save_state();
+            let mut other = boot(&speed_switch_rom(), Model::Cgb);
+            other.load_state(&state).unwrap();
+            assert_eq!(read(&other, 0xFF4D), 0xFE);
+        }
+
+        it "ignores KEY1 in DMG mode" {
+            let mut gb = boot(&speed_switch_rom(), Model::Dmg);
+            for _ in 0..3 {
+                gb.step();
+            }
+            assert_eq!(read(&gb, 0xFF4D), 0xFF);
+            let pc = gb.cpu.reg.PC;
+            gb.step();
+            assert_ne!(gb.cpu.reg.PC, pc);
+        }
+
+        it "draws with BG attributes, colour palettes and CGB priority" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            write(&gb, 0xFF40, 0x00);
+
+            // bank 1 tile 0: colour 1, bank 0 tile 1: colour 3
+            write(&gb, 0xFF4F, 0x01);
+            for row in 0..8 {
+                write(&gb, 0x8000 + row * 2, 0xFF);
+            }
+            // map (0,0) and (2,0) use bank 1 with palette 2, (0,0) over objects
+            write(&gb, 0x9800, 0x80 | 0x08 | 0x02);
+            write(&gb, 0x9802, 0x08 | 0x02);
+            write(&gb, 0xFF4F, 0x00);
+            for addr in 0x8010..0x8020 {
+                write(&gb, addr, 0xFF);
+            }
+
+            // BG palette 2 colour 1 red, OBJ palette 1 colour 3 blue
+            write(&gb, 0xFF68, 0x80 | (2 * 8 + 2));
+            write(&gb, 0xFF69, 0x1F);
+            write(&gb, 0xFF69, 0x00);
+            write(&gb, 0xFF6A, 0x80 | (8 + 6));
+            write(&gb, 0xFF6B, 0x00);
+            write(&gb, 0xFF6B, 0x7C);
+
+            // objects over tiles 0, 1 and 2
+            for (i, x) in [8, 16, 24].into_iter().enumerate() {
+                let oam = 0xFE00 + i as u16 * 4;
+                write(&gb, oam, 16);
+                write(&gb, oam + 1, x);
+                write(&gb, oam + 2, 1);
+                write(&gb, oam + 3, 0x01);
+            }
+
+            write(&gb, 0xFF40, 0x93);
+            gb.exec_frame();
+            gb.exec_frame();
+
+            const RED: [u8; 3] = [255, 0, 0];
+            const BLUE: [u8; 3] = [0, 0, 255];
+            const WHITE: [u8; 3] = [255, 255, 255];
+            // tile priority keeps the BG on top
+            assert_eq!(pixel(&gb, 0, 0), RED);
+            // BG colour 0 never covers objects
+            assert_eq!(pixel(&gb, 8, 0), BLUE);
+            // neither does BG colour 1 without the priority attribute
+            assert_eq!(pixel(&gb, 16, 0), BLUE);
+            assert_eq!(pixel(&gb, 40, 0), WHITE);
+
+            // without the LCDC master priority objects always win
+            write(&gb, 0xFF40, 0x92);
+            gb.exec_frame();
+            assert_eq!(pixel(&gb, 0, 0), BLUE);
+        }
+    }
+}
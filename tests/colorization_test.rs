@@ -0,0 +1,113 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    cartridge::Cartridge,
+    colorization::{self, CompatPalette},
+    gameboy::GameBoyConfig,
+    joypad::{BUTTON_A, BUTTON_B, BUTTON_LEFT},
+    model::Model,
+    ppu::{LayerColors, DMG_COLORS_GREEN},
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// a DMG-only idle cartridge with a title and old licensee code
+fn titled_rom(title: &[u8], old_licensee: u8) -> Vec<u8> {
+    let mut rom = idle_rom(&[(0x014B, old_licensee)]);
+    rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+    rom
+}
+
+fn lookup(title: &[u8]) -> LayerColors {
+    colorization::lookup(&Cartridge::new(&titled_rom(title, 0x01)).unwrap())
+}
+
+// the blank screen in BGP shade 1
+fn first_pixel(rom: &[u8], model: Model, compat_palette: Option<CompatPalette>) -> [u8; 3] {
+    let config = GameBoyConfig { model, compat_palette, ..Default::default() };
+    let mut gb = boot_with(rom, &config);
+    write(&gb, 0xFF47, 0x01);
+    gb.exec_frame();
+    gb.exec_frame();
+    let p = gb.display().get_pixel(0, 0).0;
+    [p[0], p[1], p[2]]
+}
+
+speculate! {
+    describe "colorization" {
+        it "uses the default palette for other publishers" {
+            let rom = titled_rom(b"POKEMON RED", 0x00);
+            assert_eq!(first_pixel(&rom, Model::Cgb, None), [0x7B, 0xFF, 0x31]);
+        }
+
+        it "looks up Nintendo titles" {
+            let rom = titled_rom(b"POKEMON RED", 0x01);
+            assert_eq!(first_pixel(&rom, Model::Cgb, None), [0xFF, 0x84, 0x84]);
+            let rom = titled_rom(b"POKEMON BLUE", 0x01);
+            assert_eq!(first_pixel(&rom, Model::Agb, None), [0x63, 0xA5, 0xFF]);
+        }
+
+        it "has the boot ROM's palettes for well known titles" {
+            assert_eq!(lookup(b"TETRIS").bg[1], [0xFF, 0xFF, 0x00]);
+            let zelda = lookup(b"ZELDA");
+            assert_eq!((zelda.bg[1], zelda.obj0[1]), ([0xFF, 0x84, 0x84], [0x00, 0xFF, 0x00]));
+            let dr_mario = lookup(b"DR.MARIO");
+            assert_eq!((dr_mario.bg[1], dr_mario.obj1[1]), ([0x63, 0xA5, 0xFF], [0xFF, 0x84, 0x84]));
+            let green = lookup(b"POKEMON GREEN");
+            assert_eq!((green.bg[1], green.obj0[1]), ([0x7B, 0xFF, 0x31], [0xFF, 0x84, 0x84]));
+        }
+
+        it "tells titles with the same checksum apart by their 4th letter" {
+            // all of them sum up to 0x46
+            let mario = lookup(b"SUPER MARIOLAND");
+            assert_eq!(mario.bg[0..2], [[0xB5, 0xB5, 0xFF], [0xFF, 0xFF, 0x94]]);
+            // these objects start one colour before a palette
+            assert_eq!(mario.obj0, [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xFF, 0x84, 0x84], [0x94, 0x39, 0x39]]);
+            let metroid = lookup(b"METROID2");
+            assert_eq!((metroid.bg[1], metroid.obj0[0]), ([0x63, 0xA5, 0xFF], [0xFF, 0xFF, 0x00]));
+            // a letter the boot ROM doesn't know gets the default
+            assert_eq!(lookup(b"SUPAR MARIOLANH"), CompatPalette::RightA.colors());
+        }
+
+        it "picks the palette from a button combo held during the first frame" {
+            let rom = titled_rom(b"POKEMON RED", 0x01);
+            let config = GameBoyConfig { model: Model::Cgb, ..Default::default() };
+            let mut gb = boot_with(&rom, &config);
+            gb.joypad.lock().unwrap().press(BUTTON_LEFT | BUTTON_B);
+            write(&gb, 0xFF47, 0x01);
+            gb.exec_frame();
+            gb.exec_frame();
+            assert_eq!(gb.display().get_pixel(0, 0).0, [0xA5, 0xA5, 0xA5, 0xFF]);
+
+            // too late once the game runs
+            gb.joypad.lock().unwrap().press(BUTTON_A);
+            gb.exec_frame();
+            assert_eq!(gb.display().get_pixel(0, 0).0, [0xA5, 0xA5, 0xA5, 0xFF]);
+        }
+
+        it "reads button combos like the boot ROM" {
+            assert_eq!(CompatPalette::from_buttons(BUTTON_LEFT), Some(CompatPalette::Left));
+            assert_eq!(CompatPalette::from_buttons(BUTTON_LEFT | BUTTON_A | BUTTON_B), Some(CompatPalette::LeftA));
+            assert_eq!(CompatPalette::from_buttons(BUTTON_A), None);
+        }
+
+        it "can be overridden" {
+            let rom = titled_rom(b"POKEMON RED", 0x01);
+            assert_eq!(first_pixel(&rom, Model::Cgb, Some(CompatPalette::RightB)), [0x00, 0x84, 0x84]);
+        }
+
+        it "leaves DMG and CGB games alone" {
+            let rom = titled_rom(b"POKEMON RED", 0x01);
+            assert_eq!(first_pixel(&rom, Model::Dmg, Some(CompatPalette::RightB)), DMG_COLORS_GREEN[1]);
+
+            let mut rom = titled_rom(b"POKEMON RED", 0x01);
+            rom[0x0143] = 0x80;
+            let gb = boot(&rom, Model::Cgb);
+            assert!(gb.cgb_mode());
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use super::mock::*;
 use rust_boy::cpu::*;
-use rust_boy::timer::Timer;
+use rust_boy::gameboy::{GameBoy, GameBoyConfig};
+use rust_boy::model::Model;
 use rust_boy::interrupt::Interrupt;
-use rust_boy::traits::*;
 use std::{
+    env,
     sync::{
         Arc,
         Mutex,
@@ -12,10 +13,46 @@ use std::{
 
 
 pub fn setup_cpu() -> Cpu {
-    let bus = Arc::new(Mutex::new(MockBus::new()));
+    let bus = Arc::new(Mutex::new(MockBus::new_shared()));
     let interrupt = Arc::new(Mutex::new(Interrupt::new()));
-    let timer = Arc::new(Mutex::new(Timer::new(Arc::clone(&interrupt))));
 
-    let cpu = Cpu::new(Arc::clone(&bus), Arc::clone(&interrupt));
-    cpu
+    Cpu::new(Arc::clone(&bus), Arc::clone(&interrupt))
+}
+
+/// Reads `tests/roms/<folder>/<file>.gb`.
+pub fn load_rom(folder: &str, file: &str) -> Vec<u8> {
+    let pwd = env::current_dir().unwrap().into_os_string().into_string().unwrap();
+    let path = pwd + "/tests/roms/" + folder + "/" + file + ".gb";
+    std::fs::read(path).unwrap()
+}
+
+/// Header byte declaring CGB support.
+pub const CGB_FLAG: (usize, u8) = (0x0143, 0x80);
+
+/// A cartridge spinning on JR -2 at 0x0100 that leaves the machine to the
+/// test, with `header` bytes patched in, e.g. `(0x0143, 0x80)` for CGB support.
+pub fn idle_rom(header: &[(usize, u8)]) -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    rom[0x0100] = 0x18;
+    rom[0x0101] = 0xFE;
+    for &(addr, value) in header {
+        rom[addr] = value;
+    }
+    rom
+}
+
+pub fn boot(rom: &[u8], model: Model) -> GameBoy {
+    boot_with(rom, &GameBoyConfig { model, ..Default::default() })
+}
+
+pub fn boot_with(rom: &[u8], config: &GameBoyConfig) -> GameBoy {
+    GameBoy::with_config(rom, config).unwrap()
+}
+
+pub fn read(gb: &GameBoy, addr: u16) -> u8 {
+    gb.cpu.bus.lock().unwrap().read(addr)
+}
+
+pub fn write(gb: &GameBoy, addr: u16, value: u8) {
+    gb.cpu.bus.lock().unwrap().write(addr, value)
 }
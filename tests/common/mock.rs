@@ -1,14 +1,14 @@
+use rust_boy::ppu::OamBugAccess;
+use rust_boy::state::*;
 use rust_boy::traits::*;
 use rust_boy::types::*;
-use rust_boy::interrupt::Interrupt;
-use rust_boy::timer::Timer;
 
 pub struct MockBus {
     buf: [Byte; 0xFFFF],
 }
 
 impl MockBus {
-    pub fn new() -> Box<dyn BusTrait + Send> {
+    pub fn new_shared() -> Box<dyn BusTrait + Send> {
         Box::new(Self { buf: [0; 0xFFFF] })
     }
 }
@@ -22,4 +22,44 @@ impl Writer for MockBus {
     fn write(&mut self, addr: Word, value: Byte) {
         self.buf[addr as usize] = value
     }
+}
+
+impl SpeedSwitch for MockBus {
+    fn double_speed(&self) -> bool {
+        false
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+}
+
+impl SerialLink for MockBus {
+    fn serial_exchange(&mut self, _value: Byte) -> Option<Byte> {
+        None
+    }
+}
+
+impl Clock for MockBus {
+    fn tick(&mut self) {}
+}
+
+impl OamBug for MockBus {
+    fn trigger_oam_bug(&mut self, _addr: Word, _access: OamBugAccess) {}
+}
+
+impl VramBank for MockBus {
+    fn vram_bank(&self) -> Byte {
+        0
+    }
+}
+
+impl Savable for MockBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.buf);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.read_bytes_into(&mut self.buf)
+    }
 }
\ No newline at end of file
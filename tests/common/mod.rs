@@ -1,2 +1,5 @@
+// every test crate builds all of these but only uses a few
+#![allow(dead_code)]
+
 pub mod fixture;
 pub mod mock;
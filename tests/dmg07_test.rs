@@ -0,0 +1,139 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    dmg07::{Dmg07, Phase},
+    gameboy::GameBoy,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// a link client: sends the bytes at 0xD000 one by one on the external clock
+// and logs what it receives at 0xC000
+fn client_rom() -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    let handler = [
+        0xF0, 0x01, // LDH A, (SB)
+        0x22,       // LD (HL+), A
+        0x13,       // INC DE
+        0x1A,       // LD A, (DE)
+        0xE0, 0x01, // LDH (SB), A
+        0x3E, 0x80, // LD A, 0x80
+        0xE0, 0x02, // LDH (SC), A
+        0xD9,       // RETI
+    ];
+    rom[0x0058..0x0058 + handler.len()].copy_from_slice(&handler);
+    let main = [
+        0x3E, 0x08,       // LD A, 0x08
+        0xE0, 0xFF,       // LDH (IE), A
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x11, 0x00, 0xD0, // LD DE, 0xD000
+        0x1A,             // LD A, (DE)
+        0xE0, 0x01,       // LDH (SB), A
+        0x3E, 0x80,       // LD A, 0x80
+        0xE0, 0x02,       // LDH (SC), A
+        0xFB,             // EI
+        0x76,             // HALT
+        0x18, 0xFD,       // JR -3
+    ];
+    rom[0x0100..0x0100 + main.len()].copy_from_slice(&main);
+    rom
+}
+
+fn client(script: &[u8]) -> GameBoy {
+    let gb = GameBoy::new(&client_rom());
+    {
+        let mut bus = gb.cpu.bus.lock().unwrap();
+        for (i, b) in script.iter().enumerate() {
+            bus.write(0xD000 + i as u16, *b);
+        }
+    }
+    gb
+}
+
+fn received(gb: &GameBoy, len: usize) -> Vec<u8> {
+    let bus = gb.cpu.bus.lock().unwrap();
+    (0..len as u16).map(|i| bus.read(0xC000 + i)).collect()
+}
+
+fn contains(log: &[u8], packet: &[u8]) -> bool {
+    log.windows(packet.len()).any(|w| w == packet)
+}
+
+// two pings, player 1 asking for 2 byte packets, then the start request
+// and a byte for each 0xCC
+fn handshake(player1: bool) -> Vec<u8> {
+    let mut script = vec![];
+    for _ in 0..2 {
+        script.extend([0x88, 0x88, 0x00, 0x02]);
+    }
+    if player1 {
+        script.extend([0xAA; 4]);
+    } else {
+        script.extend([0x88, 0x88, 0x00, 0x00]);
+    }
+    script.extend([0x00; 4]);
+    script
+}
+
+speculate! {
+    describe "dmg07" {
+        it "links 1 to 4 Game Boys" {
+            assert!(Dmg07::new(vec![]).is_err());
+            let players = (0..5).map(|_| GameBoy::new(&idle_rom(&[]))).collect();
+            assert!(Dmg07::new(players).is_err());
+        }
+
+        it "pings every port" {
+            let players = vec![client(&[0x88; 16]), GameBoy::new(&idle_rom(&[])), client(&[0x88; 16])];
+            let mut link = Dmg07::new(players).unwrap();
+            link.exec_frame();
+            link.exec_frame();
+
+            assert_eq!(link.phase(), Phase::Ping);
+            assert_eq!(link.connected(), 0b0101);
+            let log = received(&link.players()[2], 8);
+            assert_eq!(log, [0xFE, 0x03, 0x03, 0x03, 0xFE, 0x53, 0x53, 0x53]);
+        }
+
+        it "relays everyone's packets" {
+            let mut script1 = handshake(true);
+            script1.extend([0x11, 0x12, 0, 0, 0, 0, 0, 0]);
+            let mut script2 = handshake(false);
+            script2.extend([0x21, 0x22, 0, 0, 0, 0, 0, 0]);
+            let mut link = Dmg07::new(vec![client(&script1), client(&script2)]).unwrap();
+            for _ in 0..5 {
+                link.exec_frame();
+            }
+
+            assert_eq!(link.phase(), Phase::Transmission);
+            assert_eq!(link.connected(), 0b0011);
+            assert_eq!(link.packet_size(), 2);
+            for gb in link.players() {
+                let log = received(gb, 64);
+                assert_eq!(log[12..16], [0xCC; 4]);
+                // buffered for one frame
+                assert_eq!(log[16..24], [0; 8]);
+                assert_eq!(log[24..32], [0x11, 0x12, 0x21, 0x22, 0, 0, 0, 0]);
+            }
+        }
+
+        it "goes back to pinging when everyone sends 0xFF" {
+            let mut script1 = handshake(true);
+            script1.extend([0xFF; 8]);
+            let mut script2 = handshake(false);
+            script2.extend([0xFF; 8]);
+            let mut link = Dmg07::new(vec![client(&script1), client(&script2)]).unwrap();
+            for _ in 0..5 {
+                link.exec_frame();
+            }
+
+            assert_eq!(link.phase(), Phase::Ping);
+            let log = received(&link.players()[0], 64);
+            assert!(contains(&log[24..], &[0xFE, 0x31, 0x31, 0x31]));
+        }
+    }
+}
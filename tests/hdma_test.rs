@@ -0,0 +1,137 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::GameBoy,
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// fill WRAM C000-C0FF with a pattern and point HDMA from there to `dst`
+fn setup(gb: &GameBoy, dst: u16) {
+    for i in 0..0x100 {
+        write(gb, 0xC000 + i, i as u8 ^ 0x5A);
+    }
+    write(gb, 0xFF51, 0xC0);
+    write(gb, 0xFF52, 0x00);
+    write(gb, 0xFF53, (dst >> 8) as u8);
+    write(gb, 0xFF54, dst as u8);
+}
+
+fn copied(gb: &GameBoy, dst: u16, len: u16) -> bool {
+    (0..len).all(|i| read(gb, dst + i) == i as u8 ^ 0x5A)
+}
+
+fn step_until(gb: &mut GameBoy, f: impl Fn(&GameBoy) -> bool) {
+    for _ in 0..100000 {
+        if f(gb) {
+            return;
+        }
+        gb.step();
+    }
+    panic!("timed out");
+}
+
+speculate! {
+    describe "hdma" {
+        it "copies everything at once in general-purpose mode" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            write(&gb, 0xFF4F, 0x01);
+            setup(&gb, 0x8800);
+            write(&gb, 0xFF55, 0x03);
+
+            // the CPU is stalled for one block per step
+            for remaining in [0x02, 0x01, 0x00] {
+                gb.step();
+                assert_eq!(read(&gb, 0xFF55), remaining);
+            }
+            gb.step();
+            assert_eq!(read(&gb, 0xFF55), 0xFF);
+            assert!(copied(&gb, 0x8800, 0x40));
+            assert!(!copied(&gb, 0x8840, 0x10));
+
+            // into the bank selected by VBK
+            write(&gb, 0xFF4F, 0x00);
+            assert!(!copied(&gb, 0x8800, 0x10));
+        }
+
+        it "copies into VRAM while the PPU is drawing" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            write(&gb, 0xFF4F, 0x01);
+            step_until(&mut gb, |gb| read(gb, 0xFF41) & 0x03 == 0x03);
+            setup(&gb, 0x9800);
+            write(&gb, 0xFF55, 0x01);
+            gb.step();
+            gb.step();
+            assert_eq!(read(&gb, 0xFF55), 0xFF);
+            // the CPU itself is still locked out of VRAM
+            assert_eq!(read(&gb, 0xFF41) & 0x03, 0x03);
+            assert_eq!(read(&gb, 0x9800), 0xFF);
+
+            step_until(&mut gb, |gb| read(gb, 0xFF41) & 0x03 == 0x00);
+            assert!(copied(&gb, 0x9800, 0x20));
+            write(&gb, 0xFF4F, 0x00);
+            assert!(!copied(&gb, 0x9800, 0x10));
+        }
+
+        it "copies one block per HBlank" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            setup(&gb, 0x9000);
+            write(&gb, 0xFF55, 0x80 | 0x02);
+            assert_eq!(read(&gb, 0xFF55), 0x02);
+
+            step_until(&mut gb, |gb| read(gb, 0xFF55) == 0x01);
+            let ly = read(&gb, 0xFF44);
+            assert!(copied(&gb, 0x9000, 0x10));
+            assert!(!copied(&gb, 0x9010, 0x10));
+
+            step_until(&mut gb, |gb| read(gb, 0xFF55) == 0x00);
+            assert_eq!(read(&gb, 0xFF44), ly + 1);
+
+            gb.exec_frame();
+            assert_eq!(read(&gb, 0xFF55), 0xFF);
+            assert!(copied(&gb, 0x9000, 0x30));
+        }
+
+        it "stops an HBlank transfer" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            setup(&gb, 0x9000);
+            write(&gb, 0xFF55, 0x80 | 0x03);
+            step_until(&mut gb, |gb| read(gb, 0xFF55) == 0x02);
+            write(&gb, 0xFF55, 0x00);
+            assert_eq!(read(&gb, 0xFF55), 0x82);
+
+            gb.exec_frame();
+            assert_eq!(read(&gb, 0xFF55), 0x82);
+            assert!(copied(&gb, 0x9000, 0x10));
+            assert!(!copied(&gb, 0x9010, 0x10));
+        }
+
+        it "survives a save state" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            setup(&gb, 0x9000);
+            write(&gb, 0xFF55, 0x80 | 0x03);
+            step_until(&mut gb, |gb| read(gb, 0xFF55) == 0x02);
+            let state = gb.save_state();
+
+            let mut other = boot(&idle_rom(&[CGB_FLAG]), Model::Cgb);
+            other.load_state(&state).unwrap();
+            other.exec_frame();
+            assert_eq!(read(&other, 0xFF55), 0xFF);
+            assert!(copied(&other, 0x9000, 0x40));
+        }
+
+        it "doesn't exist in DMG mode" {
+            let mut gb = boot(&idle_rom(&[CGB_FLAG]), Model::Dmg);
+            setup(&gb, 0x8000);
+            write(&gb, 0xFF55, 0x00);
+            gb.step();
+            assert_eq!(read(&gb, 0xFF55), 0xFF);
+            assert!(!copied(&gb, 0x8000, 0x10));
+        }
+    }
+}
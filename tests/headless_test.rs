@@ -0,0 +1,107 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::GameBoy,
+    headless::{run as run_headless, run_test, HeadlessOptions, StopReason, TestOutcome, AUDIO_SAMPLE_RATE},
+};
+use common::fixture::*;
+use speculate::speculate;
+use std::env;
+
+speculate! {
+    describe "headless" {
+        it "stops on serial output and writes the results" {
+            let out = env::temp_dir().join("rust_boy_headless_test");
+            std::fs::create_dir_all(&out).unwrap();
+            let options = HeadlessOptions {
+                frames: 3600,
+                until_serial: vec!["Passed".to_string()],
+                screenshot: Some(out.join("screen.png")),
+                serial_out: Some(out.join("serial.txt")),
+                state_out: Some(out.join("final.state")),
+                ..Default::default()
+            };
+
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs/individual", "01-special"));
+            let report = run_headless(&mut gb, &options).unwrap();
+            assert_eq!(report.reason, StopReason::Serial);
+            assert!(report.passed(&options));
+
+            assert_eq!(std::fs::read(out.join("serial.txt")).unwrap(), report.serial);
+            assert!(image::open(out.join("screen.png")).is_ok());
+            let mut other = GameBoy::new(&load_rom("blargg/cpu_instrs/individual", "01-special"));
+            other.load_state(&std::fs::read(out.join("final.state")).unwrap()).unwrap();
+            assert_eq!(other.save_state(), gb.save_state());
+        }
+
+        it "writes what was played as WAV" {
+            let out = env::temp_dir().join("rust_boy_headless_audio_test");
+            std::fs::create_dir_all(&out).unwrap();
+            let options = HeadlessOptions {
+                frames: 60,
+                audio_out: Some(out.join("audio.wav")),
+                ..Default::default()
+            };
+
+            // channel 2 at full volume on both sides
+            let mut gb = GameBoy::new(&idle_rom(&[]));
+            for (addr, value) in [(0xFF24, 0x77), (0xFF25, 0x22), (0xFF16, 0x80), (0xFF17, 0xF0), (0xFF18, 0x7D), (0xFF19, 0x87)] {
+                write(&gb, addr, value);
+            }
+            run_headless(&mut gb, &options).unwrap();
+
+            let wav = std::fs::read(out.join("audio.wav")).unwrap();
+            assert_eq!(&wav[0..4], b"RIFF");
+            assert_eq!(&wav[8..16], b"WAVEfmt ");
+            assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 2);
+            assert_eq!(u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]), AUDIO_SAMPLE_RATE);
+            assert_eq!(&wav[36..40], b"data");
+            let size = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize;
+            assert_eq!(size, wav.len() - 44);
+            // a second of 4 byte stereo frames
+            assert!((size / 4).abs_diff(44303) <= 2, "{} bytes", size);
+            let loud = wav[44..].chunks(2).filter(|s| i16::from_le_bytes([s[0], s[1]]).abs() > 8000).count();
+            assert!(loud > size / 4);
+        }
+
+        it "stops at LD B,B" {
+            let options = HeadlessOptions {
+                frames: 600,
+                until_breakpoint: true,
+                ..Default::default()
+            };
+            let mut gb = GameBoy::new(&load_rom("mooneye-gb/acceptance", "add_sp_e_timing"));
+            let report = run_headless(&mut gb, &options).unwrap();
+            assert_eq!(report.reason, StopReason::Breakpoint);
+            assert!(report.frames < 600);
+        }
+
+        it "fails when the frame limit comes first" {
+            let options = HeadlessOptions {
+                frames: 10,
+                until_serial: vec!["Passed".to_string()],
+                ..Default::default()
+            };
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs/individual", "01-special"));
+            let report = run_headless(&mut gb, &options).unwrap();
+            assert_eq!(report.reason, StopReason::FrameLimit);
+            assert_eq!(report.frames, 10);
+            assert!(!report.passed(&options));
+        }
+
+        it "reports test ROM results" {
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs/individual", "01-special"));
+            assert_eq!(run_test(&mut gb, 3600).unwrap(), TestOutcome::Passed);
+
+            let mut gb = GameBoy::new(&load_rom("mooneye-gb/acceptance/timer", "tim00"));
+            assert_eq!(run_test(&mut gb, 600).unwrap(), TestOutcome::Passed);
+
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs/individual", "01-special"));
+            assert_eq!(run_test(&mut gb, 10).unwrap(), TestOutcome::Timeout);
+        }
+    }
+}
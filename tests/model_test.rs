@@ -0,0 +1,76 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rstest::*;
+use rust_boy::{
+    gameboy::{GameBoy, GameBoyConfig},
+    headless::{run_test, TestOutcome},
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+fn boot_test(folder: &str, file: &str, model: Model) {
+    let rom = load_rom(folder, file);
+    let config = GameBoyConfig {
+        model,
+        ..Default::default()
+    };
+    let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+    assert_eq!(run_test(&mut gb, 300).unwrap(), TestOutcome::Passed, "{} on {}", file, model);
+}
+
+speculate! {
+    describe "model" {
+        #[rstest(folder, file, model,
+            case("mooneye-gb/acceptance", "boot_regs-dmg0", Model::Dmg0),
+            case("mooneye-gb/acceptance", "boot_regs-dmgABC", Model::Dmg),
+            case("mooneye-gb/acceptance", "boot_regs-mgb", Model::Mgb),
+            case("mooneye-gb/acceptance", "boot_regs-sgb", Model::Sgb),
+            case("mooneye-gb/acceptance", "boot_regs-sgb2", Model::Sgb2),
+            case("mooneye-gb/acceptance", "boot_div-dmg0", Model::Dmg0),
+            case("mooneye-gb/acceptance", "boot_div-dmgABCmgb", Model::Dmg),
+            case("mooneye-gb/acceptance", "boot_div-dmgABCmgb", Model::Mgb),
+            case("mooneye-gb/acceptance", "boot_div-S", Model::Sgb),
+            case("mooneye-gb/acceptance", "boot_div-S", Model::Sgb2),
+            case("mooneye-gb/acceptance", "boot_div2-S", Model::Sgb),
+            case("mooneye-gb/acceptance", "boot_div2-S", Model::Sgb2),
+            case("mooneye-gb/acceptance", "boot_hwio-dmg0", Model::Dmg0),
+            case("mooneye-gb/acceptance", "boot_hwio-dmgABCmgb", Model::Dmg),
+            case("mooneye-gb/acceptance", "boot_hwio-dmgABCmgb", Model::Mgb),
+            case("mooneye-gb/acceptance", "boot_hwio-S", Model::Sgb),
+            case("mooneye-gb/acceptance", "boot_hwio-S", Model::Sgb2),
+            // DMG cartridges on CGB and AGB
+            case("mooneye-gb/misc", "boot_regs-cgb", Model::Cgb),
+            case("mooneye-gb/misc", "boot_regs-A", Model::Agb),
+            case("mooneye-gb/misc", "boot_div-cgbABCDE", Model::Cgb),
+        )]
+        fn post_boot_state(folder: &str, file: &str, model: Model) {
+            boot_test(folder, file, model);
+        }
+
+        it "only passes the boot_regs ROM of its own model" {
+            let rom = load_rom("mooneye-gb/acceptance", "boot_regs-dmgABC");
+            for model in [Model::Dmg0, Model::Mgb, Model::Sgb, Model::Cgb] {
+                let config = GameBoyConfig { model, ..Default::default() };
+                let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+                assert_ne!(run_test(&mut gb, 300).unwrap(), TestOutcome::Passed, "{}", model);
+            }
+        }
+
+        it "leaves the logo from the header in VRAM on DMG" {
+            let rom = load_rom("mooneye-gb/acceptance", "boot_regs-dmgABC");
+            let gb = boot(&rom, Model::Dmg);
+            // 0xCE, the first logo byte, as two rows of doubled pixels
+            assert_eq!([read(&gb, 0x8010), read(&gb, 0x8012), read(&gb, 0x8014)], [0xF0, 0xF0, 0xFC]);
+            assert_eq!(read(&gb, 0x8190), 0x3C);
+            assert_eq!([read(&gb, 0x9904), read(&gb, 0x9910), read(&gb, 0x992F)], [0x01, 0x19, 0x18]);
+
+            let gb = boot(&rom, Model::Cgb);
+            assert_eq!(read(&gb, 0x8190), 0x00);
+        }
+    }
+}
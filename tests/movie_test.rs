@@ -0,0 +1,148 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::{GameBoy, GameBoyConfig},
+    joypad::*,
+    model::Model,
+    movie::Movie,
+    ppu::Renderer,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// press start now and then, jump a lot
+fn input(frame: usize) -> u8 {
+    match frame % 40 {
+        0..=2 => BUTTON_START,
+        10..=14 => BUTTON_A | BUTTON_RIGHT,
+        20..=24 => BUTTON_UP,
+        _ => 0,
+    }
+}
+
+// runs NOPs up to 0x00FC, then unmaps itself
+fn boot_rom() -> Vec<u8> {
+    let mut rom = vec![0x00; 0x100];
+    rom[0xFC..].copy_from_slice(&[
+        0x3E, 0x01, // LD A,1
+        0xE0, 0x50, // LDH (0x50),A
+    ]);
+    rom
+}
+
+fn record(gb: &mut GameBoy, movie: &mut Movie, frames: std::ops::Range<usize>) {
+    for i in frames {
+        movie.record_frame(gb, input(i));
+    }
+}
+
+speculate! {
+    describe "movie" {
+        it "replays bit-exactly from power-on" {
+            let rom = load_rom("dino", "dino");
+            let mut gb = GameBoy::new(&rom);
+            let mut movie = Movie::from_power_on(&gb);
+            record(&mut gb, &mut movie, 0..300);
+
+            let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+            assert_eq!(movie.len(), 300);
+            let replayed = movie.play(&rom, &GameBoyConfig::default()).unwrap();
+            assert_eq!(replayed.save_state(), gb.save_state());
+            assert_eq!(replayed.display(), gb.display());
+        }
+
+        it "replays bit-exactly from a save state" {
+            let rom = load_rom("dino", "dino");
+            let mut gb = GameBoy::new(&rom);
+            for i in 0..100 {
+                gb.exec_frame_with_input(input(i));
+            }
+            let mut movie = Movie::from_state(&gb);
+            record(&mut gb, &mut movie, 100..300);
+
+            let replayed = movie.play(&rom, &GameBoyConfig::default()).unwrap();
+            assert_eq!(replayed.save_state(), gb.save_state());
+        }
+
+        it "reports lag frames" {
+            let rom = load_rom("dino", "dino");
+            let mut gb = GameBoy::new(&rom);
+            let mut movie = Movie::from_power_on(&gb);
+            record(&mut gb, &mut movie, 0..300);
+
+            let lag = movie.lag_frames();
+            assert!(lag.len() < movie.len());
+            for (i, frame) in movie.frames().iter().enumerate() {
+                assert_eq!(frame.lag, lag.contains(&i));
+            }
+
+            // cpu_instrs never reads the joypad
+            let rom = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let mut gb = GameBoy::new(&rom);
+            let mut movie = Movie::from_power_on(&gb);
+            record(&mut gb, &mut movie, 0..10);
+            assert_eq!(movie.lag_frames().len(), 10);
+        }
+
+        it "continues recording after truncating" {
+            let rom = load_rom("dino", "dino");
+            let mut gb = GameBoy::new(&rom);
+            let mut movie = Movie::from_power_on(&gb);
+            record(&mut gb, &mut movie, 0..300);
+
+            movie.truncate(150);
+            let mut gb = movie.play(&rom, &GameBoyConfig::default()).unwrap();
+            for i in 150..250 {
+                movie.record_frame(&mut gb, input(i + 7));
+            }
+            assert_eq!(movie.len(), 250);
+            assert_eq!(movie.play(&rom, &GameBoyConfig::default()).unwrap().save_state(), gb.save_state());
+        }
+
+        it "records every SGB player and the model" {
+            let rom = load_rom("dino", "dino");
+            let config = GameBoyConfig { model: Model::Sgb, ..Default::default() };
+            let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+            let mut movie = Movie::from_power_on(&gb);
+            for i in 0..200 {
+                movie.record_frame_players(&mut gb, [input(i), 0, input(i + 20), BUTTON_B]);
+            }
+
+            let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+            assert_eq!(movie.model(), Model::Sgb);
+            assert_eq!(movie.frames()[10].inputs, [BUTTON_A | BUTTON_RIGHT, 0, 0, BUTTON_B]);
+            let replayed = movie.play(&rom, &GameBoyConfig::default()).unwrap();
+            assert_eq!(replayed.model(), Model::Sgb);
+            assert_eq!(replayed.save_state(), gb.save_state());
+        }
+
+        it "plays back with the boot ROM and settings it's given" {
+            let rom = load_rom("dino", "dino");
+            let config = GameBoyConfig { boot_rom: Some(boot_rom()), renderer: Renderer::Fifo, ..Default::default() };
+            let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+            let mut movie = Movie::from_power_on(&gb);
+            record(&mut gb, &mut movie, 0..200);
+
+            let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+            let replayed = movie.play(&rom, gb.config()).unwrap();
+            assert_eq!(replayed.save_state(), gb.save_state());
+            assert_eq!(replayed.config().renderer, Renderer::Fifo);
+
+            // starting without the boot ROM would desync
+            assert!(movie.start(&rom, &GameBoyConfig::default()).is_err());
+            let movie = Movie::from_power_on(&GameBoy::new(&rom));
+            assert!(movie.start(&rom, &config).is_err());
+        }
+
+        it "rejects a different ROM" {
+            let gb = GameBoy::new(&load_rom("dino", "dino"));
+            let movie = Movie::from_power_on(&gb);
+            assert!(movie.start(&load_rom("blargg/cpu_instrs", "cpu_instrs"), &GameBoyConfig::default()).is_err());
+            assert!(Movie::from_bytes(&movie.to_bytes()[..8]).is_err());
+        }
+    }
+}
@@ -0,0 +1,43 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rstest::*;
+use rust_boy::{
+    headless::{run_test, TestOutcome},
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+fn oam_bug_test(file: &str) {
+    let mut gb = boot(&load_rom("blargg/oam_bug/rom_singles", file), Model::Dmg);
+    assert_eq!(run_test(&mut gb, 3000).unwrap(), TestOutcome::Passed, "{}", file);
+    // blargg's result protocol: signature at $A001, result code at $A000
+    assert_eq!([read(&gb, 0xA001), read(&gb, 0xA002), read(&gb, 0xA003)], [0xDE, 0xB0, 0x61]);
+    assert_eq!(read(&gb, 0xA000), 0x00, "{}", file);
+}
+
+speculate! {
+    describe "oam bug" {
+        #[rstest(file,
+            case("1-lcd_sync"),
+            case("2-causes"),
+            case("3-non_causes"),
+            case("4-scanline_timing"),
+            case("5-timing_bug"),
+            case("6-timing_no_bug"),
+            case("8-instr_effect"),
+        )]
+        fn passes_on_dmg(file: &str) {
+            oam_bug_test(file);
+        }
+
+        #[ignore = "7-timing_effect logs its OAM tables past the 8 KB of cartridge RAM into its own code at $C000, which restarts it"]
+        it "passes 7-timing_effect" {
+            oam_bug_test("7-timing_effect");
+        }
+    }
+}
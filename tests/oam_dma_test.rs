@@ -0,0 +1,101 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::gameboy::GameBoy;
+use common::fixture::*;
+use speculate::speculate;
+
+fn fill(gb: &GameBoy, page: u8, pattern: u8) {
+    for i in 0..0xA0 {
+        write(gb, (page as u16) << 8 | i, i as u8 ^ pattern);
+    }
+}
+
+// fill WRAM page `page` with a pattern and start a DMA from it
+fn start(gb: &GameBoy, page: u8, pattern: u8) {
+    fill(gb, page, pattern);
+    write(gb, 0xFF46, page);
+}
+
+// spins on JR -2 in HRAM, out of the DMA's way, 3 M-cycles a loop;
+// the LCD is off so the PPU leaves OAM alone
+fn boot() -> GameBoy {
+    let mut gb = GameBoy::new(&vec![0x00; 0x8000]);
+    write(&gb, 0xFF40, 0x00);
+    write(&gb, 0xFF80, 0x18);
+    write(&gb, 0xFF81, 0xFE);
+    gb.cpu.reg.PC = 0xFF80;
+    gb
+}
+
+fn copied(gb: &GameBoy, pattern: u8) -> bool {
+    (0..0xA0).all(|i| read(gb, 0xFE00 + i) == i as u8 ^ pattern)
+}
+
+speculate! {
+    describe "oam dma" {
+        before {
+            let mut gb = boot();
+        }
+
+        it "copies a byte per M-cycle" {
+            start(&gb, 0xC0, 0x5A);
+            assert_eq!(read(&gb, 0xFF46), 0xC0);
+            // 53 loops of 3 M-cycles, 2 short of the end
+            for _ in 0..53 {
+                gb.step();
+            }
+            assert_eq!(read(&gb, 0xFE00), 0xFF);
+            gb.step();
+            assert!(copied(&gb, 0x5A));
+        }
+
+        it "locks the CPU out of OAM and the bus it reads from" {
+            write(&gb, 0xFF90, 0x42);
+            write(&gb, 0x8000, 0x24);
+            start(&gb, 0xC0, 0x5A);
+            gb.step();
+
+            assert_eq!(read(&gb, 0xFE00), 0xFF);
+            // byte 1 is being copied
+            assert_eq!(read(&gb, 0xD123), 0x5B);
+            write(&gb, 0xC000, 0x00);
+            assert_eq!(read(&gb, 0xFF90), 0x42);
+            assert_eq!(read(&gb, 0x8000), 0x24);
+
+            for _ in 0..60 {
+                gb.step();
+            }
+            assert_eq!(read(&gb, 0xC000), 0x5A);
+        }
+
+        it "restarts when DMA is written again" {
+            // WRAM is out of reach once it starts
+            fill(&gb, 0xC1, 0xA5);
+            start(&gb, 0xC0, 0x5A);
+            for _ in 0..20 {
+                gb.step();
+            }
+            write(&gb, 0xFF46, 0xC1);
+            gb.step();
+            assert_eq!(read(&gb, 0xFE00), 0xFF);
+
+            for _ in 0..60 {
+                gb.step();
+            }
+            assert!(copied(&gb, 0xA5));
+        }
+
+        it "reads the WRAM below E000-FFFF" {
+            start(&gb, 0xDE, 0x3C);
+            write(&gb, 0xFF46, 0xFE);
+            for _ in 0..60 {
+                gb.step();
+            }
+            assert!(copied(&gb, 0x3C));
+        }
+    }
+}
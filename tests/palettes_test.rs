@@ -0,0 +1,58 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+use rust_boy::{
+    palettes::Palettes,
+    ppu::{DMG_COLORS_GRAY, DMG_COLORS_GREEN},
+};
+use speculate::speculate;
+
+const FILE: &str = "
+# comments and blank lines are skipped
+
+mono = #FFFFFF,#AAAAAA,#555555,#000000
+split = #FFFFFF,#AAAAAA,#555555,#000000 ; #FF0000,#AA0000,#550000,#000000 ; #0000FF,#0000AA,#000055,#000000
+green = #FFFFFF,#AAAAAA,#555555,#000000
+";
+
+speculate! {
+    describe "palettes" {
+        it "adds named and per-layer palettes from a file" {
+            let mut palettes = Palettes::default();
+            palettes.parse(FILE).unwrap();
+            assert_eq!(palettes.get("mono").unwrap(), DMG_COLORS_GRAY.into());
+
+            let split = palettes.get("split").unwrap();
+            assert_eq!(split.bg, DMG_COLORS_GRAY);
+            assert_eq!(split.obj0[1], [0xAA, 0x00, 0x00]);
+            assert_eq!(split.obj1[1], [0x00, 0x00, 0xAA]);
+
+            let names: Vec<&str> = palettes.entries().iter().map(|(n, _)| n.as_str()).collect();
+            assert_eq!(names, ["green", "gray", "pocket", "mono", "split"]);
+        }
+
+        it "lets a file replace a built-in palette" {
+            let mut palettes = Palettes::default();
+            assert_eq!(palettes.get("green").unwrap(), DMG_COLORS_GREEN.into());
+            palettes.parse(FILE).unwrap();
+            assert_eq!(palettes.get("green").unwrap(), DMG_COLORS_GRAY.into());
+        }
+
+        it "resolves names and colors" {
+            let palettes = Palettes::default();
+            assert_eq!(palettes.resolve("gray").unwrap(), DMG_COLORS_GRAY.into());
+            assert_eq!(palettes.resolve("#FFFFFF,#AAAAAA,#555555,#000000").unwrap(), DMG_COLORS_GRAY.into());
+            assert!(palettes.resolve("mono").is_err());
+        }
+
+        it "rejects broken lines" {
+            let mut palettes = Palettes::default();
+            assert!(palettes.parse("mono #FFFFFF,#AAAAAA,#555555,#000000").is_err());
+            assert!(palettes.parse("= #FFFFFF,#AAAAAA,#555555,#000000").is_err());
+            assert!(palettes.parse("mono = #FFFFFF,#AAAAAA,#555555").is_err());
+            assert!(palettes.parse("mono = #FFFFFF,#AAAAAA,#555555,#00000G").is_err());
+            assert!(palettes.parse("two = #FFFFFF,#AAAAAA,#555555,#000000 ; #FFFFFF,#AAAAAA,#555555,#000000").is_err());
+        }
+    }
+}
@@ -0,0 +1,289 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::{GameBoy, GameBoyConfig},
+    interrupt::Interrupt,
+    model::Model,
+    ppu::{Layer, LayerColors, Ppu, Renderer, DMG_COLORS_GRAY, DMG_COLORS_GREEN, DMG_COLORS_POCKET},
+    state::{Savable, StateReader, StateWriter},
+    traits::{Reader, Writer},
+};
+use common::fixture::*;
+use speculate::speculate;
+use std::sync::{Arc, Mutex};
+
+// the scanline PPU on its own, 16 dots into mode 3 of line 0, saved, with the
+// offset of its mode 3 length in the state: the last byte that SCX changes
+fn mode_3_state() -> (Ppu, Vec<u8>, usize) {
+    let save = |scx: u8| {
+        let mut ppu = Ppu::new(Arc::new(Mutex::new(Interrupt::new())), false);
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF43, scx);
+        ppu.set_position(0, 100);
+        let mut w = StateWriter::new();
+        ppu.save_state(&mut w);
+        (ppu, w.into_inner())
+    };
+    let (_, other) = save(3);
+    let (ppu, state) = save(0);
+    let at = (0..state.len()).rev().find(|&i| state[i] != other[i]).unwrap();
+    (ppu, state, at)
+}
+
+// keeps stepping DE through 0xFE40, in OAM's reach, with INC DE
+fn inc_de_rom() -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    rom[0x0100..0x0106].copy_from_slice(&[0x11, 0x40, 0xFE, 0x13, 0x18, 0xFA]);
+    rom
+}
+
+// BGP shade 1 everywhere but a solid object using OBP1 in the top left
+// corner, in shade 2, after a frame was shown
+fn boot_with_object() -> GameBoy {
+    let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+    write(&gb, 0xFF40, 0x00);
+    for i in 0..16 {
+        write(&gb, 0x8010 + i, 0xFF);
+    }
+    for (i, v) in [16, 8, 1, 0x10].into_iter().enumerate() {
+        write(&gb, 0xFE00 + i as u16, v);
+    }
+    write(&gb, 0xFF47, 0x01);
+    write(&gb, 0xFF49, 0x80);
+    write(&gb, 0xFF40, 0x93);
+    gb.exec_frame();
+    gb.exec_frame();
+    gb
+}
+
+fn step_until_mode(gb: &mut GameBoy, mode: u8) {
+    for _ in 0..100000 {
+        if read(gb, 0xFF41) & 0x03 == mode {
+            return;
+        }
+        gb.step();
+    }
+    panic!("never got to mode {}", mode);
+}
+
+// a full screen window over a blank BG, where each window line shows the
+// line counter it was drawn with in the low bitplane of its first 8 pixels
+fn boot_with_counting_window(renderer: Renderer) -> GameBoy {
+    let mut gb = boot_with(&idle_rom(&[]), &GameBoyConfig { renderer, ..Default::default() });
+    write(&gb, 0xFF40, 0x00);
+    for line in 0..144 {
+        let addr = 0x8010 + (line / 8) * 16 + (line % 8) * 2;
+        write(&gb, addr, line as u8);
+        write(&gb, addr + 1, 0xFF);
+    }
+    for i in 0..0x400 {
+        write(&gb, 0x9800 + i, 0x00);
+        write(&gb, 0x9C00 + i, 1 + (i / 32) as u8);
+    }
+    write(&gb, 0xFF47, 0xE4);
+    write(&gb, 0xFF4A, 0);
+    write(&gb, 0xFF4B, 7);
+    write(&gb, 0xFF40, 0xF1);
+    gb.exec_frame();
+    step_until_mode(&mut gb, 1);
+    gb
+}
+
+fn step_to_line(gb: &mut GameBoy, ly: u8) {
+    for _ in 0..100000 {
+        if read(gb, 0xFF44) == ly && read(gb, 0xFF41) & 0x03 == 2 {
+            return;
+        }
+        gb.step();
+    }
+    panic!("never got to line {}", ly);
+}
+
+// where the window starts and the line counter it shows, for each line of
+// the frame once it's over
+fn window_lines(gb: &mut GameBoy) -> Vec<Option<(usize, u8)>> {
+    step_until_mode(gb, 1);
+    let ppu = gb.ppu();
+    ppu.pixels()
+        .chunks(160)
+        .map(|line| {
+            let x = line.iter().position(|&p| Layer::split(p).0 >= 2)?;
+            let counter = line[x..x + 8].iter().fold(0, |c, &p| c << 1 | (Layer::split(p).0 & 1));
+            Some((x, counter))
+        })
+        .collect()
+}
+
+speculate! {
+    describe "ppu" {
+        it "locks VRAM in mode 3" {
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            step_until_mode(&mut gb, 0);
+            write(&gb, 0x8000, 0x12);
+
+            step_until_mode(&mut gb, 3);
+            assert_eq!(read(&gb, 0x8000), 0xFF);
+            write(&gb, 0x8000, 0x34);
+            assert_eq!(read(&gb, 0xFE00), 0xFF);
+
+            step_until_mode(&mut gb, 0);
+            assert_eq!(read(&gb, 0x8000), 0x12);
+        }
+
+        it "locks OAM in mode 2" {
+            let mut gb = boot(&idle_rom(&[]), Model::Dmg);
+            step_until_mode(&mut gb, 0);
+            write(&gb, 0xFE00, 0x12);
+
+            step_until_mode(&mut gb, 2);
+            assert_eq!(read(&gb, 0xFE00), 0xFF);
+            write(&gb, 0xFE00, 0x34);
+            write(&gb, 0x8000, 0x56);
+
+            step_until_mode(&mut gb, 1);
+            assert_eq!(read(&gb, 0xFE00), 0x12);
+            assert_eq!(read(&gb, 0x8000), 0x56);
+        }
+
+        it "leaves VRAM and OAM open while the LCD is off" {
+            let gb = boot(&idle_rom(&[]), Model::Dmg);
+            write(&gb, 0xFF40, 0x00);
+            write(&gb, 0x8000, 0x12);
+            write(&gb, 0xFE00, 0x34);
+            assert_eq!(read(&gb, 0x8000), 0x12);
+            assert_eq!(read(&gb, 0xFE00), 0x34);
+        }
+
+        it "can be unlocked for debugging" {
+            let mut gb = boot_with(&idle_rom(&[]), &GameBoyConfig { unlock_vram: true, ..Default::default() });
+            step_until_mode(&mut gb, 3);
+            write(&gb, 0x8000, 0x12);
+            write(&gb, 0xFE00, 0x34);
+            assert_eq!(read(&gb, 0x8000), 0x12);
+            assert_eq!(read(&gb, 0xFE00), 0x34);
+        }
+
+        it "corrupts OAM when a 16-bit register points at it in mode 2 on DMG" {
+            for (model, corrupts) in [(Model::Dmg, true), (Model::Cgb, false)] {
+                let mut gb = boot(&inc_de_rom(), model);
+                step_until_mode(&mut gb, 1);
+                for i in 0..0xA0 {
+                    write(&gb, 0xFE00 + i, i as u8);
+                }
+
+                step_until_mode(&mut gb, 2);
+                step_until_mode(&mut gb, 1);
+                let changed = (0..0xA0).any(|i| read(&gb, 0xFE00 + i) != i as u8);
+                assert_eq!(changed, corrupts, "{:?}", model);
+            }
+        }
+
+        it "keeps the frame's shades and layers before colouring" {
+            let gb = boot_with_object();
+            let ppu = gb.ppu();
+            assert_eq!(Layer::split(ppu.pixels()[0]), (2, Layer::Obj1));
+            assert_eq!(Layer::split(ppu.pixels()[8]), (1, Layer::Bg));
+
+            let green = LayerColors { bg: DMG_COLORS_GREEN, obj0: DMG_COLORS_GREEN, obj1: DMG_COLORS_GREEN };
+            assert_eq!(Ppu::render_pixels(ppu.pixels(), &green), ppu.display());
+            let gray = LayerColors { obj1: DMG_COLORS_GRAY, ..green };
+            let image = Ppu::render_pixels(ppu.pixels(), &gray);
+            assert_eq!(image.get_pixel(0, 0).0, [85, 85, 85, 255]);
+            assert_eq!(image.get_pixel(8, 0), ppu.display().get_pixel(8, 0));
+        }
+
+        it "colours each layer with its own DMG colours, changed at any time" {
+            let mut gb = boot_with_object();
+            let colors = LayerColors { bg: DMG_COLORS_GRAY, obj0: DMG_COLORS_GREEN, obj1: DMG_COLORS_POCKET };
+            gb.set_layer_colors(colors);
+            assert_eq!(gb.layer_colors(), colors);
+            // the frame on screen is coloured again right away
+            assert_eq!(gb.display().get_pixel(0, 0).0, [77, 83, 60, 255]);
+            assert_eq!(gb.display().get_pixel(8, 0).0, [170, 170, 170, 255]);
+
+            gb.exec_frame();
+            assert_eq!(gb.display().get_pixel(0, 0).0, [77, 83, 60, 255]);
+            assert_eq!(gb.display().get_pixel(8, 0).0, [170, 170, 170, 255]);
+        }
+
+        it "latches WY once per frame" {
+            for renderer in [Renderer::Scanline, Renderer::Fifo] {
+                let mut gb = boot_with_counting_window(renderer);
+                write(&gb, 0xFF4A, 10);
+                step_to_line(&mut gb, 20);
+                // too late to hide a window that already started
+                write(&gb, 0xFF4A, 100);
+                let lines = window_lines(&mut gb);
+                assert_eq!(lines[9], None, "{:?}", renderer);
+                for (ly, line) in lines.into_iter().enumerate().skip(10) {
+                    assert_eq!(line, Some((0, ly as u8 - 10)), "{:?} line {}", renderer, ly);
+                }
+
+                // the next frame starts over, and a WY lines ago is never matched
+                step_to_line(&mut gb, 50);
+                write(&gb, 0xFF4A, 30);
+                assert!(window_lines(&mut gb).iter().all(Option::is_none), "{:?}", renderer);
+            }
+        }
+
+        it "only counts window lines that were drawn" {
+            for renderer in [Renderer::Scanline, Renderer::Fifo] {
+                let mut gb = boot_with_counting_window(renderer);
+                step_to_line(&mut gb, 10);
+                write(&gb, 0xFF40, 0xD1);
+                step_to_line(&mut gb, 20);
+                write(&gb, 0xFF40, 0xF1);
+                step_to_line(&mut gb, 30);
+                write(&gb, 0xFF4B, 167);
+                step_to_line(&mut gb, 40);
+                write(&gb, 0xFF4B, 7);
+                let lines = window_lines(&mut gb);
+
+                let expected = |ly: usize| match ly / 10 {
+                    0 => Some((0, ly as u8)),
+                    1 | 3 => None,
+                    2 => Some((0, ly as u8 - 10)),
+                    _ => Some((0, ly as u8 - 20)),
+                };
+                for (ly, line) in lines.into_iter().enumerate() {
+                    assert_eq!(line, expected(ly), "{:?} line {}", renderer, ly);
+                }
+            }
+        }
+
+        it "moves the window with WX mid-frame" {
+            for renderer in [Renderer::Scanline, Renderer::Fifo] {
+                let mut gb = boot_with_counting_window(renderer);
+                step_to_line(&mut gb, 50);
+                write(&gb, 0xFF4B, 87);
+                let lines = window_lines(&mut gb);
+                assert_eq!(lines[49], Some((0, 49)), "{:?}", renderer);
+                assert_eq!(lines[50], Some((80, 50)), "{:?}", renderer);
+                assert_eq!(lines[143], Some((80, 143)), "{:?}", renderer);
+            }
+        }
+
+        it "rejects a state with a mode 3 longer than the line" {
+            let (mut ppu, mut state, at) = mode_3_state();
+            for (length, valid) in [(372u16, true), (373, false)] {
+                state[at..at + 2].copy_from_slice(&length.to_le_bytes());
+                assert_eq!(ppu.load_state(&mut StateReader::new(&state)).is_ok(), valid, "{}", length);
+            }
+        }
+
+        it "works out the mode 3 length the FIFO renderer left open" {
+            let (mut ppu, mut state, at) = mode_3_state();
+            state[at..at + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+            ppu.load_state(&mut StateReader::new(&state)).unwrap();
+            assert_eq!(ppu.read(0xFF41) & 0x03, 0x03);
+            for _ in 0..50 {
+                ppu.step(4);
+            }
+            assert_eq!(ppu.read(0xFF41) & 0x03, 0x00);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rstest::*;
+use rust_boy::{
+    gameboy::{GameBoy, GameBoyConfig},
+    headless::{run_test, TestOutcome},
+    ppu::Renderer,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+fn timing_test(file: &str, renderer: Renderer) {
+    let rom = load_rom("mooneye-gb/acceptance/ppu", file);
+    let config = GameBoyConfig {
+        renderer,
+        ..Default::default()
+    };
+    let mut gb = GameBoy::with_config(&rom, &config).unwrap();
+    assert_eq!(run_test(&mut gb, 300).unwrap(), TestOutcome::Passed, "{} with {:?}", file, renderer);
+}
+
+speculate! {
+    describe "ppu timing" {
+        #[rstest(file, renderer,
+            case("intr_2_mode0_timing_sprites", Renderer::Scanline),
+            case("intr_2_mode0_timing_sprites", Renderer::Fifo),
+            case("hblank_ly_scx_timing-GS", Renderer::Scanline),
+            case("hblank_ly_scx_timing-GS", Renderer::Fifo),
+        )]
+        fn mode_3_length(file: &str, renderer: Renderer) {
+            timing_test(file, renderer);
+        }
+
+        #[rstest(file, renderer,
+            case("stat_irq_blocking", Renderer::Scanline),
+            case("stat_irq_blocking", Renderer::Fifo),
+            case("stat_lyc_onoff", Renderer::Scanline),
+            case("stat_lyc_onoff", Renderer::Fifo),
+            case("vblank_stat_intr-GS", Renderer::Scanline),
+            case("vblank_stat_intr-GS", Renderer::Fifo),
+        )]
+        fn stat_interrupt(file: &str, renderer: Renderer) {
+            timing_test(file, renderer);
+        }
+    }
+}
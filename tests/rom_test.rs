@@ -5,7 +5,7 @@ extern crate speculate;
 
 mod common;
 use rstest::*;
-use rust_boy::{traits::*, gameboy::GameBoy};
+use rust_boy::{gameboy::{GameBoy, GameBoyConfig}, ppu::{Ppu, Renderer, DMG_COLORS_GRAY}};
 use speculate::speculate;
 use std::env;
 
@@ -33,15 +33,21 @@ fn rom_test(folder: &String, file: &String, frame: u64, pass_str: String) {
 }
 
 fn rom_test_with_image(folder: &String, file: &String, frame: u64) {
+    rom_test_with_renderer(folder, file, frame, Renderer::Scanline);
+}
+
+fn rom_test_with_renderer(folder: &String, file: &String, frame: u64, renderer: Renderer) -> GameBoy {
     let pwd = env::current_dir().unwrap().into_os_string().into_string().unwrap();
     let rom_path: String = "/tests/roms/".to_string();
     let actual_path: String = "/tests/actual/".to_string();
     let path = pwd.to_string() + &rom_path + &folder + "/" + &file + ".gb";
     let actual_image_folder: String = pwd.to_string() + &actual_path + &folder;
-    let actual_image_file: String = actual_image_folder.to_string() + "/" + &file + ".jpg";
+    let suffix = if renderer == Renderer::Fifo { "_fifo" } else { "" };
+    let actual_image_file: String = actual_image_folder.to_string() + "/" + &file + suffix + ".jpg";
     let bytes = std::fs::read(path).unwrap();
 
-    let mut gb = GameBoy::new(&bytes);
+    let config = GameBoyConfig { renderer, ..Default::default() };
+    let mut gb = GameBoy::with_config(&bytes, &config).unwrap();
     for _ in 1..=frame {
         gb.exec_frame();
     }
@@ -50,6 +56,15 @@ fn rom_test_with_image(folder: &String, file: &String, frame: u64) {
 
     std::fs::create_dir_all(&actual_image_folder).unwrap();
     image.save(&actual_image_file).unwrap();
+    gb
+}
+
+// the frame, in gray, has to match the reference in tests/expect/<folder>/<file>.png
+fn assert_expected(gb: &GameBoy, folder: &str, file: &str) {
+    let image = Ppu::render_pixels(gb.ppu().pixels(), &DMG_COLORS_GRAY.into());
+    let path = format!("tests/expect/{}/{}.png", folder, file);
+    let expected = image::open(&path).unwrap_or_else(|e| panic!("{}: {}", path, e)).to_rgba8();
+    assert!(image == expected, "{} doesn't match {}", file, path);
 }
 
 
@@ -209,18 +224,6 @@ speculate! {
                 case(Args{folder: "mooneye-gb/acceptance/timer".to_string(), file: "tima_write_reloading".to_string(), frame: 50}),
                 case(Args{folder: "mooneye-gb/acceptance/timer".to_string(), file: "tma_write_reloading".to_string(), frame: 50}),
                 case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "add_sp_e_timing".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_div-dmg0".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_div-dmgABCmgb".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_div-S".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_div2-S".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_hwio-dmg0".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_hwio-dmgABCmgb".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_hwio-S".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_regs-dmg0".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_regs-dmgABC".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_regs-mgb".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_regs-sgb".to_string(), frame: 100}),
-                case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "boot_regs-sgb2".to_string(), frame: 100}),
                 case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "call_cc_timing".to_string(), frame: 100}),
                 case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "call_cc_timing2".to_string(), frame: 100}),
                 case(Args{folder: "mooneye-gb/acceptance".to_string(), file: "call_timing".to_string(), frame: 100}),
@@ -295,4 +298,139 @@ speculate! {
             }
         }
     }
-}
\ No newline at end of file
+
+    describe "dmg-acid2" {
+        // the reference is the frame both renderers draw, looked over by hand since the
+        // ROM's own reference image isn't shipped with it
+        it "renders" {
+            for renderer in [Renderer::Scanline, Renderer::Fifo] {
+                let gb = rom_test_with_renderer(&"dmg-acid2".to_string(), &"dmg-acid2".to_string(), 60, renderer);
+                assert_expected(&gb, "dmg-acid2", "dmg-acid2");
+            }
+        }
+    }
+}
+
+// speculate drops the #[case] attributes on arguments that the per-ROM ignores need
+mod mealybug_tearoom_tests {
+    use super::*;
+
+    // the references are the FIFO renderer's frames, looked over by hand since the hardware
+    // captures aren't shipped with the ROMs; the scanline renderer only matches the ROMs that
+    // don't change registers in the middle of a line
+    #[rstest]
+    #[case::m2_win_en_toggle_scanline("m2_win_en_toggle", Renderer::Scanline)]
+    #[case::m2_win_en_toggle_fifo("m2_win_en_toggle", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_bgp_change without its mid-line register writes"]
+    #[case::m3_bgp_change_scanline("m3_bgp_change", Renderer::Scanline)]
+    #[case::m3_bgp_change_fifo("m3_bgp_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_bgp_change_sprites without its mid-line register writes"]
+    #[case::m3_bgp_change_sprites_scanline("m3_bgp_change_sprites", Renderer::Scanline)]
+    #[case::m3_bgp_change_sprites_fifo("m3_bgp_change_sprites", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_bg_en_change without its mid-line register writes"]
+    #[case::m3_lcdc_bg_en_change_scanline("m3_lcdc_bg_en_change", Renderer::Scanline)]
+    #[case::m3_lcdc_bg_en_change_fifo("m3_lcdc_bg_en_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_bg_en_change2 without its mid-line register writes"]
+    #[case::m3_lcdc_bg_en_change2_scanline("m3_lcdc_bg_en_change2", Renderer::Scanline)]
+    #[case::m3_lcdc_bg_en_change2_fifo("m3_lcdc_bg_en_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_bg_map_change without its mid-line register writes"]
+    #[case::m3_lcdc_bg_map_change_scanline("m3_lcdc_bg_map_change", Renderer::Scanline)]
+    #[case::m3_lcdc_bg_map_change_fifo("m3_lcdc_bg_map_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_bg_map_change2 without its mid-line register writes"]
+    #[case::m3_lcdc_bg_map_change2_scanline("m3_lcdc_bg_map_change2", Renderer::Scanline)]
+    #[case::m3_lcdc_bg_map_change2_fifo("m3_lcdc_bg_map_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_obj_en_change without its mid-line register writes"]
+    #[case::m3_lcdc_obj_en_change_scanline("m3_lcdc_obj_en_change", Renderer::Scanline)]
+    #[case::m3_lcdc_obj_en_change_fifo("m3_lcdc_obj_en_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_obj_en_change_variant without its mid-line register writes"]
+    #[case::m3_lcdc_obj_en_change_variant_scanline("m3_lcdc_obj_en_change_variant", Renderer::Scanline)]
+    #[case::m3_lcdc_obj_en_change_variant_fifo("m3_lcdc_obj_en_change_variant", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_obj_size_change without its mid-line register writes"]
+    #[case::m3_lcdc_obj_size_change_scanline("m3_lcdc_obj_size_change", Renderer::Scanline)]
+    #[case::m3_lcdc_obj_size_change_fifo("m3_lcdc_obj_size_change", Renderer::Fifo)]
+    #[case::m3_lcdc_obj_size_change_scx_scanline("m3_lcdc_obj_size_change_scx", Renderer::Scanline)]
+    #[case::m3_lcdc_obj_size_change_scx_fifo("m3_lcdc_obj_size_change_scx", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_tile_sel_change without its mid-line register writes"]
+    #[case::m3_lcdc_tile_sel_change_scanline("m3_lcdc_tile_sel_change", Renderer::Scanline)]
+    #[case::m3_lcdc_tile_sel_change_fifo("m3_lcdc_tile_sel_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_tile_sel_change2 without its mid-line register writes"]
+    #[case::m3_lcdc_tile_sel_change2_scanline("m3_lcdc_tile_sel_change2", Renderer::Scanline)]
+    #[case::m3_lcdc_tile_sel_change2_fifo("m3_lcdc_tile_sel_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_tile_sel_win_change without its mid-line register writes"]
+    #[case::m3_lcdc_tile_sel_win_change_scanline("m3_lcdc_tile_sel_win_change", Renderer::Scanline)]
+    #[case::m3_lcdc_tile_sel_win_change_fifo("m3_lcdc_tile_sel_win_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_tile_sel_win_change2 without its mid-line register writes"]
+    #[case::m3_lcdc_tile_sel_win_change2_scanline("m3_lcdc_tile_sel_win_change2", Renderer::Scanline)]
+    #[case::m3_lcdc_tile_sel_win_change2_fifo("m3_lcdc_tile_sel_win_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_win_en_change_multiple without its mid-line register writes"]
+    #[case::m3_lcdc_win_en_change_multiple_scanline("m3_lcdc_win_en_change_multiple", Renderer::Scanline)]
+    #[case::m3_lcdc_win_en_change_multiple_fifo("m3_lcdc_win_en_change_multiple", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_win_en_change_multiple_wx without its mid-line register writes"]
+    #[case::m3_lcdc_win_en_change_multiple_wx_scanline("m3_lcdc_win_en_change_multiple_wx", Renderer::Scanline)]
+    #[case::m3_lcdc_win_en_change_multiple_wx_fifo("m3_lcdc_win_en_change_multiple_wx", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_win_map_change without its mid-line register writes"]
+    #[case::m3_lcdc_win_map_change_scanline("m3_lcdc_win_map_change", Renderer::Scanline)]
+    #[case::m3_lcdc_win_map_change_fifo("m3_lcdc_win_map_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_lcdc_win_map_change2 without its mid-line register writes"]
+    #[case::m3_lcdc_win_map_change2_scanline("m3_lcdc_win_map_change2", Renderer::Scanline)]
+    #[case::m3_lcdc_win_map_change2_fifo("m3_lcdc_win_map_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_obp0_change without its mid-line register writes"]
+    #[case::m3_obp0_change_scanline("m3_obp0_change", Renderer::Scanline)]
+    #[case::m3_obp0_change_fifo("m3_obp0_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_scx_high_5_bits without its mid-line register writes"]
+    #[case::m3_scx_high_5_bits_scanline("m3_scx_high_5_bits", Renderer::Scanline)]
+    #[case::m3_scx_high_5_bits_fifo("m3_scx_high_5_bits", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_scx_high_5_bits_change2 without its mid-line register writes"]
+    #[case::m3_scx_high_5_bits_change2_scanline("m3_scx_high_5_bits_change2", Renderer::Scanline)]
+    #[case::m3_scx_high_5_bits_change2_fifo("m3_scx_high_5_bits_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_scx_low_3_bits without its mid-line register writes"]
+    #[case::m3_scx_low_3_bits_scanline("m3_scx_low_3_bits", Renderer::Scanline)]
+    #[case::m3_scx_low_3_bits_fifo("m3_scx_low_3_bits", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_scy_change without its mid-line register writes"]
+    #[case::m3_scy_change_scanline("m3_scy_change", Renderer::Scanline)]
+    #[case::m3_scy_change_fifo("m3_scy_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_scy_change2 without its mid-line register writes"]
+    #[case::m3_scy_change2_scanline("m3_scy_change2", Renderer::Scanline)]
+    #[case::m3_scy_change2_fifo("m3_scy_change2", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_window_timing without its mid-line register writes"]
+    #[case::m3_window_timing_scanline("m3_window_timing", Renderer::Scanline)]
+    #[case::m3_window_timing_fifo("m3_window_timing", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_window_timing_wx_0 without its mid-line register writes"]
+    #[case::m3_window_timing_wx_0_scanline("m3_window_timing_wx_0", Renderer::Scanline)]
+    #[case::m3_window_timing_wx_0_fifo("m3_window_timing_wx_0", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_wx_4_change without its mid-line register writes"]
+    #[case::m3_wx_4_change_scanline("m3_wx_4_change", Renderer::Scanline)]
+    #[case::m3_wx_4_change_fifo("m3_wx_4_change", Renderer::Fifo)]
+    #[case::m3_wx_4_change_sprites_scanline("m3_wx_4_change_sprites", Renderer::Scanline)]
+    #[case::m3_wx_4_change_sprites_fifo("m3_wx_4_change_sprites", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_wx_5_change without its mid-line register writes"]
+    #[case::m3_wx_5_change_scanline("m3_wx_5_change", Renderer::Scanline)]
+    #[case::m3_wx_5_change_fifo("m3_wx_5_change", Renderer::Fifo)]
+    #[ignore = "the scanline renderer draws m3_wx_6_change without its mid-line register writes"]
+    #[case::m3_wx_6_change_scanline("m3_wx_6_change", Renderer::Scanline)]
+    #[case::m3_wx_6_change_fifo("m3_wx_6_change", Renderer::Fifo)]
+    fn test(#[case] file: &str, #[case] renderer: Renderer) {
+        let folder = "mealybug-tearoom-tests";
+        let gb = rom_test_with_renderer(&folder.to_string(), &file.to_string(), 60, renderer);
+        assert_expected(&gb, folder, file);
+    }
+
+    // these draw with the logo tiles the boot ROM leaves in VRAM
+    #[rstest(file, renderer,
+        case("m3_scx_low_3_bits", Renderer::Scanline),
+        case("m3_scx_low_3_bits", Renderer::Fifo),
+        case("m3_obp0_change", Renderer::Scanline),
+        case("m3_obp0_change", Renderer::Fifo),
+        case("m3_lcdc_obj_en_change", Renderer::Scanline),
+        case("m3_lcdc_obj_en_change", Renderer::Fifo),
+    )]
+    fn draws_the_boot_logo_tiles(file: &str, renderer: Renderer) {
+        let bytes = std::fs::read(format!("tests/roms/mealybug-tearoom-tests/{}.gb", file)).unwrap();
+        let mut gb = GameBoy::with_config(&bytes, &GameBoyConfig { renderer, ..Default::default() }).unwrap();
+        for _ in 1..=60 {
+            gb.exec_frame();
+        }
+        let pixels = gb.ppu().pixels().to_vec();
+        assert!(pixels.iter().any(|&p| p != pixels[0]), "{} with {:?} is a single colour", file, renderer);
+    }
+}
@@ -0,0 +1,260 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::GameBoy,
+    joypad::*,
+    model::Model,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+// SGB flag and the new licensee code the SGB functions need
+const SGB_SUPPORT: &[(usize, u8)] = &[(0x0146, 0x03), (0x014B, 0x33)];
+
+// player ID with nothing selected, then the buttons of that player;
+// deselecting P15 afterwards moves on to the next player
+fn poll(gb: &GameBoy) -> (u8, u8) {
+    write(gb, 0xFF00, 0x30);
+    let id = read(gb, 0xFF00) & 0x0F;
+    write(gb, 0xFF00, 0x10);
+    let buttons = !read(gb, 0xFF00) & 0x0F;
+    write(gb, 0xFF00, 0x30);
+    (id, buttons)
+}
+
+// pulse every bit of the command's packets through P1
+fn send(gb: &GameBoy, cmd: u8, args: &[u8]) {
+    let packets = (args.len() + 1).div_ceil(16);
+    let mut data = vec![0; packets * 16];
+    data[0] = cmd << 3 | packets as u8;
+    data[1..=args.len()].copy_from_slice(args);
+
+    for packet in data.chunks(16) {
+        write(gb, 0xFF00, 0x00);
+        write(gb, 0xFF00, 0x30);
+        for i in 0..128 {
+            let one = packet[i / 8] >> (i % 8) & 1 == 1;
+            write(gb, 0xFF00, if one { 0x10 } else { 0x20 });
+            write(gb, 0xFF00, 0x30);
+        }
+        write(gb, 0xFF00, 0x20);
+        write(gb, 0xFF00, 0x30);
+    }
+}
+
+// put `data` on screen as 256 tiles, 20 per row, for a *_TRN command
+fn show_tiles(gb: &GameBoy, data: &[u8]) {
+    write(gb, 0xFF40, 0x00);
+    for (i, b) in data.iter().enumerate() {
+        write(gb, 0x8000 + i as u16, *b);
+    }
+    for i in 0..256u16 {
+        write(gb, 0x9800 + i / 20 * 32 + i % 20, i as u8);
+    }
+    write(gb, 0xFF47, 0xE4);
+    write(gb, 0xFF40, 0x91);
+}
+
+fn pixel(gb: &GameBoy, x: u32, y: u32) -> [u8; 3] {
+    let p = gb.display().get_pixel(x, y).0;
+    [p[0], p[1], p[2]]
+}
+
+// screen cell (x, y) inside the border
+fn cell(gb: &GameBoy, x: u32, y: u32) -> [u8; 3] {
+    pixel(gb, 48 + x * 8, 40 + y * 8)
+}
+
+fn rgb(c: u16) -> [u8; 3] {
+    let channel = |shift: u16| {
+        let v = ((c >> shift) & 0x1F) as u8;
+        (v << 3) | (v >> 2)
+    };
+    [channel(0), channel(5), channel(10)]
+}
+
+const RED: u16 = 0x001F;
+const GREEN: u16 = 0x03E0;
+const BLUE: u16 = 0x7C00;
+const WHITE: u16 = 0x7FFF;
+
+speculate! {
+    describe "sgb" {
+        before {
+            let gb = boot(&idle_rom(SGB_SUPPORT), Model::Sgb);
+            // every pixel in shade 1
+            write(&gb, 0xFF47, 0x55);
+        }
+
+        it "frames the screen with a border" {
+            assert_eq!(gb.screen_size(), (256, 224));
+            assert_eq!(gb.display().dimensions(), (256, 224));
+            assert_eq!(boot(&idle_rom(SGB_SUPPORT), Model::Dmg).display().dimensions(), (160, 144));
+
+            let mut gb = gb;
+            gb.exec_frame();
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 0, 0), rgb(0x265B));
+            assert_eq!(pixel(&gb, 0, 0), rgb(0x67BF));
+        }
+
+        it "sets palettes with PAL01 and PAL23" {
+            let mut gb = gb;
+            send(&gb, 0x00, &[0x1F, 0x00, 0xE0, 0x03, 0, 0, 0, 0, 0x00, 0x7C]);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 0, 0), rgb(GREEN));
+            assert_eq!(pixel(&gb, 0, 0), rgb(RED));
+
+            // colour 0 is shared by every palette
+            send(&gb, 0x01, &[0xFF, 0x7F]);
+            gb.exec_frame();
+            assert_eq!(pixel(&gb, 0, 0), rgb(WHITE));
+        }
+
+        it "assigns palettes to blocks, lines and cells" {
+            let mut gb = gb;
+            send(&gb, 0x00, &[0, 0, 0xE0, 0x03, 0, 0, 0, 0, 0x00, 0x7C]);
+            send(&gb, 0x01, &[0, 0, 0x1F, 0x00, 0, 0, 0, 0, 0xFF, 0x7F]);
+
+            // inside only: the block's border takes the inside palette
+            send(&gb, 0x04, &[1, 0x01, 0x01, 2, 2, 5, 5]);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 3, 3), rgb(BLUE));
+            assert_eq!(cell(&gb, 2, 5), rgb(BLUE));
+            assert_eq!(cell(&gb, 6, 3), rgb(GREEN));
+
+            // above line 9 palette 0, on it 2, below it 1
+            send(&gb, 0x06, &[0x40 | 2 << 4 | 1, 9]);
+            // row 0 palette 3
+            send(&gb, 0x05, &[1, 0x80 | 3 << 5]);
+            // cell (19, 17) palette 2
+            send(&gb, 0x07, &[19, 17, 1, 0, 0, 2 << 6]);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 3, 0), rgb(WHITE));
+            assert_eq!(cell(&gb, 3, 3), rgb(GREEN));
+            assert_eq!(cell(&gb, 0, 9), rgb(RED));
+            assert_eq!(cell(&gb, 0, 10), rgb(BLUE));
+            assert_eq!(cell(&gb, 19, 17), rgb(RED));
+            assert_eq!(cell(&gb, 18, 17), rgb(BLUE));
+        }
+
+        it "masks the screen" {
+            let mut gb = gb;
+            send(&gb, 0x17, &[2]);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 0, 0), [0, 0, 0]);
+
+            // frozen: changes to the screen don't show
+            send(&gb, 0x17, &[0]);
+            gb.exec_frame();
+            send(&gb, 0x17, &[1]);
+            write(&gb, 0xFF47, 0xFF);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 0, 0), rgb(0x265B));
+
+            send(&gb, 0x17, &[0]);
+            gb.exec_frame();
+            assert_eq!(cell(&gb, 0, 0), rgb(0x2866));
+        }
+
+        it "loads palettes from VRAM with PAL_TRN and PAL_SET" {
+            let mut gb = gb;
+            let mut data = vec![0; 0x1000];
+            // system palette 1
+            for (i, c) in [RED, GREEN, BLUE, WHITE].iter().enumerate() {
+                data[8 + i * 2..10 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+            show_tiles(&gb, &data);
+            send(&gb, 0x0B, &[]);
+            gb.exec_frame();
+            gb.exec_frame();
+            send(&gb, 0x0A, &[1, 0, 1, 0, 1, 0, 1, 0, 0]);
+            gb.exec_frame();
+            assert_eq!(pixel(&gb, 0, 0), rgb(RED));
+        }
+
+        it "draws a border from CHR_TRN and PCT_TRN" {
+            let mut gb = gb;
+            let mut data = vec![0; 0x1000];
+            // tile 1, top row in colour 1
+            data[32] = 0xFF;
+            show_tiles(&gb, &data);
+            send(&gb, 0x13, &[0]);
+            gb.exec_frame();
+            gb.exec_frame();
+
+            let mut data = vec![0; 0x1000];
+            // top left tile 1 flipped vertically, colour 1 of palette 4
+            data[0] = 1;
+            data[1] = 0x80;
+            data[0x802..0x804].copy_from_slice(&BLUE.to_le_bytes());
+            show_tiles(&gb, &data);
+            send(&gb, 0x14, &[]);
+            gb.exec_frame();
+            gb.exec_frame();
+
+            assert_eq!(pixel(&gb, 0, 7), rgb(BLUE));
+            assert_eq!(pixel(&gb, 7, 7), rgb(BLUE));
+            assert_eq!(pixel(&gb, 0, 0), rgb(0x67BF));
+            assert_eq!(pixel(&gb, 8, 7), rgb(0x67BF));
+        }
+
+        it "ignores cartridges without SGB support" {
+            let mut gb = boot(&idle_rom(&[]), Model::Sgb);
+            send(&gb, 0x00, &[0x1F, 0x00]);
+            gb.exec_frame();
+            assert_eq!(pixel(&gb, 0, 0), rgb(0x67BF));
+        }
+
+        it "reads four controllers after MLT_REQ" {
+            gb.joypad.lock().unwrap().set_states([BUTTON_A, BUTTON_B, BUTTON_START, BUTTON_SELECT]);
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+
+            send(&gb, 0x11, &[3]);
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+            assert_eq!(poll(&gb), (0x0E, BUTTON_B));
+            assert_eq!(poll(&gb), (0x0D, BUTTON_START));
+            assert_eq!(poll(&gb), (0x0C, BUTTON_SELECT));
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+
+            send(&gb, 0x11, &[1]);
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+            assert_eq!(poll(&gb), (0x0E, BUTTON_B));
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+
+            send(&gb, 0x11, &[0]);
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+            assert_eq!(poll(&gb), (0x0F, BUTTON_A));
+        }
+
+        it "saves its state" {
+            let mut gb = gb;
+            send(&gb, 0x00, &[0x1F, 0x00]);
+            gb.exec_frame();
+            let state = gb.save_state();
+
+            send(&gb, 0x00, &[0xE0, 0x03]);
+            gb.exec_frame();
+            assert_eq!(pixel(&gb, 0, 0), rgb(GREEN));
+
+            gb.load_state(&state).unwrap();
+            assert_eq!(pixel(&gb, 0, 0), rgb(RED));
+
+            gb.joypad.lock().unwrap().set_states([0, 0, 0, BUTTON_DOWN]);
+            send(&gb, 0x11, &[3]);
+            poll(&gb);
+            poll(&gb);
+            let state = gb.save_state();
+            poll(&gb);
+            gb.load_state(&state).unwrap();
+            assert_eq!(poll(&gb), (0x0D, 0));
+            assert_eq!(gb.joypad.lock().unwrap().states()[3], BUTTON_DOWN);
+            assert!(boot(&idle_rom(SGB_SUPPORT), Model::Dmg).load_state(&state).is_err());
+        }
+    }
+}
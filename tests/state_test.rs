@@ -0,0 +1,126 @@
+extern crate rstest;
+#[cfg(test)]
+extern crate speculate;
+
+mod common;
+
+use rust_boy::{
+    gameboy::{GameBoy, Snapshot},
+    rewind::RewindBuffer,
+};
+use common::fixture::*;
+use speculate::speculate;
+
+speculate! {
+    describe "save state" {
+        it "restores the machine to the saved point" {
+            let bytes = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let mut gb = GameBoy::new(&bytes);
+            for _ in 0..60 {
+                gb.exec_frame();
+            }
+
+            let state = gb.save_state();
+            for _ in 0..60 {
+                gb.exec_frame();
+            }
+            let expected = gb.display();
+            let expected_state = gb.save_state();
+
+            gb.load_state(&state).unwrap();
+            for _ in 0..60 {
+                gb.exec_frame();
+            }
+            assert_eq!(gb.display(), expected);
+            assert_eq!(gb.save_state(), expected_state);
+        }
+
+        it "rejects a state from a different ROM" {
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs", "cpu_instrs"));
+            let other = GameBoy::new(&load_rom("blargg/instr_timing", "instr_timing"));
+            gb.exec_frame();
+            let before = gb.save_state();
+
+            assert!(gb.load_state(&other.save_state()).is_err());
+            assert_eq!(gb.save_state(), before);
+        }
+
+        it "rejects a broken state without modifying the machine" {
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs", "cpu_instrs"));
+            gb.exec_frame();
+            let before = gb.save_state();
+
+            assert!(gb.load_state(&before[..before.len() / 2]).is_err());
+            assert!(gb.load_state(b"not a state").is_err());
+            assert_eq!(gb.save_state(), before);
+        }
+    }
+
+    describe "rewind" {
+        it "pops states newest first" {
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs", "cpu_instrs"));
+            let mut rewind = RewindBuffer::new(64 * 1024 * 1024);
+            let mut states = vec![];
+            for _ in 0..30 {
+                gb.exec_frame();
+                states.push(gb.save_state());
+                rewind.push(gb.save_state());
+            }
+            assert_eq!(rewind.len(), 30);
+
+            while let Some(state) = rewind.pop() {
+                assert_eq!(state, states.pop().unwrap());
+            }
+            assert!(states.is_empty());
+        }
+
+        it "drops the oldest states to stay within the budget" {
+            let mut gb = GameBoy::new(&load_rom("blargg/cpu_instrs", "cpu_instrs"));
+            let budget = gb.save_state().len() + 256;
+            let mut rewind = RewindBuffer::new(budget);
+            for _ in 0..120 {
+                gb.exec_frame();
+                rewind.push(gb.save_state());
+                assert!(rewind.memory_usage() <= budget);
+            }
+            assert!(rewind.len() > 1 && rewind.len() < 120);
+        }
+    }
+
+    describe "run-ahead" {
+        it "shows the frame N ahead and leaves the machine as it was" {
+            let rom = load_rom("blargg/cpu_instrs", "cpu_instrs");
+            let mut gb = GameBoy::new(&rom);
+            let mut other = GameBoy::new(&rom);
+            let mut snapshot = Snapshot::default();
+            // the screen changes on frame 158
+            for _ in 0..155 {
+                gb.exec_frame();
+                other.exec_frame();
+            }
+
+            for frames in 1..=4u8 {
+                let state = gb.save_state();
+                let current = gb.display();
+                let ahead = gb.run_ahead(frames, &mut snapshot);
+                assert_eq!(ahead != current, frames >= 3);
+                assert_eq!(gb.save_state(), state);
+                assert_eq!(gb.display(), current);
+
+                let mut expected = GameBoy::new(&rom);
+                expected.load_state(&state).unwrap();
+                for _ in 0..frames {
+                    expected.exec_frame();
+                }
+                assert_eq!(ahead, expected.display());
+            }
+
+            // and the real frames carry on as if it never happened
+            for _ in 0..60 {
+                gb.exec_frame();
+                other.exec_frame();
+            }
+            assert_eq!(gb.save_state(), other.save_state());
+        }
+    }
+}